@@ -1,12 +1,16 @@
 use std::borrow::BorrowMut;
+use std::cmp;
 use std::fmt::Debug;
 use std::clone::Clone;
 use std::fmt::Write;
 
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
 
-use crate::blockchain::transaction::{Transaction, Transactional};
-use crate::crypto::merkle;
+use crate::blockchain::chain::genesis_pre_hash;
+use crate::blockchain::pow::{Pow, Target};
+use crate::blockchain::transaction::{Transaction, Transactional, REWARD_SENDER};
+use crate::blockchain::validation::Rule;
+use crate::crypto::{hash, merkle};
 
 /// A header of a block in the blockchain
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -28,11 +32,16 @@ pub struct BlockHeader {
     /// of the entire set of transactions, thereby enabling a user to verify whether or not a transaction is included in a bloc
     merkle: String,
 
-    /// The difficulty to mine a new block.
+    /// The difficulty to mine a new block, as compact encoding of the target the hash has to meet.
     ///
     /// The difficulty is a number that regulates how long it takes for miners to add new blocks of
     /// transactions to the blockchain.
-    pub difficulty: u32,
+    pub bits: u32,
+
+    /// The reward granted to the miner of the block.
+    ///
+    /// The first transaction of every block has to pay out exactly this reward.
+    pub reward: u32,
 }
 
 impl PartialEq for BlockHeader {
@@ -54,15 +63,116 @@ where Self: Send
         write!(&mut str, "            Nonce:         {}\n", self.nonce).expect("[BlockHeader fmt()]: Unable to write in Buffer!");
         write!(&mut str, "            Previous Hash: {}\n", self.pre_hash).expect("[BlockHeader fmt()]: Unable to write in Buffer!");
         write!(&mut str, "            Merkle:        {}\n", self.merkle).expect("[BlockHeader fmt()]: Unable to write in Buffer!");
-        write!(&mut str, "            Difficulty:    {}\n", self.difficulty).expect("[BlockHeader fmt()]: Unable to write in Buffer!");
+        write!(&mut str, "            Bits:          {:#010x}\n", self.bits).expect("[BlockHeader fmt()]: Unable to write in Buffer!");
+        write!(&mut str, "            Reward:        {}\n", self.reward).expect("[BlockHeader fmt()]: Unable to write in Buffer!");
         write!(&mut str, "        ]\n").expect("[BlockHeader fmt()]: Unable to write in Buffer!");
 
         str
     }
+
+    /// The creation timestamp of the block.
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// The hash of the preceding block.
+    pub fn pre_hash(&self) -> &str {
+        &self.pre_hash
+    }
+
+    /// The expected number of hashes needed to mine a header with this difficulty.
+    pub fn work(&self) -> u128 {
+        Target::from_compact(self.bits).work()
+    }
+
+    /// The proof of work of the header, covering all fields except the nonce.
+    pub fn pow(&self) -> Pow {
+        let encoding = serde_json::to_vec(&(self.timestamp, &self.pre_hash, &self.merkle, self.bits, self.reward))
+            .expect("[BlockHeader pow()]: Unable to serialize header!");
+        Pow::new(&encoding, Target::from_compact(self.bits))
+    }
+
+    /// The hash of the header, which is the digest of its proof of work.
+    pub fn hash(&self) -> String {
+        hash::hex_to_string(&self.pow().digest(self.nonce))
+    }
+
+    /// Checks whether the hash of the header meets the target of its difficulty.
+    pub fn meets_target(&self) -> bool {
+        self.pow().verify(self.nonce)
+    }
+
+    /// Increases the timestamp by a second, which yields a fresh space of nonces to mine.
+    pub fn roll(&mut self) {
+        self.timestamp += 1;
+    }
+
+    /// Moves the timestamp forward to the given one if it is older, e.g. to follow the median
+    /// time past of the chain.
+    pub fn advance_to(&mut self, timestamp: i64) {
+        self.timestamp = cmp::max(self.timestamp, timestamp);
+    }
+
+    /// Searches for a nonce such that the hash of the header meets the target.
+    ///
+    /// If all nonces are exhausted, the timestamp is rolled and the search starts over.
+    pub fn mine(&mut self) {
+        loop {
+            match self.pow().mine(0) {
+                Some(nonce) => {
+                    self.nonce = nonce;
+                    return;
+                }
+                None => self.roll(),
+            }
+        }
+    }
 }
 
 impl Eq for BlockHeader {}
 
+/// The largest `BlockLimits::max_size` of a chain, a quarter of the largest message peers accept,
+/// so a block always fits into a message together with the keys of its senders.
+pub const MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// The limits every block of a chain has to obey, sizes measured serialized.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockLimits {
+    /// The most bytes a block may take, at most `MAX_BLOCK_SIZE`.
+    pub max_size: usize,
+    /// The most transactions a block may contain, including the reward transaction.
+    pub max_transactions: usize,
+    /// The most bytes a single transaction may take.
+    pub max_transaction_size: usize,
+}
+
+impl Default for BlockLimits {
+    fn default() -> Self {
+        BlockLimits {
+            max_size: 1024 * 1024,
+            max_transactions: 100,
+            max_transaction_size: 100 * 1024,
+        }
+    }
+}
+
+impl BlockLimits {
+    /// Checks the block against the limits.
+    /// Returns the first rule the block violates.
+    pub fn check<T: Serialize>(&self, block: &Block<T>) -> Result<(), Rule> {
+        if block.transactions.len() > self.max_transactions {
+            return Err(Rule::TransactionLimit);
+        }
+        if block.transactions.iter().any(|transaction| transaction.size() > self.max_transaction_size) {
+            return Err(Rule::TransactionSize);
+        }
+        if block.size() > self.max_size {
+            return Err(Rule::BlockSize);
+        }
+        Ok(())
+    }
+}
+
 /// A block of the blockchain
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block<T> {
@@ -82,12 +192,21 @@ impl<T> PartialEq for Block<T> {
 
 impl<T> Eq for Block<T> {}
 
+impl<T: Serialize> Block<T> {
+    /// The serialized size of the block in bytes.
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self)
+            .expect("[Block size()]: Unable to serialize the block!")
+            .len()
+    }
+}
+
 impl<T> Block<T>
 where T: Serialize + DeserializeOwned + Debug + Clone + Transactional + Send
 {
     pub fn new(
         hash: String,
-        difficulty: u32,
+        bits: u32,
         miner_address: String,
         reward: u32,
         transactions: &mut Vec<Transaction<T>>
@@ -97,7 +216,8 @@ where T: Serialize + DeserializeOwned + Debug + Clone + Transactional + Send
             nonce: 0,
             pre_hash: hash,
             merkle: String::new(),
-            difficulty
+            bits,
+            reward,
         };
 
         let reward_trans = T::genesis(miner_address, reward);
@@ -115,6 +235,65 @@ where T: Serialize + DeserializeOwned + Debug + Clone + Transactional + Send
         block
     }
 
+    /// Creates the genesis block at the timestamp with the nonce containing the reward
+    /// transactions, e.g. the allocations of a `ChainSpec`.
+    ///
+    /// The block isn't mined, so every node creates the same block without searching for the
+    /// nonce again.
+    pub fn genesis(timestamp: i64, bits: u32, nonce: u32, reward: u32, transactions: Vec<Transaction<T>>) -> Self {
+        Block {
+            header: BlockHeader {
+                timestamp,
+                nonce,
+                pre_hash: genesis_pre_hash(),
+                merkle: merkle::get_merkle(transactions.clone()),
+                bits,
+                reward,
+            },
+            count: transactions.len() as u32,
+            transactions,
+        }
+    }
+
+    /// The hash of the block's header.
+    pub fn hash(&self) -> String {
+        self.header.hash()
+    }
+
+    /// The transactions in the block, starting with the reward transaction.
+    pub fn transactions(&self) -> &[Transaction<T>] {
+        &self.transactions
+    }
+
+    /// Checks the block against the rules every block in the chain has to obey.
+    ///
+    /// `pre_hash` is the hash of the header of the preceding block.
+    /// Returns the first rule the block violates.
+    pub fn verify(&self, pre_hash: &str) -> Result<(), Rule> {
+        if self.header.pre_hash != pre_hash {
+            return Err(Rule::PreviousHash);
+        }
+        if self.count as usize != self.transactions.len() {
+            return Err(Rule::TransactionCount);
+        }
+        match self.transactions.split_first() {
+            Some((reward, rest)) => {
+                if !T::is_genesis(reward, self.header.reward)
+                    || rest.iter().any(|t| t.sender == REWARD_SENDER) {
+                    return Err(Rule::Reward);
+                }
+            }
+            None => return Err(Rule::Reward),
+        }
+        if self.header.merkle != merkle::get_merkle(self.transactions.clone()) {
+            return Err(Rule::Merkle);
+        }
+        if !self.header.meets_target() {
+            return Err(Rule::ProofOfWork);
+        }
+        Ok(())
+    }
+
     /// Used to format a block of the blockchain.
     pub fn fmt(&self) -> String {
         let mut str = String::new();
@@ -138,7 +317,22 @@ where T: Serialize + DeserializeOwned + Debug + Clone + Transactional + Send
 #[cfg(test)]
 mod tests {
     use crate::blockchain::block::{BlockHeader, Block};
+    use crate::blockchain::chain::Chain;
+    use crate::blockchain::pow::MAX_BITS;
     use crate::blockchain::transaction::{CryptoPayload, Transactional};
+    use crate::blockchain::validation::Rule;
+
+    fn mined_block() -> Block<CryptoPayload> {
+        let crypto_payload = CryptoPayload {
+            receiver: String::from("Peter"),
+            amount: 42,
+        };
+        let mut transaction = vec![CryptoPayload::new(String::from("Schwurbel"), crypto_payload)];
+        let mut block: Block<CryptoPayload> = Block::new(String::from("00xxxxxxxxxxxxxxxxxx"), MAX_BITS,
+                                                         String::from("Schwurbel"), 42, &mut transaction);
+        Chain::<CryptoPayload>::proof_of_work(&mut block.header);
+        block
+    }
 
     #[test]
     fn block_header_eq() {
@@ -147,7 +341,8 @@ mod tests {
             pre_hash: String::from("00xxxxxxxxxxxxxxxxxx"),
             nonce: 24,
             merkle: String::from("xxxxxxxxxxxxxxxxxxxx"),
-            difficulty: 2,
+            bits: MAX_BITS,
+            reward: 42,
         };
 
         let block_header_2 = BlockHeader {
@@ -155,7 +350,8 @@ mod tests {
             pre_hash: String::from("00yyyyyyyyyyyyyyyyyy"),
             nonce: 42,
             merkle: String::from("yyyyyyyyyyyyyyyyyyyy"),
-            difficulty: 2,
+            bits: MAX_BITS,
+            reward: 42,
         };
 
         assert_eq!(block_header_1.eq(&block_header_2), false);
@@ -169,7 +365,8 @@ mod tests {
             pre_hash: String::from("00xxxxxxxxxxxxxxxxxx"),
             nonce: 24,
             merkle: String::from("xxxxxxxxxxxxxxxxxxxx"),
-            difficulty: 2,
+            bits: MAX_BITS,
+            reward: 42,
         };
 
         let block_header_2 = BlockHeader {
@@ -177,7 +374,8 @@ mod tests {
             pre_hash: String::from("00yyyyyyyyyyyyyyyyyy"),
             nonce: 42,
             merkle: String::from("yyyyyyyyyyyyyyyyyyyy"),
-            difficulty: 2,
+            bits: MAX_BITS,
+            reward: 42,
         };
 
         let block_1: Block<CryptoPayload> = Block {
@@ -199,7 +397,7 @@ mod tests {
     #[test]
     fn new_block() {
         let hash = String::from("00xxxxxxxxxxxxxxxxxx");
-        let difficulty = 2;
+        let bits = MAX_BITS;
         let miner_addr = String::from("Schwurbel");
         let reward = 42;
 
@@ -209,10 +407,41 @@ mod tests {
         };
         let mut transaction = vec![CryptoPayload::new(miner_addr.clone(), crypto_payload)];
 
-        let block: Block<CryptoPayload> = Block::new(hash, difficulty, miner_addr,
+        let block: Block<CryptoPayload> = Block::new(hash, bits, miner_addr,
                                                      reward, &mut transaction);
 
         assert_eq!(block.count, 2);
         assert_eq!(block.transactions.len(), 2);
     }
+
+    #[test]
+    fn verify_block() {
+        let block = mined_block();
+        assert_eq!(block.verify("00xxxxxxxxxxxxxxxxxx"), Ok(()));
+        assert_eq!(block.verify("00yyyyyyyyyyyyyyyyyy"), Err(Rule::PreviousHash));
+    }
+
+    #[test]
+    fn verify_tampered_block() {
+        let mut block = mined_block();
+        block.count = 1;
+        assert_eq!(block.verify("00xxxxxxxxxxxxxxxxxx"), Err(Rule::TransactionCount));
+
+        let block = mined_block();
+        block.transactions[1].payload.write().unwrap().amount = 4242;
+        assert_eq!(block.verify("00xxxxxxxxxxxxxxxxxx"), Err(Rule::Merkle));
+
+        let mut block = mined_block();
+        block.header.reward = 4242;
+        assert_eq!(block.verify("00xxxxxxxxxxxxxxxxxx"), Err(Rule::Reward));
+
+        let mut block = mined_block();
+        block.transactions.remove(0);
+        block.count = 1;
+        assert_eq!(block.verify("00xxxxxxxxxxxxxxxxxx"), Err(Rule::Reward));
+
+        let mut block = mined_block();
+        block.header.bits = 0;
+        assert_eq!(block.verify("00xxxxxxxxxxxxxxxxxx"), Err(Rule::ProofOfWork));
+    }
 }
@@ -1,108 +1,938 @@
 /// data structure to maintain the chain
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::slice;
 use std::fmt::Debug;
 use std::clone::Clone;
 use std::fmt::Write;
+use std::time::Instant;
 
-use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use failure;
+use serde::{Serialize, de::DeserializeOwned};
+use sequoia_openpgp::TPK;
 
-use crate::crypto::hash;
+use crate::crypto::{hash, pgp};
+use crate::storage::chain::ChainStore;
+use crate::storage::storage::Storage;
 
-use super::block::{Block, BlockHeader};
-use super::transaction::{Transaction, Transactional};
+use super::block::{Block, BlockHeader, BlockLimits};
+use super::emission::Emission;
+use super::mempool::{Mempool, MempoolError, MempoolLimits};
+use super::miner::{Miner, MiningJob};
+use super::retarget::Retarget;
+use super::spec::{Allocation, ChainSpec, Genesis, SpecError};
+use super::state::ChainState;
+use super::transaction::{CryptoPayload, OutPoint, Output, Transaction, Transactional, UtxoPayload, REWARD_SENDER};
+use super::tree::BlockTree;
+use super::validation::{Rule, TransactionError, ValidationError};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Chain<T> { 
-    chain: Vec<Block<T>>,
-    curr_trans: Vec<Transaction<T>>,
-    difficulty: u32,
+/// The number of blocks a competing branch may fall behind the active chain before it is dropped.
+const MAX_FORK_DEPTH: usize = 100;
+
+/// The number of recent blocks `Chain::estimate_fee` is based on.
+const FEE_ESTIMATE_BLOCKS: usize = 10;
+
+/// Bytes kept free in block templates for the header fields growing with the transactions.
+const TEMPLATE_RESERVE: usize = 32;
+
+/// The number of preceding blocks whose median timestamp a block has to be younger than.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// The number of seconds the timestamp of a new block may be ahead of the local clock.
+const MAX_FUTURE_DRIFT: i64 = 2 * 60 * 60;
+
+/// The previous hash of the genesis block.
+pub fn genesis_pre_hash() -> String {
+    String::from_utf8(vec![48; 64]).unwrap()
+}
+
+/// Keeps all blocks of the active chain, of which a `Chain` only holds the recent ones in memory.
+enum Archive<T> {
+    /// The blocks in memory, for chains without a store.
+    Memory(Vec<Block<T>>),
+    /// The blocks in a store, loaded on demand. The public keys of the senders are stored too.
+    Store(ChainStore<Box<dyn Storage + Send>>),
+}
+
+impl<T> Archive<T>
+where T: Transactional
+{
+    /// The block of the active chain at the height.
+    fn get(&self, height: usize) -> Option<Block<T>> {
+        match self {
+            Archive::Memory(blocks) => blocks.get(height).cloned(),
+            Archive::Store(store) => store.block_at(height).unwrap_or_else(|e| {
+                println!("Failed to load the block at height {}: {}", height, e);
+                None
+            }),
+        }
+    }
+
+    /// Replaces the blocks from the height on by the new blocks of the active chain.
+    fn replace(&mut self, height: usize, blocks: &[Block<T>]) {
+        match self {
+            Archive::Memory(archived) => {
+                archived.truncate(height);
+                archived.extend_from_slice(blocks);
+            }
+            Archive::Store(store) => {
+                if let Err(e) = store.replace(height, blocks) {
+                    println!("Failed to persist the chain: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Copies the blocks into memory, loading all blocks of a store, as only the original chain
+    /// writes to the store.
+    fn try_clone(&self) -> Result<Archive<T>, failure::Error> {
+        match self {
+            Archive::Memory(blocks) => Ok(Archive::Memory(blocks.clone())),
+            Archive::Store(store) => Ok(Archive::Memory(store.load()?)),
+        }
+    }
+
+    /// Stores the public key of a sender if the blocks are stored.
+    fn put_key(&mut self, sender: &str, key: &[u8]) {
+        if let Archive::Store(store) = self {
+            if let Err(e) = store.put_key(sender, key) {
+                println!("Failed to persist the key of {}: {}", sender, e);
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for Archive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Archive::Memory(blocks) => write!(f, "Memory({} blocks)", blocks.len()),
+            Archive::Store(_) => write!(f, "Store"),
+        }
+    }
+}
+
+/// The active chain and the competing branches of the recent blocks.
+///
+/// Only the headers of the active chain and the blocks of the last `MAX_FORK_DEPTH` heights are
+/// kept in memory, older blocks are loaded from the archive on demand. Branches forking off
+/// further below the tip are dropped.
+#[derive(Debug)]
+pub struct Chain<T>
+where T: Transactional
+{
+    /// The headers of the active chain, starting with the genesis block.
+    headers: Vec<BlockHeader>,
+    /// The height of every block on the active chain by hash.
+    heights: HashMap<String, usize>,
+    /// All blocks of the active chain.
+    archive: Archive<T>,
+    /// The recent blocks, descending from the block of the active chain at `base`.
+    forks: BlockTree<T>,
+    /// The height of the oldest block in `forks`.
+    base: usize,
+    /// The ledger state before the block at `base`, from which competing branches are replayed.
+    base_state: ChainState<T>,
+    /// The ledger state and nonces after all blocks of the active chain.
+    state: ChainState<T>,
+    /// The transactions waiting to be mined on top of the active chain.
+    mempool: Mempool<T>,
+    /// The public keys of the senders, serialized by `pgp::public_bytes`.
+    keys: HashMap<String, Vec<u8>>,
+    /// The compact target of the next block.
+    bits: u32,
+    /// The parameters shared by all nodes of the network.
+    spec: ChainSpec,
+    /// The hash of the genesis block of the spec.
+    genesis: String,
+    /// The miner used by `add_new_block`, configured per node.
+    miner: Miner,
     miner_addr: String,
-    reward: u32,
 }
 
 impl<T> Chain<T>
 where T: Serialize + DeserializeOwned + Debug + Clone + Transactional + Send 
 {
-    pub fn new(miner_addr: String, difficulty: u32) -> Chain<T> {
-        let mut chain = Chain {
-            chain: Vec::new(),
-            curr_trans: Vec::new(),
-            difficulty,
+    /// Creates a new chain whose genesis block, created now, allocates 100 coins to the miner,
+    /// e.g. for tests. Independent nodes have to share a `ChainSpec` instead.
+    pub fn new(miner_addr: String, bits: u32) -> Chain<T> {
+        Chain::with_retarget(miner_addr, bits, Retarget::default())
+    }
+
+    /// Creates a new chain like `new`, whose difficulty is adjusted according to `retarget`.
+    pub fn with_retarget(miner_addr: String, bits: u32, retarget: Retarget) -> Chain<T> {
+        assert!(retarget.is_valid(), "[Chain with_retarget()]: Invalid retargeting!");
+        let mut spec = ChainSpec {
+            network: String::from("local"),
+            genesis: Genesis {
+                timestamp: time::now().to_timespec().sec,
+                bits,
+                nonce: 0,
+                allocations: vec![Allocation { address: miner_addr.clone(), amount: 100 }],
+            },
+            emission: Emission::default(),
+            retarget,
+            limits: BlockLimits::default(),
+        };
+        spec.mine_genesis::<T>();
+        Chain::from_spec(miner_addr, spec).expect("[Chain with_retarget()]: Invalid spec!")
+    }
+
+    /// Creates a new chain consisting of the genesis block of the spec.
+    ///
+    /// Fails if the spec is invalid or its genesis block doesn't meet its target.
+    pub fn from_spec(miner_addr: String, spec: ChainSpec) -> Result<Chain<T>, SpecError> {
+        spec.check()?;
+        spec.check_genesis::<T>()?;
+        let genesis = spec.genesis_block();
+        Ok(Chain::from_blocks(miner_addr, spec, vec![genesis])
+            .expect("[Chain from_spec()]: Invalid genesis block!"))
+    }
+
+    /// Copies the chain into memory, e.g. to try blocks on it without touching the chain.
+    ///
+    /// All blocks of a stored chain are loaded, fails if they can't be. The copy doesn't write to
+    /// the store, so chains aren't `Clone`, which would load them silently.
+    pub fn try_clone(&self) -> Result<Chain<T>, failure::Error> {
+        Ok(Chain {
+            headers: self.headers.clone(),
+            heights: self.heights.clone(),
+            archive: self.archive.try_clone()?,
+            forks: self.forks.clone(),
+            base: self.base,
+            base_state: self.base_state.clone(),
+            state: self.state.clone(),
+            mempool: self.mempool.clone(),
+            keys: self.keys.clone(),
+            bits: self.bits,
+            spec: self.spec.clone(),
+            genesis: self.genesis.clone(),
+            miner: self.miner.clone(),
+            miner_addr: self.miner_addr.clone(),
+        })
+    }
+
+    /// Creates a chain from the blocks of an active chain, kept in memory.
+    ///
+    /// The first block has to be the genesis block of the spec. The blocks are checked like in
+    /// `validate`, except for the signatures, as the keys of the senders may not be known yet.
+    pub fn from_blocks(miner_addr: String, spec: ChainSpec, blocks: Vec<Block<T>>)
+                       -> Result<Chain<T>, ValidationError> {
+        if blocks.is_empty() {
+            return Err(ValidationError { height: 0, rule: Rule::Genesis });
+        }
+        let mut chain = Chain::empty(miner_addr, spec);
+        for block in &blocks {
+            chain.load(block.clone())?;
+        }
+        chain.archive = Archive::Memory(blocks);
+        Ok(chain)
+    }
+
+    /// Opens the chain persisted in the store, which starts with the genesis block of the spec,
+    /// and keeps persisting it there.
+    ///
+    /// The stored blocks are checked like in `from_blocks`, the stored keys of the senders are
+    /// restored. The miner address is stored with the chain and takes precedence over the given
+    /// one, so the node keeps mining to the same address.
+    pub fn open<S>(miner_addr: String, spec: ChainSpec, store: ChainStore<S>) -> Result<Chain<T>, failure::Error>
+    where S: Storage + Send + 'static
+    {
+        spec.check()?;
+        spec.check_genesis::<T>()?;
+        let mut store = store.boxed();
+        let miner_addr = match store.miner()? {
+            Some(stored) => stored,
+            None => {
+                store.put_miner(&miner_addr)?;
+                miner_addr
+            }
+        };
+        if store.is_empty()? {
+            store.replace(0, &[spec.genesis_block::<T>()])?;
+        }
+
+        let mut chain = Chain::empty(miner_addr, spec);
+        for height in 0..store.len()? {
+            let block = store.block_at(height)?
+                .ok_or_else(|| failure::err_msg(format!("Missing block at height {}", height)))?;
+            chain.load(block)?;
+        }
+        chain.keys = store.keys()?;
+        chain.archive = Archive::Store(store);
+        Ok(chain)
+    }
+
+    fn empty(miner_addr: String, spec: ChainSpec) -> Chain<T> {
+        Chain {
+            headers: Vec::new(),
+            heights: HashMap::new(),
+            archive: Archive::Memory(Vec::new()),
+            forks: BlockTree::default(),
+            base: 0,
+            base_state: ChainState::default(),
+            state: ChainState::default(),
+            mempool: Mempool::default(),
+            keys: HashMap::new(),
+            bits: spec.genesis.bits,
+            genesis: spec.genesis_block::<T>().hash(),
+            spec,
+            miner: Miner::default(),
             miner_addr,
-            reward: 100,
-         };
+        }
+    }
 
-        chain.add_new_block();
-        chain
+    /// Appends a block loaded from the archive to the active chain without archiving it.
+    fn load(&mut self, block: Block<T>) -> Result<(), ValidationError> {
+        let height = self.headers.len();
+        let parent = self.last_hash();
+        self.check_block(&block, &parent, height, false, |height| self.headers.get(height))
+            .map_err(|rule| ValidationError { height, rule })?;
+        Chain::replay(&mut self.state, slice::from_ref(&block), height)?;
+        self.push(&block);
+        self.forks.insert(block).map_err(|rule| ValidationError { height, rule })?;
+        self.slide();
+        self.bits = self.next_bits();
+        Ok(())
     }
 
+    /// Adds the transactions to the mempool and mines a block if one is due.
+    ///
+    /// Returns whether all transactions were accepted.
     pub fn add_transaction(&mut self, transactions: &mut Vec<Transaction<T>>) ->
     bool {
-        self.curr_trans.append(transactions);
+        let accepted = self.queue_transactions(transactions);
 
-        if self.curr_trans.len() > 20 {
+        if self.is_block_due() {
             self.add_new_block();
         }
-        true
+        accepted
+    }
+
+    /// Adds the transactions to the mempool without mining a block.
+    ///
+    /// Transactions the mempool rejects are dropped.
+    /// Returns whether all transactions were accepted.
+    pub fn queue_transactions(&mut self, transactions: &mut Vec<Transaction<T>>) -> bool {
+        let mut accepted = true;
+        for transaction in transactions.drain(..) {
+            accepted &= self.queue_transaction(transaction).is_ok();
+        }
+        accepted
+    }
+
+    /// Adds the transaction to the mempool if it is signed by its sender and the ledger accepts
+    /// it on top of the active chain and the waiting transactions.
+    pub fn queue_transaction(&mut self, transaction: Transaction<T>) -> Result<(), MempoolError> {
+        if transaction.sender == REWARD_SENDER {
+            return Err(TransactionError::Reward.into());
+        }
+        let size = transaction.size();
+        if size > self.spec.limits.max_transaction_size {
+            return Err(MempoolError::TooLarge(size));
+        }
+        self.verify_signature(&transaction)?;
+        self.mempool.insert(transaction, &self.state, self.headers.len(), Instant::now())
+    }
+
+    /// The nonce the next transaction of the address has to carry, counting the waiting ones.
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        self.mempool.next_nonce(&self.state, address)
+    }
+
+    /// Drops the transactions waiting longer than the expiry of the mempool.
+    /// Returns the number of dropped transactions.
+    pub fn expire_transactions(&mut self, now: Instant) -> usize {
+        self.mempool.expire(&self.state, self.headers.len(), now)
+    }
+
+    pub fn update_mempool_limits(&mut self, limits: MempoolLimits) -> bool {
+        self.mempool.update_limits(limits)
+    }
+
+    /// Registers the public key of a sender to check the signatures of its transactions.
+    pub fn add_key(&mut self, sender: String, tpk: &TPK) -> Result<(), failure::Error> {
+        let key = pgp::public_bytes(tpk)?;
+        self.archive.put_key(&sender, &key);
+        self.keys.insert(sender, key);
+        Ok(())
+    }
+
+    /// Checks the signature of the transaction against the public key of its sender.
+    fn verify_signature(&self, transaction: &Transaction<T>) -> Result<(), TransactionError> {
+        let key = self.keys.get(&transaction.sender).ok_or(TransactionError::UnknownSender)?;
+        match pgp::from_bytes(key) {
+            Ok(ref tpk) if transaction.verify(tpk) => Ok(()),
+            _ => Err(TransactionError::Signature),
+        }
+    }
+
+    /// The public keys of the senders of the transactions in the blocks, as far as they are known.
+    pub fn keys_for(&self, blocks: &[Block<T>]) -> HashMap<String, Vec<u8>> {
+        self.keys_of(blocks.iter().flat_map(|block| block.transactions().iter().skip(1)))
+    }
+
+    /// The public keys of the senders of the transactions, as far as they are known.
+    pub fn keys_of<'a, I>(&self, transactions: I) -> HashMap<String, Vec<u8>>
+    where I: IntoIterator<Item = &'a Transaction<T>>, T: 'a
+    {
+        transactions.into_iter()
+            .filter_map(|transaction| self.keys.get_key_value(&transaction.sender))
+            .map(|(sender, key)| (sender.clone(), key.clone()))
+            .collect()
+    }
+
+    /// Adds the public keys of senders unknown so far, e.g. received from a peer.
+    ///
+    /// Keys of known senders are never replaced.
+    pub fn import_keys(&mut self, keys: HashMap<String, Vec<u8>>) {
+        for (sender, key) in keys {
+            if !self.keys.contains_key(&sender) {
+                self.archive.put_key(&sender, &key);
+                self.keys.insert(sender, key);
+            }
+        }
+    }
+
+    /// Adds a block like `add_block` whose senders may be unknown, e.g. received from a peer
+    /// with the public keys of its senders.
+    ///
+    /// The keys of unknown senders are only kept if they verified the transactions of the block
+    /// and the block was accepted.
+    pub fn add_block_with_keys(&mut self, block: Block<T>, keys: &HashMap<String, Vec<u8>>) -> Result<bool, Rule> {
+        if self.contains(&block.hash()) {
+            return Ok(false);
+        }
+        let transactions = block.transactions().iter().skip(1).cloned().collect::<Vec<Transaction<T>>>();
+        self.with_keys(&transactions, keys, |chain| chain.add_block(block))
+    }
+
+    /// Queues a transaction like `queue_transaction` whose sender may be unknown, e.g. received
+    /// from a peer with the public key of its sender.
+    ///
+    /// The key of an unknown sender is only kept if it verified the accepted transaction.
+    pub fn queue_transaction_with_keys(&mut self, transaction: Transaction<T>, keys: &HashMap<String, Vec<u8>>)
+        -> Result<(), MempoolError> {
+        let transactions = vec![transaction.clone()];
+        self.with_keys(&transactions, keys, |chain| chain.queue_transaction(transaction))
+    }
+
+    /// Registers the keys of the unknown senders of the transactions while `f` runs and keeps
+    /// them only if it succeeds.
+    fn with_keys<R, E, F>(&mut self, transactions: &[Transaction<T>], keys: &HashMap<String, Vec<u8>>, f: F)
+        -> Result<R, E>
+    where F: FnOnce(&mut Self) -> Result<R, E>
+    {
+        let unknown: HashMap<String, Vec<u8>> = transactions.iter()
+            .filter(|transaction| !self.keys.contains_key(&transaction.sender))
+            .filter_map(|transaction| keys.get_key_value(&transaction.sender))
+            .map(|(sender, key)| (sender.clone(), key.clone()))
+            .collect();
+        self.keys.extend(unknown.clone());
+        let result = f(self);
+        for (sender, key) in unknown {
+            if result.is_ok() {
+                self.archive.put_key(&sender, &key);
+            } else {
+                self.keys.remove(&sender);
+            }
+        }
+        result
+    }
+
+    /// Checks the signatures of all transactions in the block except the reward.
+    fn verify_signatures(&self, block: &Block<T>) -> Result<(), Rule> {
+        if block.transactions().iter().skip(1).all(|transaction| self.verify_signature(transaction).is_ok()) {
+            Ok(())
+        } else {
+            Err(Rule::Signature)
+        }
+    }
+
+    /// Whether enough transactions are waiting to fill a new block.
+    pub fn is_block_due(&self) -> bool {
+        self.mempool.len() + 1 >= self.spec.limits.max_transactions
+            || self.mempool.size() + self.mempool.len() + TEMPLATE_RESERVE >= self.spec.limits.max_size
     }
 
     pub fn last_hash(&self) -> String {
-        let block = match self.chain.last() {
-            Some(block) => block,
-            None => return String::from_utf8(vec![48; 64]).unwrap()
+        let header = match self.headers.last() {
+            Some(header) => header,
+            None => return genesis_pre_hash()
         };
-        hash::hash(&block.header)
+        header.hash()
     }
 
-    pub fn update_difficulty(&mut self, difficulty: u32) -> bool {
-        self.difficulty = difficulty;
+    pub fn update_miner(&mut self, miner: Miner) -> bool {
+        self.miner = miner;
         true
     }
 
-    pub fn update_reward(&mut self, reward: u32) -> bool {
-        self.reward = reward;
-        true
+    /// The newly minted coins a block at the height pays out to its miner on top of the fees,
+    /// following the emission schedule of the spec.
+    pub fn subsidy(&self, height: usize) -> u32 {
+        self.spec.emission.subsidy(self.spec.allocated(), height)
+    }
+
+    /// The coins in existence after the block at the height, the genesis allocations plus the
+    /// subsidies of the following blocks.
+    pub fn total_supply(&self, height: usize) -> u64 {
+        self.spec.emission.supply(self.spec.allocated(), height)
+    }
+
+    /// The reward of a block at the height with the transactions, the subsidy plus the fees.
+    fn reward_for(&self, height: usize, transactions: &[Transaction<T>]) -> u32 {
+        let reward = u64::from(self.subsidy(height)) + Chain::fees(transactions);
+        cmp::min(reward, u64::from(u32::MAX)) as u32
+    }
+
+    /// The sum of the fees of the transactions.
+    fn fees<'a, I>(transactions: I) -> u64
+    where I: IntoIterator<Item = &'a Transaction<T>>, T: 'a
+    {
+        transactions.into_iter().map(|transaction| u64::from(transaction.fee)).sum()
+    }
+
+    /// Checks that the block is the genesis block of the spec.
+    fn verify_genesis(&self, block: &Block<T>) -> Result<(), Rule> {
+        if block.header.pre_hash() != genesis_pre_hash() || block.hash() != self.genesis {
+            return Err(Rule::Genesis);
+        }
+        if !block.header.meets_target() {
+            return Err(Rule::ProofOfWork);
+        }
+        Ok(())
+    }
+
+    /// Checks that the block pays out exactly the subsidy of its height plus the fees of its
+    /// transactions.
+    fn verify_reward(&self, block: &Block<T>, height: usize) -> Result<(), Rule> {
+        if block.header.reward != self.reward_for(height, &block.transactions()[1..]) {
+            return Err(Rule::Subsidy);
+        }
+        Ok(())
+    }
+
+
+    /// Creates an unmined block on top of the active chain containing the waiting transactions,
+    /// the highest fee rate first if not all of them fit into the block limits. The reward is the
+    /// subsidy plus the fees.
+    pub fn block_template(&self) -> Block<T> {
+        let height = self.headers.len();
+        let empty = self.template(height, Vec::new()).size();
+        let transactions = self.mempool.block_transactions(
+            &self.state, height, self.spec.limits.max_transactions.saturating_sub(1),
+            self.spec.limits.max_size.saturating_sub(empty + TEMPLATE_RESERVE));
+        self.template(height, transactions)
+    }
+
+    /// Creates an unmined block at the height containing the reward and the transactions.
+    fn template(&self, height: usize, mut transactions: Vec<Transaction<T>>) -> Block<T> {
+        let reward = self.reward_for(height, &transactions);
+        let mut block = Block::<T>::new(self.last_hash(), self.next_bits(), self.miner_addr.clone(), reward, &mut transactions);
+        block.header.advance_to(self.median_time_past() + 1);
+        block
+    }
+
+    /// The median timestamp of the last blocks of the active chain, which the timestamp of the
+    /// next block has to exceed.
+    pub fn median_time_past(&self) -> i64 {
+        median_time_past(self.headers.len(), |height| self.headers.get(height))
+            .unwrap_or(i64::MIN)
+    }
+
+    /// Estimates the fee a transaction of the given serialized size has to pay to be mined soon.
+    ///
+    /// Based on the median fee per byte paid in the last `FEE_ESTIMATE_BLOCKS` blocks. If more
+    /// transactions are waiting than these blocks included on average, the transactions compete
+    /// for the next blocks and the median fee per byte of the waiting ones is taken if higher.
+    pub fn estimate_fee(&self, size: usize) -> u32 {
+        let start = self.headers.len().saturating_sub(FEE_ESTIMATE_BLOCKS);
+        let recent: Vec<f64> = (start..self.headers.len())
+            .filter_map(|height| self.block_at(height))
+            .flat_map(|block| block.transactions().iter().skip(1).map(fee_rate).collect::<Vec<f64>>())
+            .collect();
+        let blocks = self.headers.len() - start;
+        let mut rate = median(recent.clone());
+        if self.mempool.len() * blocks > recent.len() {
+            rate = rate.max(median(self.mempool.transactions().map(fee_rate).collect()));
+        }
+        (rate * size as f64).ceil() as u32
+    }
+
+    /// Mines a block containing the waiting transactions and appends it to the chain.
+    ///
+    /// Blocks until the block is mined. Returns the finished mining job, e.g. to report its hash
+    /// rate, or `None` if the chain rejected the block.
+    pub fn add_new_block(&mut self) -> Option<MiningJob> {
+        let mut block = self.block_template();
+        let job = self.miner.mine(&mut block.header);
+        self.add_block(block).ok().map(|_| job)
+    }
+
+    /// The compact target of the next block on the active chain.
+    fn next_bits(&self) -> u32 {
+        self.spec.retarget.next_bits(self.headers.len(), |height| self.headers.get(height))
+            .unwrap_or(self.spec.genesis.bits)
+    }
+
+    /// The parameters of the chain.
+    pub fn spec(&self) -> &ChainSpec {
+        &self.spec
+    }
+
+    /// The address the blocks mined by the node pay their reward to.
+    pub fn miner_address(&self) -> &str {
+        &self.miner_addr
+    }
+
+    /// Identifies the network of the chain, its name and the hash of its whole spec, so nodes
+    /// only join peers which agree on the genesis block and on every consensus parameter.
+    pub fn network_id(&self) -> String {
+        format!("{}:{}", self.spec.network, hash::hash(&self.spec))
+    }
+
+    /// The headers of the active chain, starting with the genesis block.
+    pub fn headers(&self) -> &[BlockHeader] {
+        &self.headers
+    }
+
+    /// The block of the active chain at the height, loaded from the archive unless it is recent.
+    pub fn block_at(&self, height: usize) -> Option<Block<T>> {
+        let header = self.headers.get(height)?;
+        if height >= self.base {
+            return self.forks.get(&header.hash()).cloned();
+        }
+        self.archive.get(height)
+    }
+
+    /// Whether the block is known, either on the active chain or a recent competing branch.
+    pub fn contains(&self, hash: &str) -> bool {
+        self.forks.contains(hash) || self.heights.contains_key(hash)
+    }
+
+    /// The known block with the given hash.
+    pub fn block(&self, hash: &str) -> Option<Block<T>> {
+        match self.forks.get(hash) {
+            Some(block) => Some(block.clone()),
+            None => self.block_at(*self.heights.get(hash)?),
+        }
+    }
+
+    /// The height of the block if it is on the active chain.
+    fn active_height(&self, hash: &str) -> Option<usize> {
+        self.heights.get(hash).cloned()
+    }
+
+    /// The height of the known block with the given hash.
+    pub fn height_of(&self, hash: &str) -> Option<usize> {
+        self.forks.height(hash).or_else(|| self.active_height(hash))
+    }
+
+    /// Hashes of blocks on the active chain, starting at the tip and spaced exponentially further
+    /// apart down to the genesis block.
+    ///
+    /// A peer finds the last block both share in the first hash it knows.
+    pub fn locator(&self) -> Vec<String> {
+        let mut locator = Vec::new();
+        let mut height = self.headers.len();
+        let mut step = 1;
+        while height > 0 {
+            height = height.saturating_sub(step);
+            locator.push(self.headers[height].hash());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+        }
+        locator
+    }
+
+    /// The headers of at most `max` blocks of the active chain following the first block of the
+    /// locator on the active chain, or following no block if none is.
+    pub fn headers_after(&self, locator: &[String], max: usize) -> Vec<BlockHeader> {
+        let start = locator.iter()
+            .filter_map(|hash| self.active_height(hash))
+            .next()
+            .map_or(0, |height| height + 1);
+        self.headers.iter()
+            .skip(start)
+            .take(max)
+            .cloned()
+            .collect()
+    }
+
+    /// The accumulated work of all blocks in the chain.
+    pub fn total_work(&self) -> u128 {
+        self.forks.work(&self.last_hash()).unwrap_or(0)
+    }
+
+    /// The header at the height on the branch ending in the given block, which is either recent
+    /// or on the active chain.
+    pub fn header_on(&self, tip: &str, height: usize) -> Option<&BlockHeader> {
+        if height < self.base || self.active_height(tip).map_or(false, |tip| height <= tip) {
+            // the active chain, which all recent branches share below the base
+            return self.headers.get(height);
+        }
+        self.forks.ancestor(tip, height).map(|block| &block.header)
+    }
+
+    /// Adds a block, e.g. received from a peer, to the known blocks.
+    ///
+    /// Switches to the branch of the block if it has accumulated more work than the active chain.
+    /// Blocks of branches forking off more than `MAX_FORK_DEPTH` blocks below the tip are
+    /// rejected as their parent is unknown.
+    /// Returns whether the active chain changed or the rule the block broke.
+    pub fn add_block(&mut self, block: Block<T>) -> Result<bool, Rule> {
+        if self.contains(&block.hash()) {
+            return Ok(false);
+        }
+        let parent = block.header.pre_hash().to_string();
+        let height = if parent == genesis_pre_hash() {
+            0
+        } else {
+            match self.forks.height(&parent) {
+                Some(height) => height + 1,
+                None => return Err(Rule::UnknownParent),
+            }
+        };
+        if block.header.timestamp() > time::now().to_timespec().sec + MAX_FUTURE_DRIFT {
+            return Err(Rule::FutureTimestamp);
+        }
+        self.check_block(&block, &parent, height, true, |height| self.header_on(&parent, height))?;
+
+        // a block extending the active chain is applied to its state right away
+        if parent == self.last_hash() {
+            if let Err(error) = Chain::replay(&mut self.state, slice::from_ref(&block), height) {
+                self.rebuild_state();
+                return Err(error.rule);
+            }
+            if let Err(rule) = self.forks.insert(block.clone()) {
+                self.rebuild_state();
+                return Err(rule);
+            }
+            self.extend(height, vec![block], Vec::new());
+            return Ok(true);
+        }
+
+        let mut state = self.base_state.clone();
+        Chain::replay(&mut state, &self.forks.branch(&parent), self.base).map_err(|error| error.rule)?;
+        Chain::replay(&mut state, slice::from_ref(&block), height).map_err(|error| error.rule)?;
+
+        self.forks.insert(block)?;
+        Ok(self.choose_fork())
     }
 
-    pub fn add_new_block(&mut self) -> bool  {
-        let mut block = Block::<T>::new(
-            self.last_hash(), self.difficulty,
-            self.miner_addr.clone(), self.reward, &mut self.curr_trans);
+    /// Checks the block at the height on top of its parent against all rules except the ledger.
+    ///
+    /// `header_at` looks up the headers of the preceding blocks by height.
+    fn check_block<'a, F>(&self, block: &Block<T>, parent: &str, height: usize, signatures: bool, header_at: F)
+        -> Result<(), Rule>
+    where F: Fn(usize) -> Option<&'a BlockHeader>
+    {
+        if height == 0 {
+            return self.verify_genesis(block);
+        }
+        let expected = self.spec.retarget.next_bits(height, &header_at);
+        if expected.map_or(false, |bits| bits != block.header.bits) {
+            return Err(Rule::Difficulty);
+        }
+        if median_time_past(height, &header_at).map_or(false, |past| block.header.timestamp() <= past) {
+            return Err(Rule::Timestamp);
+        }
+        self.spec.limits.check(block)?;
+        block.verify(parent)?;
+        self.verify_reward(block, height)?;
+        if signatures {
+            self.verify_signatures(block)?;
+        }
+        Ok(())
+    }
 
+    /// Adds all blocks of another chain, e.g. received in a pong, to the known blocks.
+    ///
+    /// Public keys of senders unknown so far are taken from the other chain once its blocks are
+    /// valid and its keys verified all of their transactions.
+    /// Switches to the other chain if it has accumulated more work than the active chain.
+    /// Returns whether the active chain changed or why the other chain is invalid.
+    pub fn merge(&mut self, other: &Chain<T>) -> Result<bool, ValidationError> {
+        let blocks: Vec<Block<T>> = (0..other.headers.len()).filter_map(|height| other.block_at(height)).collect();
+        self.validate_blocks(blocks.iter().cloned(), false)?;
+        if let Some(height) = blocks.iter().position(|block| other.verify_signatures(block).is_err()) {
+            return Err(ValidationError { height, rule: Rule::Signature });
+        }
+        self.import_keys(other.keys_for(&blocks));
+        let mut changed = false;
+        for (height, block) in blocks.into_iter().enumerate() {
+            changed |= self.add_block(block).map_err(|rule| ValidationError { height, rule })?;
+        }
+        Ok(changed)
+    }
 
-        Chain::<T>::proof_of_work(&mut block.header);
-        println!("{}", &block.fmt());
-        self.chain.push(block);
-        self.curr_trans.clear();
-        if self.chain.len() % 100 == 0 {
-           self.difficulty += 1; 
-           self.reward += 1;
+    /// Switches to the heaviest known branch if it has accumulated more work than the active chain.
+    fn choose_fork(&mut self) -> bool {
+        let tip = match self.forks.heaviest() {
+            Some(tip) => tip.to_string(),
+            None => return false,
+        };
+        if self.forks.work(&tip).unwrap_or(0) <= self.total_work() {
+            return false;
         }
+        let branch = self.forks.branch(&tip);
+        let fork = branch.iter().enumerate()
+            .take_while(|(offset, block)| self.active_height(&block.hash()) == Some(self.base + offset))
+            .count();
+        let height = self.base + fork;
+        let displaced = (height..self.headers.len())
+            .filter_map(|height| self.block_at(height))
+            .collect();
+        for header in self.headers.split_off(height) {
+            self.heights.remove(&header.hash());
+        }
+
+        // blocks in the tree passed the ledger check against their parent
+        self.rebuild_state();
+        let branch: Vec<Block<T>> = branch.into_iter().skip(fork).collect();
+        Chain::replay(&mut self.state, &branch, height)
+            .expect("[Chain choose_fork()]: Branch contains an invalid transaction!");
+        self.extend(height, branch, displaced);
         true
     }
 
+    /// Appends the blocks of the tree, whose transactions are applied to the state already, to
+    /// the active chain at the height.
+    ///
+    /// Transactions of displaced blocks that are not part of the new blocks are returned to the
+    /// mempool, except for the rewards. Waiting transactions the ledger no longer accepts are
+    /// dropped.
+    fn extend(&mut self, height: usize, blocks: Vec<Block<T>>, displaced: Vec<Block<T>>) {
+        for block in &blocks {
+            self.push(block);
+        }
+        self.archive.replace(height, &blocks);
+
+        if displaced.is_empty() {
+            // the pending state only has to be rebuilt after a reorg
+            self.mempool.confirm(&blocks, height, &self.state, self.headers.len());
+        } else {
+            let included: HashSet<String> = blocks.iter()
+                .flat_map(|block| block.transactions().iter())
+                .map(|transaction| transaction.id())
+                .collect();
+            self.mempool.remove(&included);
+
+            let now = Instant::now();
+            for block in displaced {
+                for transaction in block.transactions().iter().skip(1) {
+                    if !included.contains(&transaction.id()) {
+                        self.mempool.reinsert(transaction.clone(), now);
+                    }
+                }
+            }
+            self.revalidate_pending();
+        }
+        self.bits = self.next_bits();
+        self.slide();
+    }
+
+    /// Appends the header of the block to the active chain.
+    fn push(&mut self, block: &Block<T>) {
+        self.heights.insert(block.hash(), self.headers.len());
+        self.headers.push(block.header.clone());
+    }
+
+    /// Moves the base up to `MAX_FORK_DEPTH` blocks below the tip, dropping older blocks and
+    /// the branches forking off below it from memory.
+    fn slide(&mut self) {
+        let base = self.headers.len().saturating_sub(MAX_FORK_DEPTH);
+        if base <= self.base {
+            return;
+        }
+        for height in self.base..base {
+            let block = self.forks.get(&self.headers[height].hash())
+                .expect("[Chain slide()]: Recent block is missing!");
+            Chain::replay(&mut self.base_state, slice::from_ref(block), height)
+                .expect("[Chain slide()]: Chain contains an invalid transaction!");
+        }
+        self.base = base;
+        let root = self.headers[base].hash();
+        self.forks.prune(&root);
+    }
+
+    /// Rebuilds the ledger state of the recent blocks of the active chain on top of the base
+    /// state, e.g. after a rollback.
+    fn rebuild_state(&mut self) {
+        let mut state = self.base_state.clone();
+        for height in self.base..self.headers.len() {
+            let block = self.forks.get(&self.headers[height].hash())
+                .expect("[Chain rebuild_state()]: Recent block is missing!");
+            Chain::replay(&mut state, slice::from_ref(block), height)
+                .expect("[Chain rebuild_state()]: Chain contains an invalid transaction!");
+        }
+        self.state = state;
+    }
+
+    /// Applies the transactions of the blocks, starting at the given height, to the ledger state.
+    ///
+    /// Returns the height of the first block containing a rejected transaction.
+    fn replay(state: &mut ChainState<T>, blocks: &[Block<T>], height: usize) -> Result<(), ValidationError> {
+        for (offset, block) in blocks.iter().enumerate() {
+            let height = height + offset;
+            for (index, transaction) in block.transactions().iter().enumerate() {
+                state.apply(transaction, height, index)
+                    .map_err(|error| ValidationError { height, rule: Rule::Ledger(error) })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rechecks the waiting transactions on top of the active chain, dropping those the ledger
+    /// rejects.
+    fn revalidate_pending(&mut self) {
+        self.mempool.revalidate(&self.state, self.headers.len());
+    }
+
+    /// The waiting transaction with the id, if any.
+    pub fn transaction(&self, id: &str) -> Option<&Transaction<T>> {
+        self.mempool.get(id)
+    }
+
     pub fn get_no_curr_trans(&self) -> usize {
-        self.curr_trans.len()
+        self.mempool.len()
     }
 
     pub fn proof_of_work(header: &mut BlockHeader) {
-        loop {
-            let hash = hash::hash(header);
-            let slice = &hash[..header.difficulty as usize];
-            match slice.parse::<u32>() {
-                Ok(val) => {
-                    if val != 0 {
-                        header.nonce += 1;
-                    } else {
-                        println!("Block hash: {}", hash);
-                        break;
-                    }
-                }
-                Err(_) => {
-                    header.nonce += 1;
-                    continue;
-                }
-            };
+        header.mine();
+    }
+
+    /// Validates the whole chain starting from the genesis block, loading all blocks from the
+    /// archive.
+    ///
+    /// The first block has to be the genesis block of the spec. Checks for every other block the
+    /// linkage to its predecessor, the difficulty, the block limits, the transaction count, the
+    /// reward transaction, the merkle root, the proof of work, the subsidy, the signatures and
+    /// whether the ledger accepts its transactions.
+    /// Returns the height of the first offending block and the rule it broke.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        self.validate_blocks((0..self.headers.len()).map(|height| {
+            self.block_at(height).expect("[Chain validate()]: Block is missing in the archive!")
+        }), true)
+    }
+
+    /// Validates the given blocks as a chain using the parameters of this chain.
+    fn validate_blocks<I>(&self, blocks: I, signatures: bool) -> Result<(), ValidationError>
+    where I: IntoIterator<Item = Block<T>>
+    {
+        let mut state = ChainState::default();
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        let mut pre_hash = genesis_pre_hash();
+        for (height, block) in blocks.into_iter().enumerate() {
+            self.check_block(&block, &pre_hash, height, signatures, |height| headers.get(height))
+                .map_err(|rule| ValidationError { height, rule })?;
+            Chain::replay(&mut state, slice::from_ref(&block), height)?;
+            pre_hash = block.hash();
+            headers.push(block.header);
         }
+        Ok(())
     }
 
     pub fn fmt(&self) -> String {
@@ -110,18 +940,18 @@ where T: Serialize + DeserializeOwned + Debug + Clone + Transactional + Send
 
         write!(&mut str, "Chain [\n").expect("[Chain fmt()]: Unable to write in Buffer!");
 
-        for block in &self.chain {
+        for block in (0..self.headers.len()).filter_map(|height| self.block_at(height)) {
             write!(&mut str, "{}", block.fmt()).expect("[Chain fmt()]: Unable to write in Buffer!");
         }
 
         write!(&mut str, "    Current Transactions: [\n").expect("[Chain fmt()]: Unable to write in Buffer!");
 
-        for trans in &self.curr_trans {
+        for trans in self.mempool.transactions() {
             write!(&mut str, "{:?}", trans.fmt()).expect("[Chain fmt()]: Unable to write in Buffer!");
         }
 
         write!(&mut str, "    ]\n").expect("[Chain fmt()]: Unable to write in Buffer!");
-        write!(&mut str, "    Difficulty:    {}\n", &self.difficulty).expect("[Chain fmt()]: Unable to write in Buffer!");
+        write!(&mut str, "    Bits:          {:#010x}\n", &self.bits).expect("[Chain fmt()]: Unable to write in Buffer!");
         write!(&mut str, "    Miner address: {}\n", &self.miner_addr).expect("[Chain fmt()]: Unable to write in Buffer!");
         write!(&mut str, "]\n").expect("[Chain fmt()]: Unable to write in Buffer!");
 
@@ -129,11 +959,70 @@ where T: Serialize + DeserializeOwned + Debug + Clone + Transactional + Send
     }
 }
 
+/// The fee per byte the transaction pays.
+fn fee_rate<T: Serialize>(transaction: &Transaction<T>) -> f64 {
+    f64::from(transaction.fee) / transaction.size() as f64
+}
+
+/// The median of the values, 0 if there are none.
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal));
+    values.get(values.len() / 2).cloned().unwrap_or(0.0)
+}
+
+/// The median timestamp of the up to `MEDIAN_TIME_SPAN` blocks preceding the height, looked up by
+/// `header_at`. `None` for the genesis block and if a header is missing.
+fn median_time_past<'a, F>(height: usize, header_at: F) -> Option<i64>
+where F: Fn(usize) -> Option<&'a BlockHeader>
+{
+    let mut timestamps = (height.saturating_sub(MEDIAN_TIME_SPAN)..height)
+        .map(|height| header_at(height).map(BlockHeader::timestamp))
+        .collect::<Option<Vec<i64>>>()?;
+    timestamps.sort();
+    timestamps.get(timestamps.len() / 2).cloned()
+}
+
+impl Chain<CryptoPayload> {
+    /// The balance of the address on the active chain.
+    pub fn balance_of(&self, address: &str) -> u32 {
+        self.state.ledger.balance_of(address)
+    }
+
+    /// The transfers sent or received by the address on the active chain with the height of
+    /// their block, oldest first.
+    ///
+    /// Loads all blocks of the active chain, the ledger state only keeps the balances.
+    pub fn history_of(&self, address: &str) -> Vec<(usize, Transaction<CryptoPayload>)> {
+        let mut history = Vec::new();
+        for height in 0..self.headers.len() {
+            for transaction in self.block_at(height).iter().flat_map(|block| block.transactions()) {
+                let sent = transaction.sender != REWARD_SENDER && transaction.sender == address;
+                if sent || transaction.payload.read().unwrap().receiver == address {
+                    history.push((height, transaction.clone()));
+                }
+            }
+        }
+        history
+    }
+}
+
+impl Chain<UtxoPayload> {
+    /// The sum of the unspent outputs of the address on the active chain.
+    pub fn balance_of(&self, address: &str) -> u64 {
+        self.state.ledger.balance_of(address)
+    }
+
+    /// The unspent outputs of the address on the active chain.
+    pub fn unspent_of(&self, address: &str) -> Vec<(OutPoint, Output)> {
+        self.state.ledger.unspent_of(address)
+    }
+}
+
 impl<T> PartialEq for Chain<T>
 where T: Serialize + DeserializeOwned + Transactional + Clone + Transactional
 {
     fn eq(&self, other: &Self) -> bool {
-        self.chain.first().eq(&other.chain.first())
+        self.headers.eq(&other.headers)
     }
 }
 
@@ -141,3 +1030,453 @@ impl<T> Eq for Chain<T>
 where T: Transactional + DeserializeOwned
 {}
 
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use sequoia_openpgp::TPK;
+    use uuid::Uuid;
+
+    use crate::blockchain::block::{Block, BlockLimits};
+    use crate::blockchain::chain::{Chain, MAX_FORK_DEPTH, MAX_FUTURE_DRIFT};
+    use crate::blockchain::mempool::MempoolError;
+    use crate::blockchain::pow::MAX_BITS;
+    use crate::blockchain::retarget::Retarget;
+    use crate::blockchain::spec::ChainSpec;
+    use crate::blockchain::transaction::{CryptoPayload, Output, Transaction, Transactional, UtxoPayload};
+    use crate::blockchain::utxo::COINBASE_MATURITY;
+    use crate::blockchain::validation::{Rule, TransactionError, ValidationError};
+    use crate::crypto::pgp;
+    use crate::storage::chain::{ChainStore, NAMESPACE};
+    use crate::storage::hashmap::Namespaces;
+    use crate::storage::storage::{Namespaced, Storage};
+
+    thread_local! {
+        /// The key pair of the miner "Schwurbel", generated once per test thread.
+        static KEY: TPK = pgp::generate(Uuid::new_v4()).unwrap().0;
+    }
+
+    fn transfer(receiver: &str, amount: u32, nonce: u64) -> Vec<Transaction<CryptoPayload>> {
+        let crypto_payload = CryptoPayload {
+            receiver: String::from(receiver),
+            amount,
+        };
+        KEY.with(|key| vec![CryptoPayload::signed(String::from("Schwurbel"), crypto_payload, nonce, key).unwrap()])
+    }
+
+    fn blocks<T: Transactional>(chain: &Chain<T>) -> Vec<Block<T>> {
+        (0..chain.headers.len()).map(|height| chain.block_at(height).unwrap()).collect()
+    }
+
+    fn chain() -> Chain<CryptoPayload> {
+        let mut chain = Chain::new(String::from("Schwurbel"), MAX_BITS);
+        KEY.with(|key| chain.add_key(String::from("Schwurbel"), key)).unwrap();
+        chain.add_transaction(&mut transfer("Peter", 42, 0));
+        chain.add_new_block();
+        chain
+    }
+
+    #[test]
+    fn validate_chain() {
+        assert_eq!(chain().validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_broken_link() {
+        let chain = chain();
+        let mut blocks = blocks(&chain);
+        blocks.swap(0, 1);
+        assert_eq!(chain.validate_blocks(blocks, true), Err(ValidationError { height: 0, rule: Rule::Genesis }));
+
+        let mut chain = self::chain();
+        chain.add_new_block();
+        let mut blocks = self::blocks(&chain);
+        blocks.swap(1, 2);
+        assert_eq!(chain.validate_blocks(blocks, true), Err(ValidationError { height: 1, rule: Rule::PreviousHash }));
+    }
+
+    #[test]
+    fn validate_tampered_header() {
+        let chain = chain();
+        let mut blocks = blocks(&chain);
+        blocks[1].header.bits -= 1;
+        assert_eq!(chain.validate_blocks(blocks, true), Err(ValidationError { height: 1, rule: Rule::Difficulty }));
+
+        let mut blocks = self::blocks(&chain);
+        while blocks[1].header.meets_target() {
+            blocks[1].header.nonce += 1;
+        }
+        assert_eq!(chain.validate_blocks(blocks, true), Err(ValidationError { height: 1, rule: Rule::ProofOfWork }));
+
+        let mut blocks = self::blocks(&chain);
+        blocks[1].header.reward += 1;
+        assert_eq!(chain.validate_blocks(blocks, true), Err(ValidationError { height: 1, rule: Rule::Reward }));
+    }
+
+    #[test]
+    fn fork_choice_by_work() {
+        let mut chain = chain();
+        let mut fork = chain.try_clone().unwrap();
+        chain.add_transaction(&mut transfer("Paul", 1, 1));
+        chain.add_new_block();
+        fork.add_new_block();
+
+        assert_eq!(chain.merge(&fork), Ok(false));
+        fork.add_new_block();
+        assert_eq!(chain.merge(&fork), Ok(true));
+        assert_eq!(chain.headers, fork.headers);
+        // the transfer to Paul was displaced by the reorg
+        assert_eq!(chain.get_no_curr_trans(), 1);
+        assert_eq!(chain.mempool.transactions().next().unwrap().payload.read().unwrap().receiver, "Paul");
+        assert_eq!(chain.balance_of("Paul"), 0);
+        assert_eq!(chain.next_nonce("Schwurbel"), 2);
+    }
+
+    #[test]
+    fn merge_keys_after_validation() {
+        let fork = chain();
+        let mut chain = Chain::from_blocks(String::from("Peter"), fork.spec().clone(), blocks(&fork)[..1].to_vec()).unwrap();
+
+        // keys which don't verify the transactions of the other chain are not taken
+        let mut forged = fork.try_clone().unwrap();
+        forged.add_key(String::from("Schwurbel"), &pgp::generate(Uuid::new_v4()).unwrap().0).unwrap();
+        assert_eq!(chain.merge(&forged), Err(ValidationError { height: 1, rule: Rule::Signature }));
+        assert!(chain.keys.is_empty());
+
+        let mut other = fork.try_clone().unwrap();
+        let key = other.keys["Schwurbel"].clone();
+        other.keys.insert(String::from("Mallory"), key);
+        assert_eq!(chain.merge(&other), Ok(true));
+        assert_eq!(chain.headers, fork.headers);
+        // only the keys which signed transactions of the blocks are taken
+        assert_eq!(chain.keys, fork.keys);
+    }
+
+    #[test]
+    fn keep_verified_keys() {
+        let fork = chain();
+        let mut chain = Chain::from_blocks(String::from("Peter"), fork.spec().clone(), blocks(&fork)[..1].to_vec()).unwrap();
+        let mut forged = HashMap::new();
+        forged.insert(String::from("Schwurbel"), pgp::public_bytes(&pgp::generate(Uuid::new_v4()).unwrap().0).unwrap());
+
+        let block = fork.block_at(1).unwrap();
+        assert_eq!(chain.add_block_with_keys(block.clone(), &forged), Err(Rule::Signature));
+        assert!(chain.keys.is_empty());
+        assert_eq!(chain.add_block_with_keys(block, &fork.keys), Ok(true));
+        assert_eq!(chain.keys, fork.keys);
+
+        let mut chain = Chain::from_blocks(String::from("Peter"), fork.spec().clone(), blocks(&fork)[..1].to_vec()).unwrap();
+        let overdraft = transfer("Paul", 1000, 0).remove(0);
+        assert_eq!(chain.queue_transaction_with_keys(overdraft, &fork.keys),
+                   Err(TransactionError::Overdraft.into()));
+        assert!(chain.keys.is_empty());
+        let transaction = transfer("Paul", 1, 0).remove(0);
+        assert_eq!(chain.queue_transaction_with_keys(transaction.clone(), &forged),
+                   Err(TransactionError::Signature.into()));
+        assert_eq!(chain.queue_transaction_with_keys(transaction, &fork.keys), Ok(()));
+        assert_eq!(chain.keys, fork.keys);
+    }
+
+    #[test]
+    fn balances() {
+        let chain = chain();
+        assert_eq!(chain.balance_of("Schwurbel"), 2 * 100 - 42);
+        assert_eq!(chain.balance_of("Peter"), 42);
+        assert_eq!(chain.history_of("Peter").len(), 1);
+        assert_eq!(chain.history_of("Peter")[0].0, 1);
+    }
+
+    #[test]
+    fn reject_transactions() {
+        let mut chain = chain();
+        assert!(!chain.add_transaction(&mut transfer("Paul", 1000, 1)));
+        assert!(!chain.add_transaction(&mut transfer("Paul", 0, 1)));
+        assert!(chain.add_transaction(&mut transfer("Paul", 100, 1)));
+        // the pending transfer to Paul already spent most of the coins
+        assert_eq!(chain.queue_transaction(transfer("Mary", 100, 2).remove(0)), Err(TransactionError::Overdraft.into()));
+        // neither the included nor the pending transfer can be replayed
+        assert_eq!(chain.queue_transaction(transfer("Peter", 42, 0).remove(0)), Err(TransactionError::Nonce.into()));
+        assert_eq!(chain.queue_transaction(transfer("Mary", 1, 1).remove(0)), Err(TransactionError::Nonce.into()));
+        assert_eq!(chain.next_nonce("Schwurbel"), 2);
+        assert_eq!(chain.queue_transaction(CryptoPayload::genesis(String::from("Mary"), 100)), Err(TransactionError::Reward.into()));
+        assert_eq!(chain.get_no_curr_trans(), 1);
+
+        let mut chain = self::chain();
+        let mut block = Block::new(chain.headers[0].hash(), MAX_BITS, String::from("Schwurbel"), 100,
+                                   &mut transfer("Paul", 1000, 0));
+        block.header.advance_to(chain.median_time_past() + 1);
+        block.header.mine();
+        assert_eq!(chain.add_block(block.clone()), Err(Rule::Ledger(TransactionError::Overdraft)));
+        let mut blocks = blocks(&chain);
+        blocks[1] = block;
+        assert_eq!(chain.validate_blocks(blocks, true), Err(ValidationError { height: 1, rule: Rule::Ledger(TransactionError::Overdraft) }));
+    }
+
+    #[test]
+    fn add_block() {
+        let mut chain = chain();
+        let mut fork = chain.try_clone().unwrap();
+        fork.add_new_block();
+        fork.add_new_block();
+
+        assert_eq!(chain.add_block(fork.block_at(3).unwrap()), Err(Rule::UnknownParent));
+        assert_eq!(chain.add_block(fork.block_at(2).unwrap()), Ok(true));
+        assert_eq!(chain.add_block(fork.block_at(3).unwrap()), Ok(true));
+        assert_eq!(chain.add_block(fork.block_at(3).unwrap()), Ok(false));
+        assert_eq!(chain.total_work(), fork.total_work());
+    }
+
+    #[test]
+    fn reject_unsigned_transactions() {
+        let mut chain = chain();
+        let mut unsigned = transfer("Paul", 1, 1);
+        unsigned[0].signature.clear();
+        assert_eq!(chain.queue_transaction(unsigned[0].clone()), Err(TransactionError::Signature.into()));
+
+        let tampered = transfer("Paul", 1, 1);
+        tampered[0].payload.write().unwrap().amount = 100;
+        assert_eq!(chain.queue_transaction(tampered[0].clone()), Err(TransactionError::Signature.into()));
+
+        let stranger = CryptoPayload::new(String::from("Peter"), CryptoPayload { receiver: String::from("Paul"), amount: 1 });
+        assert_eq!(chain.queue_transaction(stranger), Err(TransactionError::UnknownSender.into()));
+        assert_eq!(chain.get_no_curr_trans(), 0);
+
+        let mut block = Block::new(chain.last_hash(), MAX_BITS, String::from("Schwurbel"), 100, &mut unsigned);
+        block.header.advance_to(chain.median_time_past() + 1);
+        block.header.mine();
+        assert_eq!(chain.add_block(block.clone()), Err(Rule::Signature));
+        let mut blocks = blocks(&chain);
+        blocks.push(block);
+        assert_eq!(chain.validate_blocks(blocks, true), Err(ValidationError { height: 2, rule: Rule::Signature }));
+    }
+
+    #[test]
+    fn reject_double_spends() {
+        let mut chain: Chain<UtxoPayload> = Chain::new(String::from("Schwurbel"), MAX_BITS);
+        KEY.with(|key| chain.add_key(String::from("Schwurbel"), key)).unwrap();
+        let (input, _) = chain.unspent_of("Schwurbel").remove(0);
+        let payment = |receiver: &str, nonce| KEY.with(|key| UtxoPayload::signed(String::from("Schwurbel"), UtxoPayload {
+            inputs: vec![input.clone()],
+            outputs: vec![Output { receiver: String::from(receiver), amount: 100 }],
+        }, nonce, key).unwrap());
+        assert_eq!(chain.queue_transaction(payment("Peter", 0)), Err(TransactionError::Immature.into()));
+
+        while chain.headers.len() < COINBASE_MATURITY {
+            chain.add_new_block();
+        }
+        assert_eq!(chain.queue_transaction(payment("Peter", 0)), Ok(()));
+        assert_eq!(chain.queue_transaction(payment("Paul", 1)), Err(TransactionError::Spent.into()));
+
+        let mut block = Block::new(chain.last_hash(), chain.next_bits(), String::from("Schwurbel"), 100,
+                                   &mut vec![payment("Peter", 0), payment("Paul", 1)]);
+        block.header.advance_to(chain.median_time_past() + 1);
+        block.header.mine();
+        assert_eq!(chain.add_block(block), Err(Rule::Ledger(TransactionError::Spent)));
+
+        chain.add_new_block();
+        assert_eq!(chain.balance_of("Peter"), 100);
+        // one reward was spent
+        assert_eq!(chain.balance_of("Schwurbel"), 100 * COINBASE_MATURITY as u64);
+        assert_eq!(chain.total_supply(COINBASE_MATURITY), 100 * (COINBASE_MATURITY as u64 + 1));
+    }
+
+    #[test]
+    fn collect_fees() {
+        let mut chain = chain();
+        let paying = |receiver: &str, nonce: u64, fee: u32| KEY.with(|key| CryptoPayload::signed_with_fee(String::from("Schwurbel"), CryptoPayload {
+            receiver: String::from(receiver),
+            amount: 10,
+        }, nonce, fee, key).unwrap());
+        let size = paying("Peter", 1, 5).size();
+
+        chain.add_transaction(&mut vec![paying("Peter", 1, 5)]);
+        chain.add_new_block();
+        assert_eq!(chain.headers[2].reward, chain.subsidy(2) + 5);
+        // the miner paid the fee to itself
+        assert_eq!(chain.balance_of("Schwurbel"), 3 * 100 - 42 - 10);
+        assert!(chain.estimate_fee(size) >= 5);
+        // the waiting transactions outbid the recent blocks
+        chain.queue_transaction(paying("Paul", 2, 50)).unwrap();
+        assert!(chain.estimate_fee(size) > 40);
+
+        let mut block = Block::new(chain.last_hash(), chain.next_bits(), String::from("Schwurbel"), chain.subsidy(3) + 51,
+                                   &mut vec![paying("Mary", 2, 50)]);
+        block.header.advance_to(chain.median_time_past() + 1);
+        block.header.mine();
+        assert_eq!(chain.add_block(block.clone()), Err(Rule::Subsidy));
+        // the miner can't keep less than the schedule either
+        let mut underpaid = Block::new(chain.last_hash(), chain.next_bits(), String::from("Schwurbel"), chain.subsidy(3) + 49,
+                                       &mut vec![paying("Mary", 2, 50)]);
+        underpaid.header.advance_to(chain.median_time_past() + 1);
+        underpaid.header.mine();
+        assert_eq!(chain.add_block(underpaid), Err(Rule::Subsidy));
+        let mut blocks = blocks(&chain);
+        blocks.push(block);
+        assert_eq!(chain.validate_blocks(blocks, true), Err(ValidationError { height: 3, rule: Rule::Subsidy }));
+    }
+
+    #[test]
+    fn enforce_block_limits() {
+        let spec = chain().spec().clone();
+        let limited = |limits: BlockLimits| {
+            let mut chain = Chain::from_spec(String::from("Schwurbel"), ChainSpec { limits, ..spec.clone() }).unwrap();
+            KEY.with(|key| chain.add_key(String::from("Schwurbel"), key)).unwrap();
+            chain
+        };
+
+        let mut chain = limited(BlockLimits { max_transactions: 3, ..BlockLimits::default() });
+        chain.queue_transactions(&mut transfer("Peter", 1, 0));
+        assert!(!chain.is_block_due());
+        chain.queue_transactions(&mut transfer("Paul", 1, 1));
+        chain.queue_transactions(&mut transfer("Mary", 1, 2));
+        assert!(chain.is_block_due());
+        assert_eq!(chain.block_template().transactions().len(), 3);
+
+        let mut block = Block::new(chain.last_hash(), chain.next_bits(), String::from("Schwurbel"), 100,
+                                   &mut chain.mempool.transactions().cloned().collect());
+        block.header.advance_to(chain.median_time_past() + 1);
+        block.header.mine();
+        assert_eq!(chain.add_block(block.clone()), Err(Rule::TransactionLimit));
+
+        let mut chain = limited(BlockLimits { max_transaction_size: 10, ..BlockLimits::default() });
+        assert_eq!(chain.add_block(block.clone()), Err(Rule::TransactionSize));
+        let transaction = transfer("Peter", 1, 0).remove(0);
+        let size = transaction.size();
+        assert_eq!(chain.queue_transaction(transaction), Err(MempoolError::TooLarge(size)));
+
+        let mut chain = limited(BlockLimits { max_size: block.size() - 1, ..BlockLimits::default() });
+        assert_eq!(chain.add_block(block.clone()), Err(Rule::BlockSize));
+        let mut blocks = blocks(&chain);
+        blocks.push(block);
+        assert_eq!(chain.validate_blocks(blocks, true), Err(ValidationError { height: 1, rule: Rule::BlockSize }));
+    }
+
+    #[test]
+    fn enforce_timestamps() {
+        let mut chain = chain();
+        let now = time::now().to_timespec().sec;
+        let mut block = chain.block_template();
+        block.header.advance_to(now + MAX_FUTURE_DRIFT + 60);
+        block.header.mine();
+        assert_eq!(chain.add_block(block), Err(Rule::FutureTimestamp));
+
+        // blocks from the near future lift the median time past of the next block above now
+        for _ in 0..2 {
+            let mut block = chain.block_template();
+            block.header.advance_to(now + 600);
+            block.header.mine();
+            assert_eq!(chain.add_block(block), Ok(true));
+        }
+        assert!(chain.median_time_past() >= now + 600);
+
+        let mut block = Block::new(chain.last_hash(), chain.next_bits(), String::from("Schwurbel"), chain.subsidy(4),
+                                   &mut vec![]);
+        block.header.mine();
+        assert_eq!(chain.add_block(block.clone()), Err(Rule::Timestamp));
+        let mut blocks = blocks(&chain);
+        blocks.push(block);
+        assert_eq!(chain.validate_blocks(blocks, true), Err(ValidationError { height: 4, rule: Rule::Timestamp }));
+
+        assert!(chain.add_new_block().is_some());
+        assert!(chain.headers[4].timestamp() > now + 600);
+    }
+
+    #[test]
+    fn from_blocks() {
+        let chain = chain();
+        let loaded = Chain::from_blocks(String::from("Schwurbel"), chain.spec().clone(), blocks(&chain)).unwrap();
+        assert_eq!(loaded, chain);
+        assert_eq!(loaded.balance_of("Peter"), 42);
+        assert_eq!(loaded.last_hash(), chain.last_hash());
+
+        // a node starting from the same spec joins the network of the chain
+        let fresh = Chain::<CryptoPayload>::from_spec(String::from("Peter"), chain.spec().clone()).unwrap();
+        assert_eq!(fresh.network_id(), chain.network_id());
+        let mut spec = chain.spec().clone();
+        spec.emission.reward += 1;
+        assert_ne!(Chain::<CryptoPayload>::from_spec(String::from("Peter"), spec).unwrap().network_id(), chain.network_id());
+        assert_eq!(fresh.headers(), &chain.headers[..1]);
+
+        let other = Chain::<CryptoPayload>::new(String::from("Peter"), MAX_BITS);
+        assert_eq!(Chain::<CryptoPayload>::from_blocks(String::from("Schwurbel"), other.spec().clone(), blocks(&chain)).err(),
+                   Some(ValidationError { height: 0, rule: Rule::Genesis }));
+    }
+
+    #[test]
+    fn open_store() {
+        let spec = chain().spec().clone();
+        let mut namespaces = Namespaces::default();
+        let store = ChainStore::open(&mut namespaces).unwrap();
+        let mut chain: Chain<CryptoPayload> = Chain::open(String::from("Schwurbel"), spec.clone(), store).unwrap();
+        KEY.with(|key| chain.add_key(String::from("Schwurbel"), key)).unwrap();
+        chain.add_transaction(&mut transfer("Peter", 42, 0));
+        while chain.headers.len() < MAX_FORK_DEPTH + 3 {
+            chain.add_new_block();
+        }
+        // old blocks are only kept in the store
+        assert!(!chain.forks.contains(&chain.headers[1].hash()));
+        assert_eq!(chain.block_at(1).unwrap().transactions().len(), 2);
+
+        let store = ChainStore::open(&mut namespaces).unwrap();
+        let reopened: Chain<CryptoPayload> = Chain::open(String::from("Peter"), spec, store).unwrap();
+        assert_eq!(reopened.miner_address(), "Schwurbel");
+        assert_eq!(reopened.headers, chain.headers);
+        assert_eq!(reopened.block_at(1), chain.block_at(1));
+        assert_eq!(reopened.balance_of("Peter"), 42);
+        assert_eq!(reopened.keys, chain.keys);
+        assert_eq!(reopened.validate(), Ok(()));
+
+        // copies load all blocks into memory and fail if one is missing
+        assert_eq!(reopened.try_clone().unwrap().block_at(1), chain.block_at(1));
+        let key = [&b"block/"[..], &1u64.to_be_bytes()].concat();
+        namespaces.namespace(NAMESPACE).unwrap().delete(&key).unwrap();
+        assert!(reopened.try_clone().is_err());
+    }
+
+    #[test]
+    fn headers_after_locator() {
+        let mut chain = chain();
+        for _ in 0..20 {
+            chain.add_new_block();
+        }
+        let locator = chain.locator();
+        assert_eq!(locator.first(), Some(&chain.last_hash()));
+        assert_eq!(locator.last(), Some(&chain.headers()[0].hash()));
+        assert!(locator.len() < chain.headers().len());
+
+        // a peer that knows the first 5 blocks and a block of its own
+        let mut peer = Chain::<CryptoPayload>::from_blocks(String::from("Peter"), chain.spec().clone(),
+                                                           blocks(&chain)[..5].to_vec()).unwrap();
+        peer.add_new_block();
+        let headers = chain.headers_after(&peer.locator(), 10);
+        assert_eq!(headers.len(), 10);
+        assert_eq!(headers[0], chain.headers()[5]);
+        assert_eq!(chain.headers_after(&[], 1), vec![chain.headers()[0].clone()]);
+
+        let keys = chain.keys_for(&blocks(&chain)[1..2]);
+        assert!(keys.contains_key("Schwurbel"));
+        peer.import_keys(keys);
+        for header in headers {
+            let block = chain.block(&header.hash()).unwrap();
+            assert!(peer.contains(block.header.pre_hash()));
+            peer.add_block(block).unwrap();
+        }
+        assert_eq!(peer.last_hash(), chain.headers()[14].hash());
+    }
+
+    #[test]
+    fn retarget_difficulty() {
+        let retarget = Retarget {
+            window: 2,
+            block_time: 3600,
+            max_adjustment: 4,
+        };
+        let mut chain: Chain<CryptoPayload> = Chain::with_retarget(String::from("Schwurbel"), MAX_BITS, retarget.clone());
+        chain.add_new_block();
+
+        // the blocks were mined way too fast, so the difficulty rises after the first window
+        assert_eq!(chain.bits, retarget.adjust(MAX_BITS, 0));
+        assert_ne!(chain.bits, MAX_BITS);
+        assert_eq!(chain.validate(), Ok(()));
+    }
+}
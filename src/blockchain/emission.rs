@@ -0,0 +1,103 @@
+use std::cmp;
+
+use serde::{Serialize, Deserialize};
+
+/// The schedule by which blocks mint new coins.
+///
+/// The subsidy starts at `reward` and halves every `halving_interval` blocks until it falls to
+/// `tail`, which is paid from then on. No subsidy is paid beyond `max_supply`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Emission {
+    /// The subsidy of the blocks before the first halving.
+    pub reward: u32,
+    /// The number of blocks between two halvings, 0 to never halve.
+    pub halving_interval: usize,
+    /// The least subsidy of a block, 0 to end the emission once the subsidy is halved to 0.
+    pub tail: u32,
+    /// The most coins there may ever be, including the genesis allocations.
+    pub max_supply: u64,
+}
+
+impl Default for Emission {
+    fn default() -> Self {
+        Emission {
+            reward: 100,
+            halving_interval: 10_000,
+            tail: 0,
+            max_supply: 21_000_000,
+        }
+    }
+}
+
+impl Emission {
+    /// The coins in existence after the block at the height, the `initial` coins of the genesis
+    /// block plus the subsidies of the following blocks, at most `max_supply`.
+    pub fn supply(&self, initial: u64, height: usize) -> u64 {
+        let minted = self.minted(height).saturating_add(u128::from(initial));
+        cmp::min(minted, u128::from(self.max_supply)) as u64
+    }
+
+    /// The subsidy of the block at the height on top of the `initial` coins of the genesis block,
+    /// which pays none itself.
+    pub fn subsidy(&self, initial: u64, height: usize) -> u32 {
+        if height == 0 {
+            return 0;
+        }
+        let subsidy = self.supply(initial, height).saturating_sub(self.supply(initial, height - 1));
+        cmp::min(subsidy, u64::from(u32::MAX)) as u32
+    }
+
+    /// The sum of the subsidies of the blocks from height 1 up to and including the height,
+    /// ignoring `max_supply`.
+    fn minted(&self, height: usize) -> u128 {
+        let mut minted = 0u128;
+        let mut start = 1usize;
+        let mut halvings = 0u32;
+        while start <= height {
+            let halved = self.reward.checked_shr(halvings).unwrap_or(0);
+            let subsidy = u128::from(cmp::max(halved, self.tail));
+            // the subsidy stays the same for the remaining blocks
+            if self.halving_interval == 0 || halved <= self.tail {
+                return minted + subsidy * (height - start + 1) as u128;
+            }
+            let end = cmp::min(height, start.saturating_add(self.halving_interval - 1));
+            minted += subsidy * (end - start + 1) as u128;
+            start = end.saturating_add(1);
+            halvings += 1;
+        }
+        minted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::emission::Emission;
+
+    #[test]
+    fn halve_and_cap() {
+        let emission = Emission {
+            reward: 50,
+            halving_interval: 10,
+            tail: 0,
+            max_supply: u64::MAX,
+        };
+        assert_eq!(emission.subsidy(1000, 0), 0);
+        assert_eq!(emission.subsidy(1000, 10), 50);
+        assert_eq!(emission.subsidy(1000, 11), 25);
+        assert_eq!(emission.subsidy(1000, 31), 6);
+        assert_eq!(emission.supply(1000, 20), 1000 + 10 * 50 + 10 * 25);
+        // 50, 25, 12, 6, 3, 1 and nothing after the 6th halving
+        assert_eq!(emission.subsidy(1000, 61), 0);
+        assert_eq!(emission.supply(1000, 1_000_000), 1000 + 10 * 97);
+
+        let tail = Emission { tail: 5, ..emission.clone() };
+        assert_eq!(tail.subsidy(1000, 31), 6);
+        assert_eq!(tail.subsidy(1000, 1_000_000), 5);
+        assert_eq!(tail.supply(1000, 50), 1000 + 10 * (50 + 25 + 12 + 6 + 5));
+
+        let capped = Emission { max_supply: 1020, ..tail };
+        assert_eq!(capped.supply(1000, 1_000_000), 1020);
+        assert_eq!(capped.subsidy(1000, 1), 20);
+        assert_eq!(capped.subsidy(1000, 2), 0);
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use super::transaction::{CryptoPayload, Transaction, REWARD_SENDER};
+use super::validation::TransactionError;
+
+/// The account balances of a crypto currency, built by replaying the transfers of a chain.
+#[derive(Clone, Debug, Default)]
+pub struct Balances {
+    /// The balance of each address that ever received coins.
+    balances: HashMap<String, u32>,
+}
+
+impl Balances {
+    /// The balance of the address, zero for unknown addresses.
+    pub fn balance_of(&self, address: &str) -> u32 {
+        self.balances.get(address).cloned().unwrap_or(0)
+    }
+
+    /// Moves the amount of the transfer from the sender to the receiver and takes the fee from
+    /// the sender, which the miner collects with the reward.
+    ///
+    /// Rewards are minted instead of being taken from the sender. The balances are left
+    /// untouched if the transfer is rejected.
+    pub fn transfer(&mut self, transaction: &Transaction<CryptoPayload>) -> Result<(), TransactionError> {
+        let payload = transaction.payload.read().unwrap();
+        let minted = transaction.sender == REWARD_SENDER;
+
+        let mut sender_balance = None;
+        if !minted {
+            if payload.amount == 0 {
+                return Err(TransactionError::ZeroAmount);
+            }
+            let debit = payload.amount.checked_add(transaction.fee).ok_or(TransactionError::Overdraft)?;
+            match self.balance_of(&transaction.sender).checked_sub(debit) {
+                Some(balance) => sender_balance = Some(balance),
+                None => return Err(TransactionError::Overdraft),
+            }
+        }
+
+        let receiver_balance = match sender_balance {
+            Some(balance) if payload.receiver == transaction.sender => balance,
+            _ => self.balance_of(&payload.receiver),
+        };
+        let receiver_balance = receiver_balance.checked_add(payload.amount)
+            .ok_or(TransactionError::Overflow)?;
+
+        if let Some(balance) = sender_balance {
+            self.balances.insert(transaction.sender.clone(), balance);
+        }
+        self.balances.insert(payload.receiver.clone(), receiver_balance);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::ledger::Balances;
+    use crate::blockchain::transaction::{CryptoPayload, Transaction, Transactional};
+    use crate::blockchain::validation::TransactionError;
+
+    fn transfer(sender: &str, receiver: &str, amount: u32) -> Transaction<CryptoPayload> {
+        CryptoPayload::new(String::from(sender), CryptoPayload {
+            receiver: String::from(receiver),
+            amount,
+        })
+    }
+
+    #[test]
+    fn transfer_coins() {
+        let mut balances = Balances::default();
+        balances.transfer(&CryptoPayload::genesis(String::from("Schwurbel"), 100)).unwrap();
+        balances.transfer(&transfer("Schwurbel", "Peter", 42)).unwrap();
+        balances.transfer(&transfer("Peter", "Peter", 42)).unwrap();
+
+        assert_eq!(balances.balance_of("Schwurbel"), 58);
+        assert_eq!(balances.balance_of("Peter"), 42);
+        assert_eq!(balances.balance_of("Paul"), 0);
+    }
+
+    #[test]
+    fn reject_transfers() {
+        let mut balances = Balances::default();
+        balances.transfer(&CryptoPayload::genesis(String::from("Schwurbel"), 100)).unwrap();
+        balances.transfer(&CryptoPayload::genesis(String::from("Peter"), u32::max_value())).unwrap();
+
+        assert_eq!(balances.transfer(&transfer("Schwurbel", "Paul", 0)), Err(TransactionError::ZeroAmount));
+        assert_eq!(balances.transfer(&transfer("Schwurbel", "Paul", 101)), Err(TransactionError::Overdraft));
+        assert_eq!(balances.transfer(&transfer("Schwurbel", "Peter", 1)), Err(TransactionError::Overflow));
+        assert_eq!(balances.balance_of("Schwurbel"), 100);
+        assert_eq!(balances.balance_of("Paul"), 0);
+    }
+}
@@ -0,0 +1,501 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+
+use super::block::Block;
+use super::state::ChainState;
+use super::transaction::{Transaction, Transactional};
+use super::validation::TransactionError;
+
+/// The limits of the transactions waiting in a `Mempool`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MempoolLimits {
+    /// The most transactions waiting at a time
+    pub max_transactions: usize,
+    /// The most bytes all waiting transactions may take serialized
+    pub max_size: usize,
+    /// Transactions waiting longer than this are dropped
+    pub expiry: Duration,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        MempoolLimits {
+            max_transactions: 5000,
+            max_size: 4 * 1024 * 1024,
+            expiry: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Error returned when the mempool rejects a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Fail)]
+pub enum MempoolError {
+    /// The ledger state on top of the active chain and the waiting transactions rejects it.
+    #[fail(display = "{}", _0)]
+    Rejected(TransactionError),
+    /// Every transaction is only accepted once.
+    #[fail(display = "transaction is already waiting")]
+    Duplicate,
+    /// A single transaction may not exceed the size limit of a block or the whole mempool.
+    #[fail(display = "transaction of {} bytes exceeds the mempool", _0)]
+    TooLarge(usize),
+    /// The mempool is full of transactions paying at least the same fee rate.
+    #[fail(display = "mempool is full")]
+    Full,
+}
+
+impl From<TransactionError> for MempoolError {
+    fn from(error: TransactionError) -> Self {
+        MempoolError::Rejected(error)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Entry<T> {
+    id: String,
+    transaction: Transaction<T>,
+    fee: u32,
+    /// The serialized size in bytes
+    size: usize,
+    added: Instant,
+}
+
+impl<T> Entry<T> {
+    /// Compares the fee per byte of two entries.
+    fn cmp_fee_rate(&self, other: &Entry<T>) -> Ordering {
+        (u128::from(self.fee) * other.size as u128).cmp(&(u128::from(other.fee) * self.size as u128))
+    }
+}
+
+/// The transactions waiting to be mined, in the order they arrived.
+///
+/// Every transaction is checked against the ledger state of the active chain with all waiting
+/// transactions applied. When the limits are exceeded, the transactions paying the lowest fee per
+/// byte are evicted first. Block templates take the transactions with the highest fee rate first
+/// if not all of them fit.
+#[derive(Clone, Debug)]
+pub struct Mempool<T>
+where T: Transactional
+{
+    /// The waiting transactions by the order they arrived in
+    entries: BTreeMap<u64, Entry<T>>,
+    /// The arrival of each waiting transaction by id
+    ids: HashMap<String, u64>,
+    /// The arrival of the next transaction
+    next: u64,
+    /// The serialized size of all waiting transactions in bytes
+    size: usize,
+    /// The state after the waiting transactions in the order they arrived on top of the active
+    /// chain, `None` until it is rebuilt from the state of the active chain
+    pending: Option<ChainState<T>>,
+    limits: MempoolLimits,
+}
+
+impl<T> Default for Mempool<T>
+where T: Transactional
+{
+    fn default() -> Self {
+        Mempool::new(MempoolLimits::default())
+    }
+}
+
+impl<T> Mempool<T>
+where T: Transactional
+{
+    pub fn new(limits: MempoolLimits) -> Self {
+        Mempool {
+            entries: BTreeMap::new(),
+            ids: HashMap::new(),
+            next: 0,
+            size: 0,
+            pending: None,
+            limits,
+        }
+    }
+
+    pub fn update_limits(&mut self, limits: MempoolLimits) -> bool {
+        self.limits = limits;
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The serialized size of all waiting transactions in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains_key(id)
+    }
+
+    /// The waiting transaction with the id.
+    pub fn get(&self, id: &str) -> Option<&Transaction<T>> {
+        self.ids.get(id)
+            .and_then(|arrival| self.entries.get(arrival))
+            .map(|entry| &entry.transaction)
+    }
+
+    /// The waiting transactions in the order they arrived.
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction<T>> {
+        self.entries.values().map(|entry| &entry.transaction)
+    }
+
+    /// Adds a transaction if the pending state accepts it.
+    ///
+    /// `state` is the state of the active chain of the given height, used to rebuild the pending
+    /// state if transactions are evicted.
+    pub fn insert(&mut self, transaction: Transaction<T>, state: &ChainState<T>, height: usize, now: Instant)
+        -> Result<(), MempoolError> {
+        let id = transaction.id();
+        if self.contains(&id) {
+            return Err(MempoolError::Duplicate);
+        }
+        let size = transaction.size();
+        if size > self.limits.max_size {
+            return Err(MempoolError::TooLarge(size));
+        }
+        let entry = Entry { id, fee: transaction.fee, transaction, size, added: now };
+        // the transaction would be evicted right away, so the others are left untouched
+        if self.is_evicted(&entry) {
+            return Err(MempoolError::Full);
+        }
+        // the position the transaction takes in a block of all waiting ones, after the reward
+        let index = self.entries.len() + 1;
+        self.pending.get_or_insert_with(|| state.clone()).apply(&entry.transaction, height, index)?;
+
+        self.push(entry);
+        if self.is_full() {
+            // the evicted transactions may have funded others
+            self.revalidate(state, height);
+        }
+        Ok(())
+    }
+
+    /// Adds a transaction without checking it, e.g. one of a block displaced by a reorg.
+    ///
+    /// Has to be followed by `revalidate`.
+    pub fn reinsert(&mut self, transaction: Transaction<T>, now: Instant) {
+        let id = transaction.id();
+        if self.contains(&id) {
+            return;
+        }
+        let size = transaction.size();
+        self.push(Entry { id, fee: transaction.fee, transaction, size, added: now });
+        self.pending = None;
+    }
+
+    /// Removes the transactions with the ids, e.g. because a block of the new tip after a reorg
+    /// includes them.
+    ///
+    /// Has to be followed by `revalidate`.
+    pub fn remove(&mut self, ids: &HashSet<String>) {
+        for id in ids {
+            if let Some(arrival) = self.ids.get(id).cloned() {
+                self.take(arrival);
+            }
+        }
+        self.pending = None;
+    }
+
+    /// Removes the transactions included in the blocks appended to the active chain at the height
+    /// and applies the others, e.g. the rewards, to the pending state, which contains the waiting
+    /// ones already.
+    ///
+    /// The pending state is only rebuilt on top of `state`, the state of the active chain of
+    /// `tip` blocks, if it rejects one of them as it conflicts with a waiting transaction.
+    pub fn confirm(&mut self, blocks: &[Block<T>], height: usize, state: &ChainState<T>, tip: usize) {
+        let mut pending = self.pending.take();
+        for (offset, block) in blocks.iter().enumerate() {
+            for (index, transaction) in block.transactions().iter().enumerate() {
+                match self.ids.get(&transaction.id()).cloned() {
+                    Some(arrival) => {
+                        self.take(arrival);
+                    }
+                    None => if let Some(ref mut rest) = pending {
+                        if rest.apply(transaction, height + offset, index).is_err() {
+                            pending = None;
+                        }
+                    },
+                }
+            }
+        }
+        self.pending = pending;
+        if self.pending.is_none() && !self.entries.is_empty() {
+            self.revalidate(state, tip);
+        }
+    }
+
+    /// Drops the transactions waiting longer than the expiry.
+    /// Returns the number of dropped transactions.
+    pub fn expire(&mut self, state: &ChainState<T>, height: usize, now: Instant) -> usize {
+        let expiry = self.limits.expiry;
+        let expired: Vec<u64> = self.entries.iter()
+            .filter(|(_, entry)| now.duration_since(entry.added) >= expiry)
+            .map(|(arrival, _)| *arrival)
+            .collect();
+        for arrival in &expired {
+            self.take(*arrival);
+        }
+        if !expired.is_empty() {
+            self.revalidate(state, height);
+        }
+        expired.len()
+    }
+
+    /// Evicts transactions until the limits are met and rebuilds the pending state on top of the
+    /// state of the active chain, dropping the transactions it no longer accepts.
+    pub fn revalidate(&mut self, state: &ChainState<T>, height: usize) {
+        while self.is_full() {
+            // the lowest fee rate, the most recent among equal ones
+            let lowest = self.entries.iter()
+                .min_by(|(i, a), (j, b)| a.cmp_fee_rate(b).then(j.cmp(i)))
+                .map(|(arrival, _)| *arrival);
+            if let Some(arrival) = lowest {
+                self.take(arrival);
+            }
+        }
+        if self.entries.is_empty() {
+            // rebuilt from the state of the active chain by the next insert
+            self.pending = None;
+            return;
+        }
+        let mut pending = state.clone();
+        let mut rejected = Vec::new();
+        for (position, (arrival, entry)) in self.entries.iter().enumerate() {
+            // the index in a block of the accepted ones, after the reward
+            let index = position - rejected.len() + 1;
+            if pending.apply(&entry.transaction, height, index).is_err() {
+                rejected.push(*arrival);
+            }
+        }
+        for arrival in rejected {
+            self.take(arrival);
+        }
+        self.pending = Some(pending);
+    }
+
+    /// The transactions for a block on top of the state of the active chain.
+    ///
+    /// Takes at most `max_transactions` transactions, whose sizes plus a separating byte each add
+    /// up to at most `max_size` bytes. If all waiting transactions fit, they are taken in the
+    /// order the pending state accepted them. Otherwise the highest fee rate comes first, and
+    /// transactions relying on another waiting transaction follow it.
+    pub fn block_transactions(&self, state: &ChainState<T>, height: usize, max_transactions: usize, max_size: usize)
+        -> Vec<Transaction<T>> {
+        let size: usize = self.entries.values().map(|entry| entry.size + 1).sum();
+        if self.pending.is_some() && self.entries.len() <= max_transactions && size <= max_size {
+            return self.transactions().cloned().collect();
+        }
+
+        let mut remaining: Vec<&Entry<T>> = self.entries.values().collect();
+        // stable, so equal fee rates keep their arrival order
+        remaining.sort_by(|a, b| b.cmp_fee_rate(a));
+
+        let mut state = state.clone();
+        let mut transactions = Vec::new();
+        let mut size = 0;
+        loop {
+            let count = remaining.len();
+            remaining.retain(|entry| {
+                if transactions.len() < max_transactions && size + entry.size < max_size
+                    && state.apply(&entry.transaction, height, transactions.len() + 1).is_ok() {
+                    transactions.push(entry.transaction.clone());
+                    size += entry.size + 1;
+                    false
+                } else {
+                    true
+                }
+            });
+            if remaining.is_empty() || remaining.len() == count {
+                return transactions;
+            }
+        }
+    }
+
+    /// The nonce the next transaction of the address has to carry, counting the waiting ones.
+    pub fn next_nonce(&self, state: &ChainState<T>, address: &str) -> u64 {
+        match self.pending {
+            Some(ref pending) => pending.next_nonce(address),
+            None => state.next_nonce(address),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.entries.len() > self.limits.max_transactions || self.size > self.limits.max_size
+    }
+
+    /// Whether the entry would be the first to be evicted if it was added, as the transactions
+    /// paying a lower fee rate don't free enough space.
+    fn is_evicted(&self, entry: &Entry<T>) -> bool {
+        let (count, size) = self.entries.values()
+            .filter(|other| other.cmp_fee_rate(entry) != Ordering::Less)
+            .fold((1, entry.size), |(count, size), other| (count + 1, size + other.size));
+        count > self.limits.max_transactions || size > self.limits.max_size
+    }
+
+    fn push(&mut self, entry: Entry<T>) {
+        self.size += entry.size;
+        self.ids.insert(entry.id.clone(), self.next);
+        self.entries.insert(self.next, entry);
+        self.next += 1;
+    }
+
+    fn take(&mut self, arrival: u64) -> Option<Entry<T>> {
+        let entry = self.entries.remove(&arrival)?;
+        self.size -= entry.size;
+        self.ids.remove(&entry.id);
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    use crate::blockchain::block::Block;
+    use crate::blockchain::mempool::{Mempool, MempoolError, MempoolLimits};
+    use crate::blockchain::state::ChainState;
+    use crate::blockchain::transaction::{CryptoPayload, Transaction, Transactional};
+    use crate::blockchain::validation::TransactionError;
+
+    fn transfer(sender: &str, receiver: &str, amount: u32, nonce: u64) -> Transaction<CryptoPayload> {
+        let mut transaction = CryptoPayload::new(String::from(sender), CryptoPayload { receiver: String::from(receiver), amount });
+        transaction.nonce = nonce;
+        transaction
+    }
+
+    fn state() -> ChainState<CryptoPayload> {
+        let mut state = ChainState::default();
+        state.apply(&CryptoPayload::genesis(String::from("Schwurbel"), 100), 0, 0).unwrap();
+        state.apply(&CryptoPayload::genesis(String::from("Peter"), 100), 0, 1).unwrap();
+        state
+    }
+
+    #[test]
+    fn validate_and_deduplicate() {
+        let state = state();
+        let now = Instant::now();
+        let mut mempool = Mempool::default();
+        assert_eq!(mempool.insert(transfer("Schwurbel", "Peter", 60, 0), &state, 1, now), Ok(()));
+        assert_eq!(mempool.insert(transfer("Schwurbel", "Peter", 60, 0), &state, 1, now), Err(MempoolError::Duplicate));
+        assert_eq!(mempool.insert(transfer("Schwurbel", "Paul", 60, 1), &state, 1, now),
+                   Err(MempoolError::Rejected(TransactionError::Overdraft)));
+        assert_eq!(mempool.insert(transfer("Schwurbel", "Paul", 10, 0), &state, 1, now),
+                   Err(MempoolError::Rejected(TransactionError::Nonce)));
+        assert_eq!(mempool.next_nonce(&state, "Schwurbel"), 1);
+        // Peter spends the coins he is about to receive
+        assert_eq!(mempool.insert(transfer("Peter", "Paul", 150, 0), &state, 1, now), Ok(()));
+        assert_eq!(mempool.len(), 2);
+
+        // a new tip includes the first transfer, the second one has to wait for it
+        let mut ids = HashSet::new();
+        ids.insert(transfer("Schwurbel", "Peter", 60, 0).id());
+        mempool.remove(&ids);
+        mempool.revalidate(&state, 2);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn evict_and_expire() {
+        let state = state();
+        let now = Instant::now();
+        let mut mempool = Mempool::new(MempoolLimits { max_transactions: 2, ..MempoolLimits::default() });
+        mempool.insert(transfer("Schwurbel", "Peter", 1, 0), &state, 1, now).unwrap();
+        mempool.insert(transfer("Peter", "Paul", 1, 0), &state, 1, now + Duration::from_secs(1)).unwrap();
+        assert_eq!(mempool.insert(transfer("Schwurbel", "Mary", 1, 1), &state, 1, now), Err(MempoolError::Full));
+        assert_eq!(mempool.len(), 2);
+
+        let expiry = MempoolLimits::default().expiry;
+        assert_eq!(mempool.expire(&state, 1, now + expiry), 1);
+        assert_eq!(mempool.transactions().next().unwrap().payload.read().unwrap().receiver, "Paul");
+
+        // a higher fee rate evicts the most recent of the lowest ones
+        let mut mempool = Mempool::new(MempoolLimits { max_transactions: 2, ..MempoolLimits::default() });
+        mempool.insert(transfer("Schwurbel", "Peter", 1, 0), &state, 1, now).unwrap();
+        mempool.insert(transfer("Peter", "Paul", 1, 0), &state, 1, now).unwrap();
+        let mut paying = transfer("Schwurbel", "Mary", 1, 1);
+        paying.fee = 5;
+        assert_eq!(mempool.insert(paying.clone(), &state, 1, now), Ok(()));
+        assert_eq!(mempool.get(&paying.id()).map(|transaction| transaction.fee), Some(5));
+        assert!(!mempool.contains(&transfer("Peter", "Paul", 1, 0).id()));
+        assert_eq!(mempool.size(), transfer("Schwurbel", "Peter", 1, 0).size() + paying.size());
+    }
+
+    #[test]
+    fn order_dependent_transactions() {
+        let state = state();
+        let now = Instant::now();
+        let mut mempool = Mempool::default();
+        mempool.insert(transfer("Schwurbel", "Peter", 60, 0), &state, 1, now).unwrap();
+        mempool.insert(transfer("Peter", "Paul", 150, 0), &state, 1, now).unwrap();
+        let mut paying = transfer("Schwurbel", "Mary", 10, 1);
+        paying.fee = 20;
+        mempool.insert(paying, &state, 1, now).unwrap();
+
+        let mut other = Mempool::default();
+        let transactions: Vec<Transaction<CryptoPayload>> = mempool.transactions().cloned().collect();
+        for transaction in transactions.into_iter().rev() {
+            other.reinsert(transaction, now);
+        }
+        let transactions = other.block_transactions(&state, 1, usize::MAX, usize::MAX);
+        let order: Vec<(&str, u64)> = transactions.iter().map(|transaction| (transaction.sender.as_str(), transaction.nonce)).collect();
+        assert_eq!(order, vec![("Schwurbel", 0), ("Schwurbel", 1), ("Peter", 0)]);
+
+        // the limits of a block cut off the transactions with the lowest fee rate
+        assert_eq!(other.block_transactions(&state, 1, 2, usize::MAX).len(), 2);
+        let size = transactions[0].size() + transactions[1].size() + 2;
+        assert_eq!(other.block_transactions(&state, 1, usize::MAX, size).len(), 2);
+    }
+
+    #[test]
+    fn confirm_blocks() {
+        let mut tip = state();
+        let now = Instant::now();
+        let mut mempool = Mempool::default();
+        mempool.insert(transfer("Schwurbel", "Peter", 60, 0), &tip, 1, now).unwrap();
+        mempool.insert(transfer("Schwurbel", "Mary", 10, 1), &tip, 1, now).unwrap();
+
+        // the block includes a waiting transaction and one relying on it unknown so far
+        let mut transactions = vec![transfer("Schwurbel", "Peter", 60, 0), transfer("Peter", "Paul", 150, 0)];
+        let block = Block::new(String::new(), 0, String::from("Mary"), 5, &mut transactions);
+        for (index, transaction) in block.transactions().iter().enumerate() {
+            tip.apply(transaction, 1, index).unwrap();
+        }
+        mempool.confirm(&[block], 1, &tip, 2);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.next_nonce(&tip, "Schwurbel"), 2);
+        assert_eq!(mempool.next_nonce(&tip, "Peter"), 1);
+        assert_eq!(mempool.insert(transfer("Peter", "Mary", 11, 1), &tip, 2, now),
+                   Err(MempoolError::Rejected(TransactionError::Overdraft)));
+        let transactions = mempool.block_transactions(&tip, 2, usize::MAX, usize::MAX);
+        assert_eq!(transactions.iter().map(|transaction| transaction.id()).collect::<Vec<_>>(),
+                   vec![transfer("Schwurbel", "Mary", 10, 1).id()]);
+
+        // a block conflicting with a waiting transaction rebuilds the pending state without it
+        let mut tip = state();
+        let mut mempool = Mempool::default();
+        mempool.insert(transfer("Schwurbel", "Peter", 60, 0), &tip, 1, now).unwrap();
+        mempool.insert(transfer("Peter", "Paul", 10, 0), &tip, 1, now).unwrap();
+        let mut transactions = vec![transfer("Schwurbel", "Mary", 90, 0)];
+        let block = Block::new(String::new(), 0, String::from("Mary"), 5, &mut transactions);
+        for (index, transaction) in block.transactions().iter().enumerate() {
+            tip.apply(transaction, 1, index).unwrap();
+        }
+        mempool.confirm(&[block], 1, &tip, 2);
+        assert_eq!(mempool.transactions().map(|transaction| transaction.id()).collect::<Vec<_>>(),
+                   vec![transfer("Peter", "Paul", 10, 0).id()]);
+        assert_eq!(mempool.next_nonce(&tip, "Schwurbel"), 1);
+        assert_eq!(mempool.next_nonce(&tip, "Peter"), 1);
+    }
+}
@@ -0,0 +1,218 @@
+use std::cmp;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use serde::{Serialize, Deserialize};
+
+use super::block::BlockHeader;
+
+/// The number of nonces a worker checks between two looks at the cancellation flag.
+const BATCH: u32 = 1024;
+
+/// Mines block headers on a number of worker threads.
+///
+/// The nonce space is split evenly between the workers. A worker that exhausted its share
+/// increases the timestamp of its header by a second and starts over, so workers never check the
+/// same header twice.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Miner {
+    /// The number of worker threads.
+    pub threads: usize,
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        Miner {
+            threads: 1,
+        }
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            threads,
+        }
+    }
+
+    /// Starts mining the header in the background and returns a handle to the job.
+    pub fn start(&self, header: BlockHeader) -> MiningJob {
+        let threads = cmp::max(self.threads, 1);
+        let job = MiningJob::new(threads);
+        let share = u32::max_value() / threads as u32;
+
+        for worker in 0..threads {
+            let start = worker as u32 * share;
+            let end = if worker == threads - 1 { u32::max_value() } else { start + share - 1 };
+            let job = job.clone();
+            let header = header.clone();
+            thread::spawn(move || job.work(header, start, end));
+        }
+        job
+    }
+
+    /// Mines the header on the worker threads and blocks until a matching nonce is found.
+    ///
+    /// Returns the job to report the hash rate.
+    pub fn mine(&self, header: &mut BlockHeader) -> MiningJob {
+        let job = self.start(header.clone());
+        if let Some(mined) = job.wait() {
+            *header = mined;
+        }
+        job
+    }
+}
+
+/// The shared state of a mining job.
+#[derive(Debug)]
+struct JobState {
+    /// The mined header, once found.
+    header: Option<BlockHeader>,
+    /// The number of workers still running.
+    running: usize,
+}
+
+/// A handle to a running mining job, shared with its workers.
+#[derive(Clone, Debug)]
+pub struct MiningJob {
+    cancelled: Arc<AtomicBool>,
+    hashes: Arc<AtomicU64>,
+    state: Arc<(Mutex<JobState>, Condvar)>,
+    started: Instant,
+}
+
+impl MiningJob {
+    fn new(workers: usize) -> MiningJob {
+        MiningJob {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            hashes: Arc::new(AtomicU64::new(0)),
+            state: Arc::new((Mutex::new(JobState { header: None, running: workers }), Condvar::new())),
+            started: Instant::now(),
+        }
+    }
+
+    /// Stops all workers as soon as possible, e.g. when a new tip arrived from a peer.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Whether any worker is still mining.
+    pub fn is_running(&self) -> bool {
+        self.state.0.lock().unwrap().running > 0
+    }
+
+    /// The number of hashes computed so far.
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    /// The average number of hashes per second since the job started.
+    pub fn hash_rate(&self) -> f64 {
+        let elapsed = self.started.elapsed();
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.hashes() as f64 / seconds.max(1e-9)
+    }
+
+    /// The mined header, if a worker found a matching nonce yet.
+    pub fn result(&self) -> Option<BlockHeader> {
+        self.state.0.lock().unwrap().header.clone()
+    }
+
+    /// Blocks until a worker found a matching nonce or all workers stopped.
+    ///
+    /// Returns `None` if the job was cancelled before.
+    pub fn wait(&self) -> Option<BlockHeader> {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.header.is_none() && state.running > 0 {
+            state = condvar.wait(state).unwrap();
+        }
+        state.header.clone()
+    }
+
+    /// Checks the nonces from `start` to `end`, rolling the timestamp whenever they are exhausted.
+    fn work(&self, mut header: BlockHeader, start: u32, end: u32) {
+        'roll: loop {
+            let pow = header.pow();
+            let mut nonce = start;
+            loop {
+                if self.is_cancelled() {
+                    break 'roll;
+                }
+                let last = cmp::min(end, nonce.saturating_add(BATCH - 1));
+                let found = (nonce..=last).find(|nonce| pow.verify(*nonce));
+                let checked = found.unwrap_or(last) - nonce + 1;
+                self.hashes.fetch_add(u64::from(checked), Ordering::Relaxed);
+
+                if let Some(found) = found {
+                    header.nonce = found;
+                    self.finish(header);
+                    break 'roll;
+                }
+                if last == end {
+                    break;
+                }
+                nonce = last + 1;
+            }
+            header.roll();
+        }
+
+        let (lock, condvar) = &*self.state;
+        lock.lock().unwrap().running -= 1;
+        condvar.notify_all();
+    }
+
+    /// Publishes the mined header and stops the other workers.
+    fn finish(&self, header: BlockHeader) {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if state.header.is_none() {
+            state.header = Some(header);
+        }
+        self.cancel();
+        condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::blockchain::block::Block;
+    use crate::blockchain::miner::Miner;
+    use crate::blockchain::transaction::CryptoPayload;
+
+    #[test]
+    fn mine() {
+        let block: Block<CryptoPayload> = Block::new(String::from("00xxxxxxxxxxxxxxxxxx"), 0x3f7f_ffff,
+                                                     String::from("Schwurbel"), 42, &mut vec![]);
+        let mut header = block.header.clone();
+        let job = Miner::new(4).mine(&mut header);
+
+        assert!(header.meets_target());
+        assert!(job.hashes() > 0);
+        assert_eq!(job.result(), Some(header));
+    }
+
+    #[test]
+    fn cancel() {
+        let block: Block<CryptoPayload> = Block::new(String::from("00xxxxxxxxxxxxxxxxxx"), 0,
+                                                     String::from("Schwurbel"), 42, &mut vec![]);
+        let job = Miner::new(2).start(block.header.clone());
+        thread::sleep(Duration::from_millis(50));
+        assert!(job.is_running());
+
+        job.cancel();
+        assert_eq!(job.wait(), None);
+        assert!(!job.is_running());
+        assert!(job.hashes() > 0);
+        assert!(job.hash_rate() > 0.0);
+    }
+}
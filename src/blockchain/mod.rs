@@ -2,5 +2,27 @@
 pub mod block;
 /// The blockchain per se
 pub mod chain;
+/// The schedule of the coins minted by new blocks
+pub mod emission;
+/// The account balances of the crypto currency
+pub mod ledger;
+/// The transactions waiting to be mined
+pub mod mempool;
+/// The multi-threaded miner
+pub mod miner;
+/// The proof of work securing the blocks
+pub mod pow;
+/// The adjustment of the mining difficulty
+pub mod retarget;
+/// The parameters and genesis block shared by the nodes of a network
+pub mod spec;
+/// The ledger state and nonces built by replaying a chain
+pub mod state;
+/// The tree of competing branches of the blockchain
+pub mod tree;
 /// The transaction stored in a block of the blockchain
 pub mod transaction;
+/// The unspent transaction outputs of the crypto currency
+pub mod utxo;
+/// The rules a valid chain has to obey
+pub mod validation;
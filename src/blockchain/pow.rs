@@ -0,0 +1,187 @@
+use std::fmt;
+
+use sha3::{Sha3_512, Digest};
+
+use crate::crypto::hash;
+
+/// The compact encoding of the easiest target, met by about every second hash.
+pub const MAX_BITS: u32 = 0x407f_ffff;
+
+/// A 512 bit target for the proof of work, stored as big endian bytes.
+///
+/// A hash meets the target if its digest, read as big endian number, is not larger than the
+/// target. The lower the target, the harder it is to find a matching hash.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target([u8; 64]);
+
+impl Target {
+    /// The largest possible target.
+    pub fn max() -> Target {
+        Target([0xff; 64])
+    }
+
+    /// Decodes a target from the compact encoding used in block headers.
+    ///
+    /// Like in Bitcoin, the highest byte is the number of significant bytes of the target and the
+    /// lower three bytes are its most significant bytes, but without a sign bit.
+    /// Targets exceeding 512 bits are capped at the maximum.
+    pub fn from_compact(bits: u32) -> Target {
+        let size = (bits >> 24) as usize;
+        let mantissa = (bits & 0x00ff_ffff).to_be_bytes();
+        let mut target = [0u8; 64];
+        for (i, byte) in mantissa[1..].iter().enumerate().take(size) {
+            if size - i > target.len() {
+                if *byte != 0 {
+                    return Target::max();
+                }
+                continue;
+            }
+            target[target.len() + i - size] = *byte;
+        }
+        Target(target)
+    }
+
+    /// Encodes the target in the compact encoding, keeping its three most significant bytes.
+    pub fn to_compact(&self) -> u32 {
+        let size = self.0.len() - self.0.iter().take_while(|byte| **byte == 0).count();
+        let mut mantissa = 0u32;
+        for i in 0..3 {
+            mantissa <<= 8;
+            if i < size {
+                mantissa |= u32::from(self.0[self.0.len() - size + i]);
+            }
+        }
+        (size as u32) << 24 | mantissa
+    }
+
+    /// Scales the target by `mul / div`, capping it at the maximum.
+    pub fn mul_div(&self, mul: u64, div: u64) -> Target {
+        let mut target = self.0;
+
+        let mut carry = 0u128;
+        for byte in target.iter_mut().rev() {
+            carry += u128::from(*byte) * u128::from(mul);
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        if carry != 0 {
+            return Target::max();
+        }
+
+        let mut remainder = 0u128;
+        for byte in target.iter_mut() {
+            remainder = remainder << 8 | u128::from(*byte);
+            *byte = (remainder / u128::from(div)) as u8;
+            remainder %= u128::from(div);
+        }
+        Target(target)
+    }
+
+    /// The expected number of hashes needed to meet the target, saturating at `u128::max_value()`.
+    pub fn work(&self) -> u128 {
+        let target = self.0.iter().fold(0f64, |value, byte| value * 256.0 + f64::from(*byte));
+        (2f64.powi(512) / (target + 1.0)) as u128
+    }
+
+    /// Checks whether the digest, read as big endian number, is not larger than the target.
+    pub fn is_met_by(&self, digest: &[u8]) -> bool {
+        digest <= &self.0[..]
+    }
+}
+
+impl fmt::Debug for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Target({})", hash::hex_to_string(&self.0))
+    }
+}
+
+/// Proof of work for a block header.
+///
+/// The hash of a header is the SHA3-512 digest of its encoding without the nonce, followed by
+/// the nonce in little endian. The encoding is absorbed only once, so checking a nonce only
+/// hashes four more bytes.
+#[derive(Clone)]
+pub struct Pow {
+    /// The hasher that already absorbed the encoding of the header without the nonce.
+    prefix: Sha3_512,
+    /// The target the hash has to meet.
+    target: Target,
+}
+
+impl Pow {
+    /// Creates the proof of work for the encoding of a header without its nonce.
+    pub fn new(encoding: &[u8], target: Target) -> Pow {
+        let mut prefix = Sha3_512::new();
+        prefix.input(encoding);
+        Pow {
+            prefix,
+            target,
+        }
+    }
+
+    /// Computes the digest of the header with the given nonce.
+    pub fn digest(&self, nonce: u32) -> [u8; 64] {
+        let mut hasher = self.prefix.clone();
+        hasher.input(&nonce.to_le_bytes());
+        let mut digest = [0u8; 64];
+        digest.copy_from_slice(&hasher.result());
+        digest
+    }
+
+    /// Checks whether the header with the given nonce meets the target.
+    pub fn verify(&self, nonce: u32) -> bool {
+        self.target.is_met_by(&self.digest(nonce))
+    }
+
+    /// Searches for a nonce meeting the target, starting at `start`.
+    ///
+    /// Returns `None` if no nonce up to `u32::max_value()` meets the target.
+    pub fn mine(&self, start: u32) -> Option<u32> {
+        (start..=u32::max_value()).find(|nonce| self.verify(*nonce))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::pow::{Pow, Target, MAX_BITS};
+
+    #[test]
+    fn compact_encoding() {
+        let mut bytes = [0u8; 64];
+        bytes[62] = 0x12;
+        bytes[63] = 0x34;
+        assert_eq!(Target::from_compact(0x0212_3400), Target(bytes));
+        assert_eq!(Target(bytes).to_compact(), 0x0212_3400);
+        assert_eq!(Target::from_compact(0x0112_3400).to_compact(), 0x0112_0000);
+
+        assert_eq!(Target::from_compact(MAX_BITS).to_compact(), MAX_BITS);
+        assert_eq!(Target::from_compact(0x4100_0001), Target::from_compact(0x4000_0100));
+        assert_eq!(Target::from_compact(0x4101_0000), Target::max());
+        assert_eq!(Target::from_compact(0), Target([0; 64]));
+    }
+
+    #[test]
+    fn mul_div() {
+        let target = Target::from_compact(0x2012_3456);
+        assert_eq!(target.mul_div(4, 1).to_compact(), 0x2048_d158);
+        assert_eq!(target.mul_div(1, 4).to_compact(), 0x2004_8d15);
+        assert_eq!(target.mul_div(3, 3), target);
+        assert_eq!(Target::max().mul_div(2, 1), Target::max());
+    }
+
+    #[test]
+    fn work() {
+        assert_eq!(Target::from_compact(MAX_BITS).work(), 2);
+        assert!(Target::from_compact(0x3f7f_ffff).work() > Target::from_compact(MAX_BITS).work());
+        assert_eq!(Target::from_compact(0).work(), u128::max_value());
+    }
+
+    #[test]
+    fn mine() {
+        let pow = Pow::new(b"header", Target::from_compact(0x3f7f_ffff));
+        let nonce = pow.mine(0).unwrap();
+        assert!(pow.verify(nonce));
+        assert!((0..nonce).all(|nonce| !pow.verify(nonce)));
+        assert!(!Pow::new(b"header", Target::from_compact(0)).verify(nonce));
+    }
+}
@@ -0,0 +1,108 @@
+use std::cmp;
+
+use serde::{Serialize, Deserialize};
+
+use super::block::BlockHeader;
+use super::pow::Target;
+
+/// Parameters of the difficulty retargeting.
+///
+/// Every `window` blocks the difficulty is adjusted such that blocks are mined every `block_time`
+/// seconds on average, based on the timestamps of the last `window` blocks.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retarget {
+    /// The number of blocks between two adjustments, at least `MIN_WINDOW`.
+    pub window: usize,
+    /// The targeted time between two blocks in seconds.
+    pub block_time: i64,
+    /// The maximum factor by which the target may change per adjustment.
+    pub max_adjustment: u32,
+}
+
+impl Default for Retarget {
+    fn default() -> Self {
+        Retarget {
+            window: 100,
+            block_time: 60,
+            max_adjustment: 4,
+        }
+    }
+}
+
+/// The smallest window, as the timespan of a window is measured between its first and last block.
+pub const MIN_WINDOW: usize = 2;
+
+impl Retarget {
+    /// Checks whether the window spans at least `MIN_WINDOW` blocks and the block time is positive.
+    pub fn is_valid(&self) -> bool {
+        self.window >= MIN_WINDOW && self.block_time > 0
+    }
+
+    /// Computes the expected compact target of the block at the given height.
+    ///
+    /// `header_at` looks up the headers of the preceding blocks by height.
+    /// Returns `None` for the genesis block, which may choose its difficulty freely, and if a
+    /// required header is missing.
+    pub fn next_bits<'a, F>(&self, height: usize, header_at: F) -> Option<u32>
+    where F: Fn(usize) -> Option<&'a BlockHeader>
+    {
+        if height == 0 {
+            return None;
+        }
+        let last = header_at(height - 1)?;
+        // an invalid retargeting keeps the difficulty instead of dividing by zero
+        if !self.is_valid() || height % self.window != 0 {
+            return Some(last.bits);
+        }
+        let first = header_at(height - self.window)?;
+        Some(self.adjust(last.bits, last.timestamp() - first.timestamp()))
+    }
+
+    /// Adjusts the compact target to the time it took to mine the last window of blocks.
+    ///
+    /// The target is scaled by the ratio of actual and expected time, which is clamped such
+    /// that the target changes at most by the factor `max_adjustment`.
+    pub fn adjust(&self, bits: u32, timespan: i64) -> u32 {
+        let expected = cmp::max(self.block_time * (self.window as i64 - 1), 1);
+        let max = i64::from(cmp::max(self.max_adjustment, 1));
+        let timespan = cmp::min(cmp::max(timespan, cmp::max(expected / max, 1)), expected * max);
+        Target::from_compact(bits).mul_div(timespan as u64, expected as u64).to_compact()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::block::Block;
+    use crate::blockchain::chain::genesis_pre_hash;
+    use crate::blockchain::pow::Target;
+    use crate::blockchain::retarget::Retarget;
+    use crate::blockchain::transaction::CryptoPayload;
+
+    #[test]
+    fn adjust() {
+        let retarget = Retarget {
+            window: 11,
+            block_time: 60,
+            max_adjustment: 4,
+        };
+        let bits = 0x2012_3456;
+        let target = Target::from_compact(bits);
+
+        assert_eq!(retarget.adjust(bits, 600), bits);
+        assert_eq!(retarget.adjust(bits, 300), target.mul_div(1, 2).to_compact());
+        assert_eq!(retarget.adjust(bits, 1200), target.mul_div(2, 1).to_compact());
+        assert_eq!(retarget.adjust(bits, 0), target.mul_div(1, 4).to_compact());
+        assert_eq!(retarget.adjust(bits, 600 * 16), target.mul_div(4, 1).to_compact());
+        assert_eq!(retarget.adjust(0x407f_ffff, 600 * 16), Target::max().to_compact());
+    }
+    #[test]
+    fn keep_bits_of_invalid_window() {
+        let block: Block<CryptoPayload> = Block::new(genesis_pre_hash(), 0x2012_3456, String::from("Schwurbel"), 100, &mut vec![]);
+        let header = block.header;
+        let retarget = Retarget { window: 0, ..Retarget::default() };
+        assert!(!retarget.is_valid());
+        assert_eq!(retarget.next_bits(4, |_| Some(&header)), Some(header.bits));
+        assert!(!Retarget { window: 1, ..Retarget::default() }.is_valid());
+        assert!(Retarget { window: 2, ..Retarget::default() }.is_valid());
+    }
+}
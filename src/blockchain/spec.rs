@@ -0,0 +1,290 @@
+use std::cmp;
+use std::fs;
+use std::path::Path;
+
+use failure::{self, Fail};
+use serde::{Serialize, Deserialize};
+
+use super::block::{Block, BlockLimits, MAX_BLOCK_SIZE};
+use super::emission::Emission;
+use super::pow::{Target, MAX_BITS};
+use super::retarget::Retarget;
+use super::transaction::Transactional;
+
+/// Coins minted to an address by the genesis block.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Allocation {
+    pub address: String,
+    pub amount: u32,
+}
+
+/// The genesis block every node of a network starts with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Genesis {
+    /// The creation timestamp of the genesis block.
+    pub timestamp: i64,
+    /// The compact target of the genesis block, adjusted afterwards by the retargeting.
+    pub bits: u32,
+    /// The nonce the genesis block meets its target with, found by `ChainSpec::mine_genesis`.
+    pub nonce: u32,
+    /// The coins minted by the genesis block, at least one.
+    pub allocations: Vec<Allocation>,
+}
+
+/// The parameters of a chain, which independent nodes have to share to start on the same chain.
+///
+/// Usually loaded from a JSON file, e.g.
+///
+/// ```json
+/// {
+///     "network": "testnet",
+///     "genesis": {
+///         "timestamp": 1561939200,
+///         "bits": 1082130431,
+///         "nonce": 0,
+///         "allocations": [{ "address": "Schwurbel", "amount": 1000 }]
+///     },
+///     "emission": { "reward": 100, "halving_interval": 10000, "tail": 1, "max_supply": 21000000 }
+/// }
+/// ```
+///
+/// The emission, the retargeting and the block limits take their defaults if they are omitted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainSpec {
+    /// The name of the network, part of the network id nodes compare in their handshake.
+    pub network: String,
+    pub genesis: Genesis,
+    #[serde(default)]
+    pub emission: Emission,
+    #[serde(default)]
+    pub retarget: Retarget,
+    #[serde(default)]
+    pub limits: BlockLimits,
+}
+
+/// Error returned when a chain spec describes no valid chain.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+pub enum SpecError {
+    /// The genesis block has to mint coins for at least one address.
+    #[fail(display = "genesis block has no allocations")]
+    NoAllocations,
+    /// Every allocation has to mint a positive amount.
+    #[fail(display = "allocation to {} has to be greater than zero", _0)]
+    ZeroAllocation(String),
+    /// All allocations together may not exceed the maximum amount.
+    #[fail(display = "allocations exceed the maximum amount")]
+    Overflow,
+    /// All allocations together may not exceed the maximum supply of the emission.
+    #[fail(display = "allocations exceed the maximum supply")]
+    Supply,
+    /// The maximum block size may not exceed `MAX_BLOCK_SIZE`, which peers can send.
+    #[fail(display = "maximum block size of {} bytes exceeds the largest block peers can send", _0)]
+    BlockSize(usize),
+    /// Blocks have to be allowed to contain at least the reward transaction.
+    #[fail(display = "blocks have to allow at least one transaction")]
+    TransactionLimit,
+    /// The retargeting window has to span at least `MIN_WINDOW` blocks and the block time has
+    /// to be positive.
+    #[fail(display = "retargeting window of {} blocks or block time of {} seconds is invalid", window, block_time)]
+    Retarget { window: usize, block_time: i64 },
+    /// The genesis difficulty has to be a canonical compact target not easier than `MAX_BITS`.
+    #[fail(display = "genesis difficulty {:#x} is invalid", _0)]
+    Bits(u32),
+    /// The tail subsidy may not exceed the initial subsidy it is halved from.
+    #[fail(display = "tail subsidy {} exceeds the initial subsidy {}", tail, reward)]
+    Emission { reward: u32, tail: u32 },
+    /// The hash of the genesis block has to meet its target with the nonce of the spec.
+    #[fail(display = "genesis nonce {} does not meet the genesis difficulty", _0)]
+    ProofOfWork(u32),
+}
+
+impl ChainSpec {
+    /// Parses and checks a chain spec in JSON.
+    ///
+    /// The genesis block depends on the transaction type, so its proof of work is checked once a
+    /// chain is created from the spec, see `check_genesis`.
+    pub fn from_json(json: &str) -> Result<ChainSpec, failure::Error> {
+        let spec: ChainSpec = serde_json::from_str(json)?;
+        spec.check()?;
+        Ok(spec)
+    }
+
+    /// Loads and checks the chain spec in the JSON file at the path.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ChainSpec, failure::Error> {
+        ChainSpec::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Checks whether the spec describes a valid genesis block, emission, retargeting and block
+    /// limits peers can carry.
+    pub fn check(&self) -> Result<(), SpecError> {
+        if self.genesis.allocations.is_empty() {
+            return Err(SpecError::NoAllocations);
+        }
+        if let Some(allocation) = self.genesis.allocations.iter().find(|allocation| allocation.amount == 0) {
+            return Err(SpecError::ZeroAllocation(allocation.address.clone()));
+        }
+        if self.allocated() > u64::from(u32::MAX) {
+            return Err(SpecError::Overflow);
+        }
+        if self.allocated() > self.emission.max_supply {
+            return Err(SpecError::Supply);
+        }
+        if self.limits.max_size > MAX_BLOCK_SIZE {
+            return Err(SpecError::BlockSize(self.limits.max_size));
+        }
+        if self.limits.max_transactions == 0 {
+            return Err(SpecError::TransactionLimit);
+        }
+        if !self.retarget.is_valid() {
+            return Err(SpecError::Retarget { window: self.retarget.window, block_time: self.retarget.block_time });
+        }
+        let target = Target::from_compact(self.genesis.bits);
+        if self.genesis.bits == 0 || target.to_compact() != self.genesis.bits || target > Target::from_compact(MAX_BITS) {
+            return Err(SpecError::Bits(self.genesis.bits));
+        }
+        if self.emission.tail > self.emission.reward {
+            return Err(SpecError::Emission { reward: self.emission.reward, tail: self.emission.tail });
+        }
+        Ok(())
+    }
+
+    /// The sum of the allocations of the genesis block.
+    pub fn allocated(&self) -> u64 {
+        self.genesis.allocations.iter().map(|allocation| u64::from(allocation.amount)).sum()
+    }
+
+    /// Checks whether the genesis block meets its target with the nonce of the spec.
+    ///
+    /// The genesis block depends on the transaction type, so this isn't part of `check`.
+    pub fn check_genesis<T: Transactional>(&self) -> Result<(), SpecError> {
+        if !self.genesis_block::<T>().header.meets_target() {
+            return Err(SpecError::ProofOfWork(self.genesis.nonce));
+        }
+        Ok(())
+    }
+
+    /// Creates the genesis block, the same on every node.
+    ///
+    /// Every allocation is minted by a reward transaction and the reward of the header is their
+    /// sum.
+    pub fn genesis_block<T: Transactional>(&self) -> Block<T> {
+        let transactions = self.genesis.allocations.iter()
+            .map(|allocation| T::genesis(allocation.address.clone(), allocation.amount))
+            .collect();
+        Block::genesis(self.genesis.timestamp, self.genesis.bits, self.genesis.nonce,
+                       cmp::min(self.allocated(), u64::from(u32::MAX)) as u32, transactions)
+    }
+
+    /// Mines the genesis block and stores its nonce in the spec, e.g. to create a new network.
+    ///
+    /// If all nonces are exhausted, the timestamp of the genesis block is rolled.
+    pub fn mine_genesis<T: Transactional>(&mut self) {
+        let mut header = self.genesis_block::<T>().header;
+        header.mine();
+        self.genesis.timestamp = header.timestamp();
+        self.genesis.nonce = header.nonce;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::block::{Block, BlockLimits, MAX_BLOCK_SIZE};
+    use crate::blockchain::chain::Chain;
+    use crate::blockchain::pow::MAX_BITS;
+    use crate::blockchain::spec::{ChainSpec, SpecError};
+    use crate::blockchain::transaction::CryptoPayload;
+    use crate::blockchain::validation::Rule;
+
+    const SPEC: &str = r#"{
+        "network": "testnet",
+        "genesis": {
+            "timestamp": 1561939200,
+            "bits": 1082130431,
+            "nonce": 0,
+            "allocations": [
+                { "address": "Schwurbel", "amount": 1000 },
+                { "address": "Peter", "amount": 500 }
+            ]
+        },
+        "emission": { "reward": 100, "halving_interval": 1000, "tail": 1, "max_supply": 10000 }
+    }"#;
+
+    #[test]
+    fn deterministic_genesis() {
+        let spec = ChainSpec::from_json(SPEC).unwrap();
+        assert_eq!(spec.limits, BlockLimits::default());
+
+        let genesis: Block<CryptoPayload> = spec.genesis_block();
+        assert_eq!(genesis.header.timestamp(), 1561939200);
+        assert_eq!(genesis.header.reward, 1500);
+        assert_eq!(genesis.transactions().len(), 2);
+        assert!(genesis.header.meets_target());
+        assert_eq!(genesis.hash(), ChainSpec::from_json(SPEC).unwrap().genesis_block::<CryptoPayload>().hash());
+
+        let mut spec = ChainSpec::from_json(SPEC).unwrap();
+        assert_eq!(spec.check_genesis::<CryptoPayload>(), Ok(()));
+        while spec.check_genesis::<CryptoPayload>().is_ok() {
+            spec.genesis.nonce += 1;
+        }
+        assert_eq!(spec.check_genesis::<CryptoPayload>(), Err(SpecError::ProofOfWork(spec.genesis.nonce)));
+        let genesis = spec.genesis_block::<CryptoPayload>();
+        let invalid = Chain::from_blocks(String::from("Schwurbel"), spec.clone(), vec![genesis]);
+        assert_eq!(invalid.err().map(|e| e.rule), Some(Rule::ProofOfWork));
+        let nonce = spec.genesis.nonce;
+        let node = Chain::<CryptoPayload>::from_spec(String::from("Schwurbel"), spec.clone());
+        assert_eq!(node.err(), Some(SpecError::ProofOfWork(nonce)));
+        spec.mine_genesis::<CryptoPayload>();
+        assert_eq!(spec.check_genesis::<CryptoPayload>(), Ok(()));
+
+        let mut spec = ChainSpec::from_json(SPEC).unwrap();
+        spec.genesis.allocations[1].amount = 0;
+        assert_eq!(spec.check(), Err(SpecError::ZeroAllocation(String::from("Peter"))));
+        spec.genesis.allocations.clear();
+        assert_eq!(spec.check(), Err(SpecError::NoAllocations));
+        assert!(ChainSpec::from_json(&SPEC.replace("\"amount\": 1000", "\"amount\": 4294967295")).is_err());
+        assert!(ChainSpec::from_json(&SPEC.replace("10000", "1000")).is_err());
+
+        let mut spec = ChainSpec::from_json(SPEC).unwrap();
+        spec.limits.max_size = MAX_BLOCK_SIZE + 1;
+        assert_eq!(spec.check(), Err(SpecError::BlockSize(MAX_BLOCK_SIZE + 1)));
+    }
+
+    #[test]
+    fn documented_spec() {
+        let json: String = include_str!("spec.rs").lines()
+            .skip_while(|line| *line != "/// ```json")
+            .skip(1)
+            .take_while(|line| *line != "/// ```")
+            .map(|line| line.trim_start_matches("///"))
+            .collect();
+        let spec = ChainSpec::from_json(&json).unwrap();
+        assert_eq!(spec.check_genesis::<CryptoPayload>(), Ok(()));
+        assert!(Chain::<CryptoPayload>::from_spec(String::from("Schwurbel"), spec).is_ok());
+    }
+
+    #[test]
+    fn check_parameters() {
+        let spec = ChainSpec::from_json(SPEC).unwrap();
+        assert_eq!(spec.check(), Ok(()));
+
+        let mut invalid = spec.clone();
+        invalid.limits.max_transactions = 0;
+        assert_eq!(invalid.check(), Err(SpecError::TransactionLimit));
+
+        let mut invalid = spec.clone();
+        invalid.retarget.window = 1;
+        assert_eq!(invalid.check(), Err(SpecError::Retarget { window: 1, block_time: 60 }));
+        invalid.retarget.window = 0;
+        assert!(ChainSpec::from_json(&serde_json::to_string(&invalid).unwrap()).is_err());
+
+        for bits in &[0, MAX_BITS + 1, 0x4100_ffff, 0x2000_0001] {
+            let mut invalid = spec.clone();
+            invalid.genesis.bits = *bits;
+            assert_eq!(invalid.check(), Err(SpecError::Bits(*bits)));
+        }
+
+        let mut invalid = spec.clone();
+        invalid.emission.tail = 101;
+        assert_eq!(invalid.check(), Err(SpecError::Emission { reward: 100, tail: 101 }));
+    }
+}
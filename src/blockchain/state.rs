@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use super::transaction::{Transaction, Transactional, REWARD_SENDER};
+use super::validation::TransactionError;
+
+/// The state built by replaying the transactions of a chain: the ledger state of the payload and
+/// the nonce each sender has to use next.
+#[derive(Clone, Debug)]
+pub struct ChainState<T>
+where T: Transactional
+{
+    /// The ledger state of the payload, e.g. account balances.
+    pub ledger: T::State,
+    /// The nonce of the next transaction of each sender that sent one.
+    nonces: HashMap<String, u64>,
+}
+
+impl<T> Default for ChainState<T>
+where T: Transactional
+{
+    fn default() -> Self {
+        ChainState {
+            ledger: T::State::default(),
+            nonces: HashMap::new(),
+        }
+    }
+}
+
+impl<T> ChainState<T>
+where T: Transactional
+{
+    /// The nonce the next transaction of the address has to carry, the number of its transactions
+    /// so far.
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        self.nonces.get(address).cloned().unwrap_or(0)
+    }
+
+    /// Applies the transaction at the index of a block at the given height.
+    ///
+    /// Every transaction except the rewards has to carry the next nonce of its sender, so it can't
+    /// be replayed. Leaves the state untouched if the transaction is rejected.
+    pub fn apply(&mut self, transaction: &Transaction<T>, height: usize, index: usize) -> Result<(), TransactionError> {
+        if transaction.sender == REWARD_SENDER {
+            return T::apply(&mut self.ledger, transaction, height, index);
+        }
+        if transaction.nonce != self.next_nonce(&transaction.sender) {
+            return Err(TransactionError::Nonce);
+        }
+        T::apply(&mut self.ledger, transaction, height, index)?;
+        self.nonces.insert(transaction.sender.clone(), transaction.nonce + 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::state::ChainState;
+    use crate::blockchain::transaction::{CodePayload, CryptoPayload, Transactional, VotePayload};
+    use crate::blockchain::validation::TransactionError;
+
+    #[test]
+    fn reject_replays() {
+        let mut state = ChainState::default();
+        let reward = CryptoPayload::genesis(String::from("Schwurbel"), 100);
+        state.apply(&reward, 0, 0).unwrap();
+        state.apply(&reward, 1, 0).unwrap();
+        assert_eq!(state.next_nonce("Schwurbel"), 0);
+
+        let mut transfer = CryptoPayload::new(String::from("Schwurbel"), CryptoPayload { receiver: String::from("Peter"), amount: 1 });
+        state.apply(&transfer, 1, 1).unwrap();
+        assert_eq!(state.apply(&transfer, 1, 1), Err(TransactionError::Nonce));
+        transfer.nonce = 2;
+        assert_eq!(state.apply(&transfer, 1, 1), Err(TransactionError::Nonce));
+        transfer.nonce = 1;
+        state.apply(&transfer, 1, 1).unwrap();
+        assert_eq!(state.next_nonce("Schwurbel"), 2);
+        assert_eq!(state.ledger.balance_of("Peter"), 2);
+
+        // payloads without a ledger are protected as well
+        let mut state = ChainState::default();
+        let vote = VotePayload::new(String::from("Peter"), VotePayload { vote: String::from("Paul") });
+        state.apply(&vote, 0, 1).unwrap();
+        assert_eq!(state.apply(&vote, 0, 1), Err(TransactionError::Nonce));
+    }
+
+    #[test]
+    fn reject_fees_without_ledger() {
+        // the fee would be paid out to the miner without being taken from the sender
+        let mut state = ChainState::default();
+        let mut vote = VotePayload::new(String::from("Peter"), VotePayload { vote: String::from("Paul") });
+        vote.fee = u32::MAX;
+        assert_eq!(state.apply(&vote, 1, 1), Err(TransactionError::Fee));
+        assert_eq!(state.next_nonce("Peter"), 0);
+
+        let mut state = ChainState::default();
+        let mut commit = CodePayload::new(String::from("Peter"), CodePayload {
+            file_name: String::from("Readme.md"),
+            contents: String::from("Schwurbel"),
+            commit_message: String::from("Update Readme"),
+        });
+        commit.fee = 1;
+        assert_eq!(state.apply(&commit, 1, 1), Err(TransactionError::Fee));
+        commit.fee = 0;
+        state.apply(&commit, 1, 1).unwrap();
+    }
+}
@@ -4,8 +4,18 @@ use std::fmt::Debug;
 use std::fmt::Write;
 use std::sync::{Arc, RwLock};
 
+use failure;
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use sequoia_openpgp::TPK;
 
+use crate::crypto::{hash, pgp};
+
+use super::ledger::Balances;
+use super::utxo::UtxoSet;
+use super::validation::TransactionError;
+
+/// The sender of the reward transactions created by `Transactional::genesis`.
+pub const REWARD_SENDER: &str = "Root";
 
 /// The transaction stored in a block of the blockchain.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,6 +24,15 @@ pub struct Transaction<T> {
     pub sender: String,
     /// The payload of the transaction.
     pub payload: Arc<RwLock<T>>,
+    /// The OpenPGP signed message of the sender over `signing_data`, empty for rewards.
+    #[serde(default)]
+    pub signature: Vec<u8>,
+    /// The fee the sender pays to the miner of the block including the transaction.
+    #[serde(default)]
+    pub fee: u32,
+    /// The number of transactions the sender sent before, so no transaction can be replayed.
+    #[serde(default)]
+    pub nonce: u64,
 }
 
 
@@ -25,6 +44,8 @@ impl<T> Transaction<T>
 
         write!(&mut str, "            Transaction: [\n").expect("[Transaction fmt()]: Unable to write in Buffer!");
         write!(&mut str, "                Sender:   {}\n", self.sender).expect("[Transaction fmt()]: Unable to write in Buffer!");
+        write!(&mut str, "                Fee:      {}\n", self.fee).expect("[Transaction fmt()]: Unable to write in Buffer!");
+        write!(&mut str, "                Nonce:    {}\n", self.nonce).expect("[Transaction fmt()]: Unable to write in Buffer!");
         write!(&mut str, "                Payload: [\n").expect("[Transaction fmt()]: Unable to write in Buffer!");
         write!(&mut str, "                    {:?}\n", self.payload).expect("[Transaction fmt()]: Unable to write in Buffer!");
         write!(&mut str, "                ]\n").expect("[Transaction fmt()]: Unable to write in Buffer!");
@@ -34,18 +55,99 @@ impl<T> Transaction<T>
     }
 }
 
+impl<T> Transaction<T>
+    where T: Serialize {
+    /// The hash identifying the transaction, e.g. in inventory announcements.
+    pub fn id(&self) -> String {
+        hash::hash(self)
+    }
+
+    /// The serialized size in bytes, the fee is paid per byte.
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self)
+            .expect("[Transaction size()]: Unable to serialize the transaction!")
+            .len()
+    }
+
+    /// The canonical encoding of sender, payload, fee and nonce covered by the signature.
+    pub fn signing_data(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.sender, &*self.payload.read().unwrap(), self.fee, self.nonce))
+            .expect("[Transaction signing_data()]: Unable to serialize the payload!")
+    }
+
+    /// Signs sender, payload, fee and nonce with the secret key of the sender.
+    pub fn sign(&mut self, tsk: &TPK) -> Result<(), failure::Error> {
+        let mut signature = Vec::new();
+        pgp::sign(&mut signature, &self.signing_data(), tsk)?;
+        self.signature = signature;
+        Ok(())
+    }
+
+    /// Checks whether the transaction carries a signature of its sender, payload, fee and nonce
+    /// by the key.
+    pub fn verify(&self, tpk: &TPK) -> bool {
+        let mut signed = Vec::new();
+        pgp::verify(&mut signed, &self.signature, tpk).is_ok() && signed == self.signing_data()
+    }
+}
+
 pub trait Transactional
 where Self: Sized + Send + Serialize + DeserializeOwned + PartialEq + Eq + Debug + Clone {
-    /// Creates a new transaction with a sender and the specified payload.
-    fn new(sender: String, payload: Self) -> Transaction<Self> { // , key: sequoia_openpgp::TPK
+    /// The ledger state built by replaying the transactions of a chain, e.g. account balances.
+    type State: Default + Clone + Debug + Send + Sync;
+
+    /// The name of the payload type, nodes only connect to peers using the same payload.
+    const NAME: &'static str;
+
+    /// Creates a new unsigned transaction with a sender and the specified payload.
+    fn new(sender: String, payload: Self) -> Transaction<Self> {
         Transaction {
             sender,
             payload: Arc::new(RwLock::new(payload)),
-            // FIXME create the sender signature with the key. 
+            signature: Vec::new(),
+            fee: 0,
+            nonce: 0,
         }
     }
 
+    /// Creates a new transaction with a sender, the specified payload and the next nonce of the
+    /// sender, signed with the secret key of the sender.
+    fn signed(sender: String, payload: Self, nonce: u64, tsk: &TPK) -> Result<Transaction<Self>, failure::Error> {
+        Self::signed_with_fee(sender, payload, nonce, 0, tsk)
+    }
+
+    /// Creates a new transaction paying the fee to the miner, signed with the secret key of the
+    /// sender.
+    fn signed_with_fee(sender: String, payload: Self, nonce: u64, fee: u32, tsk: &TPK) -> Result<Transaction<Self>, failure::Error> {
+        let mut transaction = Self::new(sender, payload);
+        transaction.nonce = nonce;
+        transaction.fee = fee;
+        transaction.sign(tsk)?;
+        Ok(transaction)
+    }
+
     fn genesis(miner_address: String, reward: u32) -> Transaction<Self>;
+
+    /// Applies the transaction at the index of a block at the given height to the ledger state.
+    ///
+    /// Leaves the state untouched if the transaction is rejected. Implementations have to take
+    /// the fee from the sender, as the fees are paid out to the miner.
+    /// Payloads without a ledger accept every transaction without a fee.
+    fn apply(_state: &mut Self::State, transaction: &Transaction<Self>, _height: usize, _index: usize)
+        -> Result<(), TransactionError> {
+        if transaction.fee > 0 {
+            return Err(TransactionError::Fee);
+        }
+        Ok(())
+    }
+
+    /// Checks whether the transaction is a reward transaction as created by `genesis` for the
+    /// given reward.
+    fn is_genesis(transaction: &Transaction<Self>, reward: u32) -> bool {
+        let expected = Self::genesis(String::new(), reward);
+        transaction.sender == REWARD_SENDER
+            && *transaction.payload.read().unwrap() == *expected.payload.read().unwrap()
+    }
 }
 
 // Examples: Crypto currency, Code, voting, timestamping of arbitary objects
@@ -76,38 +178,124 @@ pub struct CodePayload {
     pub commit_message: String,
 }
 
+/// A reference to an output of a previous transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    /// The id of the transaction, see `utxo::transaction_id`.
+    pub transaction: String,
+    /// The index of the output in the transaction.
+    pub index: u32,
+}
+
+/// An amount of coins assigned to a receiver.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Output {
+    /// The receiver of the coins.
+    pub receiver: String,
+    /// The amount of coins.
+    pub amount: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A payload for a cryptographic currency that spends previous outputs instead of balances.
+pub struct UtxoPayload {
+    /// The unspent outputs of the sender consumed by the transaction.
+    pub inputs: Vec<OutPoint>,
+    /// The outputs created by the transaction.
+    pub outputs: Vec<Output>,
+}
+
 impl Transactional for CryptoPayload {
+    type State = Balances;
+    const NAME: &'static str = "crypto";
+
     fn genesis(miner_address: String, reward: u32) -> Transaction<CryptoPayload> {
         Transaction {
-            sender: String::from("Root"),
+            sender: String::from(REWARD_SENDER),
             payload: Arc::new(RwLock::new(CryptoPayload {
                 receiver: miner_address,
                 amount: reward,
             })),
+            signature: Vec::new(),
+            fee: 0,
+            nonce: 0,
+        }
+    }
+
+    fn is_genesis(transaction: &Transaction<CryptoPayload>, reward: u32) -> bool {
+        transaction.sender == REWARD_SENDER && transaction.payload.read().unwrap().amount == reward
+    }
+
+    fn apply(state: &mut Balances, transaction: &Transaction<CryptoPayload>, _height: usize, _index: usize)
+        -> Result<(), TransactionError> {
+        state.transfer(transaction)
+    }
+}
+
+impl Transactional for UtxoPayload {
+    type State = UtxoSet;
+    const NAME: &'static str = "utxo";
+
+    fn genesis(miner_address: String, reward: u32) -> Transaction<UtxoPayload> {
+        Transaction {
+            sender: String::from(REWARD_SENDER),
+            payload: Arc::new(RwLock::new(UtxoPayload {
+                inputs: vec![],
+                outputs: vec![Output {
+                    receiver: miner_address,
+                    amount: reward,
+                }],
+            })),
+            signature: Vec::new(),
+            fee: 0,
+            nonce: 0,
         }
     }
+
+    fn is_genesis(transaction: &Transaction<UtxoPayload>, reward: u32) -> bool {
+        let payload = transaction.payload.read().unwrap();
+        transaction.sender == REWARD_SENDER && payload.inputs.is_empty()
+            && payload.outputs.iter().map(|output| u64::from(output.amount)).sum::<u64>() == u64::from(reward)
+    }
+
+    fn apply(state: &mut UtxoSet, transaction: &Transaction<UtxoPayload>, height: usize, index: usize)
+        -> Result<(), TransactionError> {
+        state.spend(transaction, height, index)
+    }
 }
 
 impl Transactional for VotePayload {
+    type State = ();
+    const NAME: &'static str = "vote";
+
     fn genesis(_miner_address: String, _reward: u32) -> Transaction<VotePayload> {
         Transaction {
-            sender: String::from("Root"),
+            sender: String::from(REWARD_SENDER),
             payload: Arc::new(RwLock::new(VotePayload {
                 vote: String::from("Root"),
             })),
+            signature: Vec::new(),
+            fee: 0,
+            nonce: 0,
         }
     }
 }
 
 impl Transactional for CodePayload {
+    type State = ();
+    const NAME: &'static str = "code";
+
     fn genesis(_miner_address: String, _reward: u32) -> Transaction<CodePayload> {
         Transaction {
-            sender: String::from("Root"),
+            sender: String::from(REWARD_SENDER),
             payload: Arc::new(RwLock::new(CodePayload {
                 file_name: String::from("Readme.md"),
                 contents: String::from(""),
                 commit_message: String::from("Initialize Repository"),
             })),
+            signature: Vec::new(),
+            fee: 0,
+            nonce: 0,
         }
     }
 }
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::block::Block;
+use super::chain::genesis_pre_hash;
+use super::transaction::Transactional;
+use super::validation::Rule;
+
+/// A block known to the tree.
+#[derive(Clone, Debug)]
+struct Entry<T> {
+    /// The block itself.
+    block: Block<T>,
+    /// The height of the block, starting with 0 for a genesis block.
+    height: usize,
+    /// The accumulated work of the branch from its genesis block up to and including this block.
+    work: u128,
+}
+
+/// A tree of the recent blocks, containing the tip of the active chain as well as competing
+/// branches.
+///
+/// Blocks are keyed by the hash of their header and linked to their parent by the previous hash.
+/// Every block in the tree has been verified against its parent. Pruning keeps a single root, all
+/// other blocks descend from it.
+#[derive(Clone, Debug)]
+pub struct BlockTree<T> {
+    entries: HashMap<String, Entry<T>>,
+}
+
+impl<T> Default for BlockTree<T> {
+    fn default() -> Self {
+        BlockTree {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<T> BlockTree<T>
+where T: Serialize + DeserializeOwned + Debug + Clone + Transactional + Send
+{
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// Adds a block to the tree.
+    ///
+    /// The parent of the block has to be known, except for genesis blocks, which the chain
+    /// checks against its spec instead of the rules of other blocks.
+    /// Returns the hash of the block or the first rule it broke.
+    pub fn insert(&mut self, block: Block<T>) -> Result<String, Rule> {
+        let hash = block.hash();
+        if self.contains(&hash) {
+            return Ok(hash);
+        }
+
+        let pre_hash = block.header.pre_hash().to_string();
+        let (height, work) = if pre_hash == genesis_pre_hash() {
+            (0, 0)
+        } else {
+            match self.entries.get(&pre_hash) {
+                Some(parent) => {
+                    block.verify(&pre_hash)?;
+                    (parent.height + 1, parent.work)
+                }
+                None => return Err(Rule::UnknownParent),
+            }
+        };
+
+        let work = work.saturating_add(block.header.work());
+        self.entries.insert(hash.clone(), Entry { block, height, work });
+        Ok(hash)
+    }
+
+    /// The block with the given hash.
+    pub fn get(&self, hash: &str) -> Option<&Block<T>> {
+        self.entries.get(hash).map(|entry| &entry.block)
+    }
+
+    /// The height of the given block.
+    pub fn height(&self, hash: &str) -> Option<usize> {
+        self.entries.get(hash).map(|entry| entry.height)
+    }
+
+    /// Looks up the block at the given height on the branch ending in the given block.
+    pub fn ancestor(&self, tip: &str, height: usize) -> Option<&Block<T>> {
+        let hash = self.ancestor_hash(tip, height)?;
+        self.get(hash)
+    }
+
+    /// The hash of the block at the given height on the branch ending in the given block.
+    fn ancestor_hash<'a>(&'a self, tip: &'a str, height: usize) -> Option<&'a str> {
+        let mut hash = tip;
+        let mut entry = self.entries.get(tip)?;
+        while entry.height > height {
+            hash = entry.block.header.pre_hash();
+            entry = self.entries.get(hash)?;
+        }
+        if entry.height == height {
+            Some(hash)
+        } else {
+            None
+        }
+    }
+
+    /// The accumulated work of the branch ending in the given block.
+    pub fn work(&self, hash: &str) -> Option<u128> {
+        self.entries.get(hash).map(|entry| entry.work)
+    }
+
+    /// The hash of the block ending the branch with the most accumulated work.
+    pub fn heaviest(&self) -> Option<&str> {
+        self.entries.iter()
+            .max_by_key(|(_, entry)| entry.work)
+            .map(|(hash, _)| hash.as_str())
+    }
+
+    /// Collects the blocks of the branch ending in the given block, starting with the oldest one
+    /// kept in the tree.
+    pub fn branch(&self, tip: &str) -> Vec<Block<T>> {
+        let mut branch = Vec::new();
+        let mut next = self.entries.get(tip);
+        while let Some(entry) = next {
+            branch.push(entry.block.clone());
+            next = self.entries.get(entry.block.header.pre_hash());
+        }
+        branch.reverse();
+        branch
+    }
+
+    /// Removes all blocks except the given one and the branches descending from it.
+    pub fn prune(&mut self, root: &str) {
+        let height = match self.height(root) {
+            Some(height) => height,
+            None => return,
+        };
+        let kept: Vec<String> = self.entries.keys()
+            .filter(|hash| self.ancestor_hash(hash, height) == Some(root))
+            .cloned()
+            .collect();
+        let mut entries = HashMap::new();
+        for hash in kept {
+            if let Some(entry) = self.entries.remove(&hash) {
+                entries.insert(hash, entry);
+            }
+        }
+        self.entries = entries;
+    }
+}
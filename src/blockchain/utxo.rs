@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::crypto::hash;
+
+use super::transaction::{OutPoint, Output, Transaction, UtxoPayload, REWARD_SENDER};
+use super::validation::TransactionError;
+
+/// The number of blocks a reward has to be buried under before it can be spent.
+pub const COINBASE_MATURITY: usize = 100;
+
+/// The id of a transaction at the index of a block at the given height, referenced by
+/// `OutPoint`s.
+///
+/// Reward transactions of the same miner and amount are identical, e.g. two allocations of a
+/// genesis block, so their id includes the height of their block and their index within it.
+pub fn transaction_id(transaction: &Transaction<UtxoPayload>, height: usize, index: usize) -> String {
+    if transaction.sender == REWARD_SENDER {
+        hash::hash(&(height, index, transaction))
+    } else {
+        hash::hash(transaction)
+    }
+}
+
+/// An unspent output together with the block it was created in.
+#[derive(Clone, Debug)]
+struct Unspent {
+    output: Output,
+    /// The height of the block containing the transaction of the output.
+    height: usize,
+    /// Whether the output was created by a reward transaction.
+    reward: bool,
+}
+
+/// The unspent transaction outputs of a chain, built by replaying its transactions.
+#[derive(Clone, Debug, Default)]
+pub struct UtxoSet {
+    unspent: HashMap<OutPoint, Unspent>,
+}
+
+impl UtxoSet {
+    /// The unspent output referenced by the out point.
+    pub fn get(&self, out_point: &OutPoint) -> Option<&Output> {
+        self.unspent.get(out_point).map(|unspent| &unspent.output)
+    }
+
+    /// The unspent outputs received by the address.
+    pub fn unspent_of(&self, address: &str) -> Vec<(OutPoint, Output)> {
+        self.unspent.iter()
+            .filter(|(_, unspent)| unspent.output.receiver == address)
+            .map(|(out_point, unspent)| (out_point.clone(), unspent.output.clone()))
+            .collect()
+    }
+
+    /// The sum of the unspent outputs received by the address.
+    pub fn balance_of(&self, address: &str) -> u64 {
+        self.unspent.values()
+            .filter(|unspent| unspent.output.receiver == address)
+            .map(|unspent| u64::from(unspent.output.amount))
+            .sum()
+    }
+
+    /// Spends the inputs of the transaction at the index of a block at the given height and adds
+    /// its outputs.
+    ///
+    /// Every input has to reference a distinct unspent output of the sender, rewards only after
+    /// `COINBASE_MATURITY` blocks, and the outputs plus the fee may not exceed the inputs. Rewards
+    /// have no inputs and mint their outputs. The set is left untouched if the transaction is
+    /// rejected.
+    pub fn spend(&mut self, transaction: &Transaction<UtxoPayload>, height: usize, index: usize)
+        -> Result<(), TransactionError> {
+        let payload = transaction.payload.read().unwrap();
+        let reward = transaction.sender == REWARD_SENDER;
+
+        if payload.outputs.iter().any(|output| output.amount == 0) {
+            return Err(TransactionError::ZeroAmount);
+        }
+        let spent = payload.outputs.iter()
+            .try_fold(0u32, |sum, output| sum.checked_add(output.amount))
+            .ok_or(TransactionError::Overflow)?;
+
+        if !reward {
+            if payload.inputs.is_empty() {
+                return Err(TransactionError::ZeroAmount);
+            }
+            let mut seen = HashSet::new();
+            let mut available = 0u64;
+            for input in &payload.inputs {
+                let unspent = match self.unspent.get(input) {
+                    Some(unspent) if seen.insert(input) => unspent,
+                    _ => return Err(TransactionError::Spent),
+                };
+                if unspent.output.receiver != transaction.sender {
+                    return Err(TransactionError::Owner);
+                }
+                if unspent.reward && height < unspent.height + COINBASE_MATURITY {
+                    return Err(TransactionError::Immature);
+                }
+                available += u64::from(unspent.output.amount);
+            }
+            if u64::from(spent) + u64::from(transaction.fee) > available {
+                return Err(TransactionError::Overdraft);
+            }
+            for input in &payload.inputs {
+                self.unspent.remove(input);
+            }
+        }
+
+        let id = transaction_id(transaction, height, index);
+        for (index, output) in payload.outputs.iter().enumerate() {
+            let out_point = OutPoint {
+                transaction: id.clone(),
+                index: index as u32,
+            };
+            self.unspent.insert(out_point, Unspent { output: output.clone(), height, reward });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::transaction::{OutPoint, Output, Transaction, Transactional, UtxoPayload};
+    use crate::blockchain::utxo::{transaction_id, UtxoSet, COINBASE_MATURITY};
+    use crate::blockchain::validation::TransactionError;
+
+    fn payment(sender: &str, inputs: Vec<OutPoint>, outputs: &[(&str, u32)]) -> Transaction<UtxoPayload> {
+        UtxoPayload::new(String::from(sender), UtxoPayload {
+            inputs,
+            outputs: outputs.iter()
+                .map(|(receiver, amount)| Output { receiver: String::from(*receiver), amount: *amount })
+                .collect(),
+        })
+    }
+
+    fn out_point(transaction: &Transaction<UtxoPayload>, height: usize, position: usize, index: u32) -> OutPoint {
+        OutPoint {
+            transaction: transaction_id(transaction, height, position),
+            index,
+        }
+    }
+
+    #[test]
+    fn spend_outputs() {
+        let mut utxos = UtxoSet::default();
+        let reward = UtxoPayload::genesis(String::from("Schwurbel"), 100);
+        utxos.spend(&reward, 0, 0).unwrap();
+        utxos.spend(&reward, 1, 0).unwrap();
+        assert_eq!(utxos.balance_of("Schwurbel"), 200);
+
+        let payment = payment("Schwurbel", vec![out_point(&reward, 0, 0, 0)], &[("Peter", 42), ("Schwurbel", 58)]);
+        utxos.spend(&payment, COINBASE_MATURITY, 1).unwrap();
+
+        assert_eq!(utxos.balance_of("Schwurbel"), 158);
+        assert_eq!(utxos.balance_of("Peter"), 42);
+        assert_eq!(utxos.unspent_of("Peter"), vec![(out_point(&payment, 0, 1, 0), Output { receiver: String::from("Peter"), amount: 42 })]);
+        assert_eq!(utxos.get(&out_point(&reward, 0, 0, 0)), None);
+    }
+
+    #[test]
+    fn reject_spends() {
+        let mut utxos = UtxoSet::default();
+        let reward = UtxoPayload::genesis(String::from("Schwurbel"), 100);
+        utxos.spend(&reward, 0, 0).unwrap();
+        let input = out_point(&reward, 0, 0, 0);
+
+        assert_eq!(utxos.spend(&payment("Schwurbel", vec![input.clone()], &[("Peter", 42)]), 1, 1),
+                   Err(TransactionError::Immature));
+        let height = COINBASE_MATURITY;
+        assert_eq!(utxos.spend(&payment("Peter", vec![input.clone()], &[("Peter", 42)]), height, 1),
+                   Err(TransactionError::Owner));
+        assert_eq!(utxos.spend(&payment("Schwurbel", vec![input.clone()], &[("Peter", 101)]), height, 1),
+                   Err(TransactionError::Overdraft));
+        assert_eq!(utxos.spend(&payment("Schwurbel", vec![input.clone(), input.clone()], &[("Peter", 200)]), height, 1),
+                   Err(TransactionError::Spent));
+        assert_eq!(utxos.spend(&payment("Schwurbel", vec![input.clone()], &[("Peter", 0)]), height, 1),
+                   Err(TransactionError::ZeroAmount));
+        assert_eq!(utxos.balance_of("Schwurbel"), 100);
+
+        utxos.spend(&payment("Schwurbel", vec![input.clone()], &[("Peter", 100)]), height, 1).unwrap();
+        assert_eq!(utxos.spend(&payment("Schwurbel", vec![input], &[("Paul", 100)]), height, 1),
+                   Err(TransactionError::Spent));
+    }
+
+    #[test]
+    fn distinguish_identical_rewards() {
+        // two allocations of a genesis block to the same address
+        let mut utxos = UtxoSet::default();
+        let allocation = UtxoPayload::genesis(String::from("Schwurbel"), 100);
+        utxos.spend(&allocation, 0, 0).unwrap();
+        utxos.spend(&allocation, 0, 1).unwrap();
+        assert_eq!(utxos.balance_of("Schwurbel"), 200);
+        assert_eq!(utxos.unspent_of("Schwurbel").len(), 2);
+
+        let inputs = vec![out_point(&allocation, 0, 0, 0), out_point(&allocation, 0, 1, 0)];
+        utxos.spend(&payment("Schwurbel", inputs, &[("Peter", 200)]), COINBASE_MATURITY, 1).unwrap();
+        assert_eq!(utxos.balance_of("Peter"), 200);
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt;
+
+use failure::Fail;
+
+/// The rules a block has to obey to be part of a valid chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// The genesis block has to be the one of the chain spec.
+    Genesis,
+    /// The previous hash of the header has to be the hash of the preceding block's header.
+    PreviousHash,
+    /// The preceding block has to be known.
+    UnknownParent,
+    /// The transaction count has to match the number of transactions in the block.
+    TransactionCount,
+    /// The first transaction has to be the reward transaction for the reward in the header
+    /// and no other transaction may be sent by the reward sender.
+    Reward,
+    /// The merkle root has to summarize the transactions of the block.
+    Merkle,
+    /// The difficulty has to match the difficulty derived by the retargeting.
+    Difficulty,
+    /// Every transaction has to be accepted by the ledger state of the chain.
+    Ledger(TransactionError),
+    /// Every transaction except the reward has to be signed by its sender.
+    Signature,
+    /// The reward has to be the subsidy of the block's height plus the fees of its
+    /// transactions.
+    Subsidy,
+    /// The hash of the header has to fulfill the proof of work for its difficulty.
+    ProofOfWork,
+    /// The block may not contain more transactions than the block limits allow.
+    TransactionLimit,
+    /// No transaction may exceed the maximum transaction size.
+    TransactionSize,
+    /// The serialized block may not exceed the maximum block size.
+    BlockSize,
+    /// The timestamp has to be later than the median timestamp of the preceding blocks.
+    Timestamp,
+    /// The timestamp may not be too far ahead of the local clock.
+    FutureTimestamp,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rule::Genesis => write!(f, "genesis block does not match the chain spec"),
+            Rule::PreviousHash => write!(f, "previous hash does not match the preceding block"),
+            Rule::UnknownParent => write!(f, "previous hash references an unknown block"),
+            Rule::TransactionCount => write!(f, "transaction count does not match the transactions"),
+            Rule::Reward => write!(f, "missing or invalid reward transaction"),
+            Rule::Merkle => write!(f, "merkle root does not match the transactions"),
+            Rule::Difficulty => write!(f, "difficulty does not match the retargeting"),
+            Rule::Ledger(error) => write!(f, "transaction rejected: {}", error),
+            Rule::Signature => write!(f, "transaction is not signed by its sender"),
+            Rule::Subsidy => write!(f, "reward does not match the subsidy plus the fees"),
+            Rule::ProofOfWork => write!(f, "hash does not meet the difficulty"),
+            Rule::TransactionLimit => write!(f, "block contains too many transactions"),
+            Rule::TransactionSize => write!(f, "transaction exceeds the maximum transaction size"),
+            Rule::BlockSize => write!(f, "block exceeds the maximum block size"),
+            Rule::Timestamp => write!(f, "timestamp is not later than the median of the preceding blocks"),
+            Rule::FutureTimestamp => write!(f, "timestamp is too far in the future"),
+        }
+    }
+}
+
+/// Error returned when validating a chain.
+///
+/// Names the height of the first offending block and the rule it broke.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+#[fail(display = "Invalid block at height {}: {}", height, rule)]
+pub struct ValidationError {
+    /// The height of the offending block, starting with 0 for the genesis block.
+    pub height: usize,
+    /// The rule the block broke.
+    pub rule: Rule,
+}
+
+/// Error returned when the ledger state of a chain rejects a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Fail)]
+pub enum TransactionError {
+    /// Only the first transaction of a block may pay out the reward.
+    #[fail(display = "reward transactions are only allowed as first transaction of a block")]
+    Reward,
+    /// Transfers have to move a positive amount.
+    #[fail(display = "amount has to be greater than zero")]
+    ZeroAmount,
+    /// The sender can't spend more than it owns.
+    #[fail(display = "sender does not own enough coins")]
+    Overdraft,
+    /// No balance may exceed the maximum amount.
+    #[fail(display = "balance of the receiver overflows")]
+    Overflow,
+    /// Inputs have to reference an unspent output, at most once.
+    #[fail(display = "input references an unknown or already spent output")]
+    Spent,
+    /// Inputs have to reference outputs received by the sender.
+    #[fail(display = "input references an output of another receiver")]
+    Owner,
+    /// Rewards can't be spent before they matured.
+    #[fail(display = "input references an immature reward")]
+    Immature,
+    /// The public key of the sender has to be known to check the signature.
+    #[fail(display = "public key of the sender is unknown")]
+    UnknownSender,
+    /// The transaction has to be signed by the sender.
+    #[fail(display = "signature does not match the sender and payload")]
+    Signature,
+    /// Every transaction has to carry the next nonce of its sender.
+    #[fail(display = "nonce does not follow the previous transaction of the sender")]
+    Nonce,
+    /// Payloads without a ledger can't take the fee from the sender.
+    #[fail(display = "fee can't be paid without a ledger")]
+    Fee,
+}
@@ -1,2 +1,3 @@
 pub mod hash;
-pub mod merkle;
\ No newline at end of file
+pub mod merkle;
+pub mod pgp;
\ No newline at end of file
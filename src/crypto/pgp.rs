@@ -1,25 +1,191 @@
 //! OpenPGP implementations. Can be used to generate, sign, verify, encrypt, decrypt, import
-//! and export PGP keys. Not used since there is no networking.
+//! and export PGP keys. Signing and verification secure the transactions.
 
-use std::collections::HashMap;
 use std::io::{self, Write, Read};
 use uuid::Uuid;
-use sequoia_openpgp::TPK;
+use sequoia_openpgp::{Packet, TPK};
 use sequoia_openpgp::serialize::stream::*;
 use sequoia_openpgp::parse::{stream::*, Parse};
 use sequoia_openpgp::constants::SymmetricAlgorithm;
-use sequoia_openpgp::crypto::{self, SessionKey};
+use sequoia_openpgp::crypto::{self, Password, SessionKey};
 use sequoia_openpgp::tpk::armor::Encoder;
 use failure;
 use sequoia_openpgp::serialize::Serialize;
 use sequoia_openpgp::packet::Signature;
 
-/// Simple Keyring
-/// Just maps known ids to TPKs
-struct Keyring {
-    peers: HashMap<Uuid, TPK>,
-    key: TPK,
-    user: Uuid,
+use crate::storage::storage::{Batch, Namespaced, Storage};
+
+/// The keyspace of the keyring.
+pub const NAMESPACE: &str = "keys";
+/// Storage key of the node's own key pair, encrypted with the passphrase.
+const OWN_KEY: &[u8] = b"own/key";
+/// Storage key of the public part of the node's own key pair.
+const OWN_PUBLIC_KEY: &[u8] = b"own/public_key";
+/// Storage key of the revocation certificate of the node's own key pair.
+const OWN_REVOCATION: &[u8] = b"own/revocation";
+/// Storage key of the node's own id.
+const OWN_UUID: &[u8] = b"own/uuid";
+/// Storage key of the ids of all known peers.
+const PEERS: &[u8] = b"peers";
+
+/// Keyring persisted through a storage backend.
+///
+/// Stores the node's own key pair, whose secret keys are protected by a passphrase, together with
+/// its revocation certificate and the public keys of known peers.
+pub struct Keyring<S> {
+    storage: S,
+}
+
+impl<S> Keyring<S>
+where S: Storage
+{
+    pub fn new(storage: S) -> Keyring<S> {
+        Keyring {
+            storage,
+        }
+    }
+
+    /// Opens the keyring in its own keyspace of the storage.
+    pub fn open<N>(storage: &mut N) -> Result<Keyring<S>, failure::Error>
+    where N: Namespaced<Namespace = S>
+    {
+        Ok(Keyring::new(storage.namespace(NAMESPACE)?))
+    }
+
+    /// Generates and stores the node's own key pair unless one is stored already.
+    /// Returns the id of the node.
+    pub fn init(&mut self, passphrase: &str) -> Result<Uuid, failure::Error> {
+        if let Some(uuid) = self.own_uuid()? {
+            return Ok(uuid);
+        }
+        let uuid = Uuid::new_v4();
+        let (tsk, revocation) = generate(uuid)?;
+        self.set_own_key(uuid, &tsk, &revocation, passphrase)?;
+        Ok(uuid)
+    }
+
+    /// Stores the node's own key pair, encrypting it with the passphrase.
+    pub fn set_own_key(&mut self, uuid: Uuid, tsk: &TPK, revocation: &Signature, passphrase: &str)
+                       -> Result<(), failure::Error> {
+        let mut secret = Vec::new();
+        tsk.as_tsk().serialize(&mut secret)?;
+        let passphrase: Password = passphrase.to_string().into();
+        let mut encrypted = Vec::new();
+        encrypt_with_passphrase(&mut encrypted, &secret, &passphrase)?;
+        let mut certificate = Vec::new();
+        revocation.serialize(&mut certificate)?;
+
+        let mut batch = Batch::new();
+        batch.put(OWN_KEY.to_vec(), encrypted);
+        batch.put(OWN_PUBLIC_KEY.to_vec(), public_bytes(tsk)?);
+        batch.put(OWN_REVOCATION.to_vec(), certificate);
+        batch.put(OWN_UUID.to_vec(), uuid.as_bytes().to_vec());
+        self.storage.write_batch(batch)
+    }
+
+    /// The id of the node, if its key pair is stored.
+    pub fn own_uuid(&self) -> Result<Option<Uuid>, failure::Error> {
+        match self.storage.get(OWN_UUID)? {
+            Some(bytes) => Ok(Some(Uuid::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The node's own key pair including the secret keys, decrypted with the passphrase.
+    pub fn own_key(&self, passphrase: &str) -> Result<Option<TPK>, failure::Error> {
+        match self.storage.get(OWN_KEY)? {
+            Some(encrypted) => {
+                let passphrase: Password = passphrase.to_string().into();
+                let mut secret = Vec::new();
+                decrypt_with_passphrase(&mut secret, &encrypted, &passphrase)?;
+                Ok(Some(TPK::from_bytes(&secret)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The public part of the node's own key pair, e.g. to hand it to peers.
+    pub fn own_public_key(&self) -> Result<Option<TPK>, failure::Error> {
+        match self.storage.get(OWN_PUBLIC_KEY)? {
+            Some(bytes) => Ok(Some(from_bytes(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The revocation certificate of the node's own key pair.
+    pub fn revocation(&self) -> Result<Option<Signature>, failure::Error> {
+        match self.storage.get(OWN_REVOCATION)? {
+            Some(bytes) => match Packet::from_bytes(&bytes)? {
+                Packet::Signature(signature) => Ok(Some(signature)),
+                _ => Err(failure::err_msg("Stored revocation certificate is no signature")),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Adds or replaces the public key of a peer.
+    pub fn add(&mut self, peer: Uuid, tpk: &TPK) -> Result<(), failure::Error> {
+        let mut batch = Batch::new();
+        batch.put(peer_key(&peer), public_bytes(tpk)?);
+        let mut peers = self.list()?;
+        if !peers.contains(&peer) {
+            peers.push(peer);
+            batch.put(PEERS.to_vec(), serde_json::to_vec(&peers)?);
+        }
+        self.storage.write_batch(batch)
+    }
+
+    /// Looks up the public key of a peer.
+    pub fn lookup(&self, peer: &Uuid) -> Result<Option<TPK>, failure::Error> {
+        match self.storage.get(&peer_key(peer))? {
+            Some(bytes) => Ok(Some(from_bytes(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the public key of a peer.
+    /// Returns whether the peer was known.
+    pub fn remove(&mut self, peer: &Uuid) -> Result<bool, failure::Error> {
+        let mut batch = Batch::new();
+        let mut peers = self.list()?;
+        let known = peers.contains(peer);
+        if known {
+            peers.retain(|other| other != peer);
+            batch.put(PEERS.to_vec(), serde_json::to_vec(&peers)?);
+        }
+        batch.delete(peer_key(peer));
+        self.storage.write_batch(batch)?;
+        Ok(known)
+    }
+
+    /// The ids of all peers whose public keys are known.
+    pub fn list(&self) -> Result<Vec<Uuid>, failure::Error> {
+        match self.storage.get(PEERS)? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Imports the public key of a peer from a file written by [`export_key`].
+    pub fn import(&mut self, peer: Uuid, path: &str) -> Result<TPK, failure::Error> {
+        let tpk = import_key(path)?;
+        self.add(peer, &tpk)?;
+        Ok(tpk)
+    }
+
+    /// Exports the public key of the node to a file, see [`export_key`].
+    /// Returns the name of the created file.
+    pub fn export(&self) -> Result<String, failure::Error> {
+        match self.own_public_key()? {
+            Some(tpk) => export_key(&tpk),
+            None => Err(failure::err_msg("No own key pair stored")),
+        }
+    }
+}
+
+/// The storage key of the public key of a peer.
+fn peer_key(peer: &Uuid) -> Vec<u8> {
+    format!("peer/{}", peer).into_bytes()
 }
 
 /// Generates an encryption-capable key.
@@ -100,8 +266,21 @@ fn sign_detached<R: Read, W: Write>(tsk: TPK, mut src: &mut R, dest: &mut W) ->
     Ok(())
 }
 
+/// Serializes the public part of a TPK, e.g. to hand it to other nodes.
+/// Note: secret keys not attached
+pub fn public_bytes(tpk: &TPK) -> Result<Vec<u8>, failure::Error> {
+    let mut bytes = Vec::new();
+    tpk.serialize(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Parses a TPK serialized by [`public_bytes`].
+pub fn from_bytes(bytes: &[u8]) -> Result<TPK, failure::Error> {
+    TPK::from_bytes(bytes)
+}
+
 /// Signs the given message.
-fn sign(sink: &mut Write, data: &[u8], tsk: &TPK)
+pub fn sign(sink: &mut Write, data: &[u8], tsk: &TPK)
         -> sequoia_openpgp::Result<()> {
     let mut keypair = tsk.keys_valid().signing_capable().nth(0).unwrap().2
         .clone().into_keypair()?;
@@ -119,7 +298,7 @@ fn sign(sink: &mut Write, data: &[u8], tsk: &TPK)
 /// Verifies the given message.
 /// Returns:
 /// Ok(()) on successful verification, Err(_) on failure.
-fn verify(sink: &mut Write, signed_message: &[u8], sender: &TPK)
+pub fn verify(sink: &mut Write, signed_message: &[u8], sender: &TPK)
           -> sequoia_openpgp::Result<()> {
 
     let helper = SignHelper {
@@ -151,6 +330,38 @@ fn encrypt(sink: &mut Write, plaintext: &str, recipient: &sequoia_openpgp::TPK)
     Ok(())
 }
 
+/// Encrypts the given data with a passphrase, e.g. to store secret keys.
+fn encrypt_with_passphrase(sink: &mut Write, plaintext: &[u8], passphrase: &Password)
+                           -> sequoia_openpgp::Result<()> {
+
+    let message = Message::new(sink);
+    let encryptor = Encryptor::new(message,
+                                   &[passphrase],
+                                   &[], // No public key encryption.
+                                   EncryptionMode::AtRest,
+                                   None)?;
+
+    let mut literal_writer = LiteralWriter::new(
+        encryptor, sequoia_openpgp::constants::DataFormat::Binary, None, None)?;
+
+    literal_writer.write_all(plaintext)?;
+    literal_writer.finalize()?;
+
+    Ok(())
+}
+
+/// Decrypts data encrypted by `encrypt_with_passphrase`.
+fn decrypt_with_passphrase(sink: &mut Write, ciphertext: &[u8], passphrase: &Password)
+                           -> sequoia_openpgp::Result<()> {
+
+    let helper = PassphraseHelper {
+        passphrase,
+    };
+    let mut decryptor = Decryptor::from_bytes(ciphertext, helper, None)?;
+    io::copy(&mut decryptor, sink)?;
+    Ok(())
+}
+
 /// Decrypts the given message.
 fn decrypt(sink: &mut Write, ciphertext: &[u8], recipient: &sequoia_openpgp::TPK)
            -> sequoia_openpgp::Result<()> {
@@ -175,6 +386,12 @@ struct CryptHelper<'a> {
     secret: &'a TPK,
 }
 
+/// A wrapper holding a passphrase.
+/// Required by Decryptor for data encrypted at rest.
+struct PassphraseHelper<'a> {
+    passphrase: &'a Password,
+}
+
 /// Implementation of our signature verification policy ala sequoia examples.
 impl<'a> VerificationHelper for SignHelper<'a> {
     /// Returns:
@@ -233,6 +450,36 @@ impl<'a> VerificationHelper for CryptHelper<'a> {
     }
 }
 
+impl<'a> VerificationHelper for PassphraseHelper<'a> {
+    fn get_public_keys(&mut self, _ids: &[sequoia_openpgp::KeyID])
+                       -> sequoia_openpgp::Result<Vec<sequoia_openpgp::TPK>> {
+        Ok(Vec::new())
+    }
+
+    fn check(&mut self, _structure: &MessageStructure)
+             -> sequoia_openpgp::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decrypts the session key with the passphrase.
+impl<'a> DecryptionHelper for PassphraseHelper<'a> {
+    fn decrypt<D>(&mut self,
+                  _pkesks: &[sequoia_openpgp::packet::PKESK],
+                  skesks: &[sequoia_openpgp::packet::SKESK],
+                  mut decrypt: D)
+                  -> sequoia_openpgp::Result<Option<sequoia_openpgp::Fingerprint>>
+        where D: FnMut(SymmetricAlgorithm, &SessionKey) -> sequoia_openpgp::Result<()>
+    {
+        let skesk = skesks.get(0)
+            .ok_or_else(|| failure::err_msg("Not encrypted with a passphrase"))?;
+
+        skesk.decrypt(self.passphrase)
+            .and_then(|(algo, session_key)| decrypt(algo, &session_key))
+            .map(|_| None)
+    }
+}
+
 /// Implementation of our decryption policy ala sequoia examples.
 impl<'a> DecryptionHelper for CryptHelper<'a> {
     /// Actual method used for decryption.
@@ -263,6 +510,7 @@ impl<'a> DecryptionHelper for CryptHelper<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::hashmap::Backend;
 
     const MESSAGE: &'static str = "дружба";
 
@@ -304,6 +552,28 @@ mod tests {
         assert_eq!(MESSAGE.as_bytes(), &plaintext[..]);
     }
 
+    #[test]
+    fn test_keyring() {
+        let mut keyring = Keyring::new(Backend::new());
+        let user = keyring.init("passphrase").unwrap();
+        assert_eq!(keyring.init("passphrase").unwrap(), user);
+        assert!(keyring.own_key("passphrase").unwrap().unwrap().is_tsk());
+        assert!(keyring.own_key("wrong").is_err());
+        assert!(!keyring.own_public_key().unwrap().unwrap().is_tsk());
+        assert!(keyring.revocation().unwrap().is_some());
+
+        let peer = Uuid::new_v4();
+        let (key, _) = generate(peer).unwrap();
+        keyring.add(peer, &key).unwrap();
+        assert_eq!(keyring.list().unwrap(), vec![peer]);
+        assert_eq!(keyring.lookup(&peer).unwrap().unwrap().fingerprint(), key.fingerprint());
+
+        assert!(keyring.remove(&peer).unwrap());
+        assert!(!keyring.remove(&peer).unwrap());
+        assert_eq!(keyring.lookup(&peer).unwrap(), None);
+        assert!(keyring.list().unwrap().is_empty());
+    }
+
     #[test]
     fn test_sign() {
         let user = Uuid::new_v4();
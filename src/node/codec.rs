@@ -1,43 +1,106 @@
 use std::str;
 use std::io;
 use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
 
 use bytes::{BytesMut, BufMut};
 use tokio::codec::{Encoder, Decoder};
-use serde::{Serialize, de::DeserializeOwned};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use serde_json;
+use bincode;
 
 use super::messages::Messages;
 use crate::blockchain::transaction::Transactional;
 
+/// The largest frame accepted from a peer, in bytes
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// First byte of a binary frame, JSON lines never start with it
+const BINARY_FRAME: u8 = 0;
+/// Size of the marker and the length prefix of a binary frame
+const BINARY_HEADER: usize = 5;
+
+/// The encoding of the messages sent to a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Format {
+    /// A JSON object per line, readable for debugging
+    Json,
+    /// A marker byte, the length as big endian u32 and the bincode encoded message
+    Binary,
+}
+
+/// Frames messages in the negotiated format.
+///
+/// Frames of both formats are decoded, so the peer may switch its format at any time. Messages
+/// are sent as JSON until the format the peer asked for is set.
 pub struct MessagesCodec<T> {
  next_index: usize,
- phantom: PhantomData<T>,   
-} // json line
+ format: Arc<RwLock<Format>>,
+ max_frame_size: usize,
+ phantom: PhantomData<T>,
+}
 
 impl<T> MessagesCodec<T>{
     pub fn new() -> Self {
+        MessagesCodec::with_max_frame_size(MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
         MessagesCodec {
             next_index: 0,
+            format: Arc::new(RwLock::new(Format::Json)),
+            max_frame_size,
             phantom: PhantomData
         }
     }
+
+    /// The format messages are encoded in, shared with the codec to switch it after the codec
+    /// was moved into a `Framed`
+    pub fn format(&self) -> Arc<RwLock<Format>> {
+        self.format.clone()
+    }
+
+    fn too_large(&self, size: usize) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("Frame of {} bytes exceeds the maximum of {} bytes", size, self.max_frame_size))
+    }
 }
 
-impl<T> Decoder for MessagesCodec<T> 
+impl<T> Decoder for MessagesCodec<T>
 where T: DeserializeOwned + Transactional
 {
     type Item = Messages<T>;
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if let Some(i) = buf.iter().position(|&b| b == b'\n') {
+        if buf.first() == Some(&BINARY_FRAME) {
+            if buf.len() < BINARY_HEADER {
+                return Ok(None);
+            }
+            let mut length = [0; 4];
+            length.copy_from_slice(&buf[1..BINARY_HEADER]);
+            let length = u32::from_be_bytes(length) as usize;
+            if length > self.max_frame_size {
+                return Err(self.too_large(length));
+            }
+            if buf.len() < BINARY_HEADER + length {
+                buf.reserve(BINARY_HEADER + length - buf.len());
+                return Ok(None);
+            }
+            // remove the serialized frame from the buffer.
+            let frame = buf.split_to(BINARY_HEADER + length);
+            return bincode::deserialize(&frame[BINARY_HEADER..])
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        if let Some(i) = buf[self.next_index..].iter().position(|&b| b == b'\n') {
             let newline_index = self.next_index + i;
+            self.next_index = 0;
             // remove the serialized frame from the buffer.
             let line = buf.split_to(newline_index + 1);
 
             let line = &line[..line.len() - 1];
-            
+
 
             // Turn this data into a UTF string
             let s = str::from_utf8(&line)
@@ -47,14 +110,18 @@ where T: DeserializeOwned + Transactional
             serde_json::from_str(&s)
                 .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
 
+        } else if buf.len() > self.max_frame_size {
+            Err(self.too_large(buf.len()))
         } else {
+            // continue the search for the newline after the bytes received so far
+            self.next_index = buf.len();
             Ok(None)
         }
     }
 
 }
 
-impl<T> Encoder for MessagesCodec<T> 
+impl<T> Encoder for MessagesCodec<T>
 where T: Transactional + Serialize
 {
     type Item = Messages<T>;
@@ -62,12 +129,92 @@ where T: Transactional + Serialize
 
     fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()>
     {
-        let json_msg = serde_json::to_string(&msg)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        buf.extend(json_msg.as_bytes());
-        buf.put_u8(b'\n');
+        let format = *self.format.read().unwrap();
+        match format {
+            Format::Json => {
+                let json_msg = serde_json::to_string(&msg)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                if json_msg.len() + 1 > self.max_frame_size {
+                    return Err(self.too_large(json_msg.len() + 1));
+                }
+                buf.reserve(json_msg.len() + 1);
+                buf.extend(json_msg.as_bytes());
+                buf.put_u8(b'\n');
+            }
+            Format::Binary => {
+                let binary_msg = bincode::serialize(&msg)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                if binary_msg.len() > self.max_frame_size {
+                    return Err(self.too_large(binary_msg.len()));
+                }
+                buf.reserve(BINARY_HEADER + binary_msg.len());
+                buf.put_u8(BINARY_FRAME);
+                buf.put_u32_be(binary_msg.len() as u32);
+                buf.extend(binary_msg);
+            }
+        }
 
         Ok(())
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio::codec::{Decoder, Encoder};
+
+    use crate::blockchain::transaction::{CryptoPayload, Transactional};
+    use crate::node::codec::{Format, MessagesCodec};
+    use crate::node::messages::Messages;
+
+    fn messages() -> Vec<Messages<CryptoPayload>> {
+        vec![
+            Messages::PeerList(vec![]),
+            Messages::Transaction(CryptoPayload::new(String::from("Schwurbel"), CryptoPayload {
+                receiver: String::from("Peter\nPan"),
+                amount: 42,
+            })),
+        ]
+    }
+
+    fn encode(codec: &mut MessagesCodec<CryptoPayload>, format: Format, buf: &mut BytesMut) {
+        *codec.format().write().unwrap() = format;
+        for msg in messages() {
+            codec.encode(msg, buf).unwrap();
+        }
+    }
+
+    #[test]
+    fn decode_both_formats() {
+        let mut codec = MessagesCodec::new();
+        let mut buf = BytesMut::new();
+        encode(&mut codec, Format::Json, &mut buf);
+        encode(&mut codec, Format::Binary, &mut buf);
+
+        // deliver the frames byte by byte
+        let mut received = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in buf.iter() {
+            received.extend_from_slice(&[*byte]);
+            while let Some(msg) = codec.decode(&mut received).unwrap() {
+                decoded.push(format!("{:?}", msg));
+            }
+        }
+        let expected: Vec<String> = messages().iter().chain(messages().iter()).map(|msg| format!("{:?}", msg)).collect();
+        assert_eq!(decoded, expected);
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn reject_large_frames() {
+        let mut codec = MessagesCodec::<CryptoPayload>::with_max_frame_size(32);
+        let mut buf = BytesMut::new();
+        assert!(codec.encode(messages().pop().unwrap(), &mut buf).is_err());
+
+        let mut buf = BytesMut::from(vec![0, 0, 0, 1, 0]);
+        assert!(codec.decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(vec![b'{'; 33]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+
+/// The most items announced or requested in a single `Inv` or `GetData` message
+pub const MAX_INVENTORY: usize = 1000;
+/// The number of items a node remembers to have seen
+pub const SEEN_CAPACITY: usize = 50_000;
+/// Items requested but not received for this long are requested from the next peer announcing them
+pub const GET_DATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An item announced to peers by its hash
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Inventory {
+    /// A block by `Block::hash`
+    Block(String),
+    /// A transaction by `Transaction::id`
+    Transaction(String),
+}
+
+/// Remembers the most recently announced or received items and the items requested from peers.
+///
+/// Every item is requested from one peer at a time and relayed only the first time it is seen,
+/// so announcements aren't flooded back and forth between peers. Requests that aren't answered
+/// within `GET_DATA_TIMEOUT` are sent to the next peer announcing the item. The oldest items are
+/// forgotten first.
+#[derive(Debug)]
+pub struct SeenCache {
+    items: HashSet<Inventory>,
+    order: VecDeque<Inventory>,
+    requested: HashMap<Inventory, Instant>,
+    /// The requests in the order they were sent, so expired ones are found at the front
+    requests: VecDeque<(Inventory, Instant)>,
+    capacity: usize,
+}
+
+impl Default for SeenCache {
+    fn default() -> Self {
+        SeenCache::with_capacity(SEEN_CAPACITY)
+    }
+}
+
+impl SeenCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        SeenCache {
+            items: HashSet::new(),
+            order: VecDeque::new(),
+            requested: HashMap::new(),
+            requests: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Whether the item was seen.
+    pub fn contains(&self, item: &Inventory) -> bool {
+        self.items.contains(item)
+    }
+
+    /// Marks the item as seen, forgetting the oldest item if the cache is full.
+    /// Returns whether the item is seen for the first time.
+    pub fn insert(&mut self, item: Inventory) -> bool {
+        self.requested.remove(&item);
+        if !self.items.insert(item.clone()) {
+            return false;
+        }
+        self.order.push_back(item);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Marks the item as requested unless it was seen or a request for it is still pending.
+    /// Returns whether to request the item.
+    pub fn request(&mut self, item: Inventory, now: Instant) -> bool {
+        self.expire(now);
+        if self.items.contains(&item) || self.requested.contains_key(&item) || self.requested.len() >= self.capacity {
+            return false;
+        }
+        self.requested.insert(item.clone(), now);
+        self.requests.push_back((item, now));
+        self.expire(now);
+        true
+    }
+
+    /// Forgets the requests older than `GET_DATA_TIMEOUT`, and the oldest ones beyond the
+    /// capacity, which includes requests answered in the meantime.
+    fn expire(&mut self, now: Instant) {
+        while let Some((item, at)) = self.requests.front() {
+            if now.duration_since(*at) < GET_DATA_TIMEOUT && self.requests.len() <= self.capacity {
+                break;
+            }
+            // a later request for the same item is still pending
+            if self.requested.get(item) == Some(at) {
+                self.requested.remove(item);
+            }
+            self.requests.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::node::inventory::{Inventory, SeenCache, GET_DATA_TIMEOUT};
+
+    #[test]
+    fn forget_oldest() {
+        let mut seen = SeenCache::with_capacity(2);
+        assert!(seen.insert(Inventory::Block(String::from("a"))));
+        assert!(!seen.insert(Inventory::Block(String::from("a"))));
+        assert!(seen.insert(Inventory::Transaction(String::from("a"))));
+        assert!(seen.insert(Inventory::Block(String::from("b"))));
+
+        assert!(!seen.contains(&Inventory::Block(String::from("a"))));
+        assert!(seen.contains(&Inventory::Transaction(String::from("a"))));
+        assert!(seen.insert(Inventory::Block(String::from("a"))));
+    }
+
+    #[test]
+    fn request_until_received() {
+        let mut seen = SeenCache::default();
+        let item = Inventory::Transaction(String::from("a"));
+        let now = Instant::now();
+        assert!(seen.request(item.clone(), now));
+        assert!(!seen.request(item.clone(), now));
+        assert!(!seen.contains(&item));
+
+        // the peer didn't answer, the next one announcing the item is asked
+        assert!(seen.request(item.clone(), now + GET_DATA_TIMEOUT));
+        assert!(seen.insert(item.clone()));
+        assert!(!seen.request(item, now + 2 * GET_DATA_TIMEOUT));
+    }
+
+    #[test]
+    fn bound_requests() {
+        let mut seen = SeenCache::with_capacity(2);
+        let now = Instant::now();
+        for name in &["a", "b"] {
+            assert!(seen.request(Inventory::Block(name.to_string()), now));
+            assert!(seen.insert(Inventory::Block(name.to_string())));
+        }
+        // answered requests are forgotten as well
+        assert!(seen.request(Inventory::Block(String::from("c")), now));
+        assert_eq!(seen.requests.len(), 2);
+        assert!(seen.request(Inventory::Block(String::from("d")), now));
+        assert!(!seen.request(Inventory::Block(String::from("e")), now));
+        assert!(seen.request(Inventory::Block(String::from("e")), now + GET_DATA_TIMEOUT));
+    }
+}
@@ -1,25 +1,161 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 // use sequoia_openpgp as openpgp;
+use failure::Fail;
 use uuid::Uuid;
-use serde::{Serialize, Deserialize}; 
+use serde::{Serialize, Deserialize};
 
-use crate::blockchain::{chain::Chain, transaction::{Transaction}}; 
+use crate::blockchain::{block::{Block, BlockHeader}, chain::Chain, transaction::{Transaction, Transactional}};
+use super::codec::Format;
+use super::inventory::Inventory;
+
+/// The version of the protocol, peers only connect if theirs matches
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Feature of nodes that decode `Format::Binary` frames
+pub const FEATURE_BINARY: &str = "binary";
 
 /// Define messages in terms of being a request, response or a broadcast
 /// FIXME: Error: openpgp::Tpk is not send so also not sync so it cant be used with futures...
 ///         How to change the design? remap everything with tokio to sequential?
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub enum Messages<T> {
-    // Request: Ping a node to register to it as new peer. SYNC
-    Ping((Uuid, SocketAddr)), // openpgp::TPK)),
-     // Response: Respond to a ping by sending the own PK, IP and version of the chain. ACK
-    Pong((Uuid, SocketAddr, Chain<T>)), // openpgp::TPK, 
+#[serde(bound = "T: Transactional")]
+pub enum Messages<T>
+where T: Transactional
+{
+    // Request: Introduce the node to a new peer, the first message on every connection. SYNC
+    Handshake(Handshake), // openpgp::TPK)),
+    // Broadcast: Announce blocks and transactions the node has seen for the first time.
+    Inv(Vec<Inventory>),
+    // Request: Ask for the announced items the node lacks.
+    GetData(Vec<Inventory>),
+    // Request: Ask for the headers following the first hash of the locator on the peer's active
+    // chain, sent to peers with a longer chain.
+    GetHeaders(Vec<String>),
+    // Response: At most `sync::MAX_HEADERS` headers following the locator.
+    Headers(Vec<BlockHeader>),
+    // Request: Ask for the blocks with the given hashes.
+    GetBlocks(Vec<String>),
+    // Response: The requested blocks the peer knows, with the public keys of their senders.
+    Blocks((Vec<Block<T>>, HashMap<String, Vec<u8>>)),
     // Broadcast: Gossip the PK and IP of others to find conflicts and connect
     // the network.
     PeerList(Vec<(Uuid, SocketAddr)>),
-    // Broadcast: broadcast a transaction
+    // Request: Submit a transaction, which is announced to the peers once accepted
     Transaction(Transaction<T>),
+    // Response: The requested transactions the peer knows, with the public keys of their senders.
+    Transactions((Vec<Transaction<T>>, HashMap<String, Vec<u8>>)),
     // broadcast the latest signed transaction. A Signed Transaction should be signed by both
     // parties
     //CompleteTransaction((Uuid, Uuid, Transaction<T>)),
 }
+
+/// Introduces a node to a peer, which disconnects unless both speak the same protocol on the
+/// same chain.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Handshake {
+    pub version: u32,
+    pub id: Uuid,
+    pub addr: SocketAddr,
+    /// The network id of the node's chain, `None` if it has no chain yet
+    pub network: Option<String>,
+    /// The name of the payload type, `Transactional::NAME`
+    pub payload: String,
+    /// The height of the node's active chain
+    pub height: usize,
+    /// The optional protocol features the node supports, e.g. `FEATURE_BINARY`
+    pub features: Vec<String>,
+    /// The format the node asks the peer to send its messages in
+    pub format: Format,
+}
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum HandshakeError {
+    #[fail(display = "Peer speaks protocol version {}, expected {}", _0, _1)]
+    Version(u32, u32),
+    #[fail(display = "Peer is on network {}, expected {}", _0, _1)]
+    Network(String, String),
+    #[fail(display = "Peer uses payload {}, expected {}", _0, _1)]
+    Payload(String, String),
+    #[fail(display = "Peer asks for {:?} messages without supporting them", _0)]
+    Format(Format),
+}
+
+impl Handshake {
+    /// Creates the handshake of a node, with the chain if it has one.
+    pub fn new<T: Transactional>(id: Uuid, addr: SocketAddr, chain: Option<&Chain<T>>, format: Format) -> Handshake {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            id,
+            addr,
+            network: chain.map(|chain| chain.network_id()),
+            payload: String::from(T::NAME),
+            height: chain.map_or(0, |chain| chain.headers().len()),
+            features: vec![String::from(FEATURE_BINARY)],
+            format,
+        }
+    }
+
+    /// Whether the node supports the feature.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+
+    /// Checks whether the peer's handshake is compatible with the own one.
+    ///
+    /// A node without a chain joins the network of its peers.
+    pub fn check(&self, peer: &Handshake) -> Result<(), HandshakeError> {
+        if peer.version != self.version {
+            return Err(HandshakeError::Version(peer.version, self.version));
+        }
+        if let (Some(own), Some(other)) = (self.network.as_ref(), peer.network.as_ref()) {
+            if own != other {
+                return Err(HandshakeError::Network(other.clone(), own.clone()));
+            }
+        }
+        if peer.payload != self.payload {
+            return Err(HandshakeError::Payload(peer.payload.clone(), self.payload.clone()));
+        }
+        if peer.format == Format::Binary && !peer.supports(FEATURE_BINARY) {
+            return Err(HandshakeError::Format(peer.format));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::chain::Chain;
+    use crate::blockchain::pow::MAX_BITS;
+    use crate::blockchain::transaction::{CryptoPayload, VotePayload};
+    use crate::node::codec::Format;
+    use crate::node::messages::{Handshake, HandshakeError, PROTOCOL_VERSION};
+    use uuid::Uuid;
+
+    #[test]
+    fn check_handshake() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let chain = Chain::<CryptoPayload>::new(String::from("Schwurbel"), MAX_BITS);
+        let own = Handshake::new(Uuid::new_v4(), addr, Some(&chain), Format::Binary);
+        assert_eq!(own.height, 1);
+
+        let fresh = Handshake::new::<CryptoPayload>(Uuid::new_v4(), addr, None, Format::Json);
+        assert_eq!(own.check(&fresh), Ok(()));
+        assert_eq!(fresh.check(&own), Ok(()));
+
+        let other = Chain::<CryptoPayload>::new(String::from("Peter"), MAX_BITS);
+        let peer = Handshake::new(Uuid::new_v4(), addr, Some(&other), Format::Binary);
+        assert_eq!(own.check(&peer), Err(HandshakeError::Network(other.network_id(), chain.network_id())));
+
+        let peer = Handshake::new::<VotePayload>(Uuid::new_v4(), addr, None, Format::Binary);
+        assert_eq!(own.check(&peer), Err(HandshakeError::Payload(String::from("vote"), String::from("crypto"))));
+
+        let mut peer = fresh.clone();
+        peer.version += 1;
+        assert_eq!(own.check(&peer), Err(HandshakeError::Version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION)));
+
+        let mut peer = fresh;
+        peer.format = Format::Binary;
+        peer.features.clear();
+        assert_eq!(own.check(&peer), Err(HandshakeError::Format(Format::Binary)));
+    }
+}
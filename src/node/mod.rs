@@ -1,3 +1,5 @@
-mod codec;
+pub mod codec;
+pub mod inventory;
 pub mod messages;
 mod node;
+pub mod sync;
@@ -1,7 +1,10 @@
-use std::collections::{VecDeque, HashMap};
+use std::collections::HashMap;
+use std::iter;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::slice;
 use std::time::{Duration, Instant};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 use futures::{Future, Stream, Sink};
 use futures::sync::mpsc;
@@ -9,39 +12,102 @@ use tokio::io;
 use tokio::codec;
 use tokio::net::{TcpStream, TcpListener};
 use tokio::timer::Interval;
+use failure;
 use uuid::Uuid;
 //use sequoia_openpgp as openpgp;
+use crate::blockchain::block::{Block, BlockHeader};
 use crate::blockchain::chain::Chain;
+use crate::blockchain::miner::{Miner, MiningJob};
+use crate::blockchain::spec::{ChainSpec, SpecError};
 use crate::blockchain::transaction::{Transaction, Transactional};
+use crate::storage::chain::ChainStore;
+use crate::storage::{schema, Namespace, Namespaces};
 
-use super::messages::Messages;
-use super::codec::MessagesCodec;
+use super::messages::{Handshake, Messages};
+use super::codec::{Format, MessagesCodec, MAX_FRAME_SIZE};
+use super::inventory::{Inventory, SeenCache, MAX_INVENTORY};
+use super::sync::{HeaderSync, MAX_BLOCKS, MAX_HEADERS};
 
 type Tx<T> = mpsc::UnboundedSender<Messages<T>>;
 type Rx<T> = mpsc::UnboundedReceiver<Messages<T>>;
+type Peers<T> = HashMap<Uuid, (Tx<T>, SocketAddr)>;
+type Mining<T> = Option<(Block<T>, MiningJob)>;
+
+/// The most bytes of blocks or transactions and the keys of their senders sent in a single
+/// message, leaving room for the encoding, twice `MAX_BLOCK_SIZE`
+const MAX_MESSAGE_SIZE: usize = MAX_FRAME_SIZE / 2;
 
 #[derive(Clone, Debug)]
-pub struct Node<T> {
+pub struct Node<T>
+where T: Transactional
+{
     inner: Arc<RwLock<NodeInner<T>>>,
 }
 
 #[derive(Clone, Debug)]
-pub struct NodeInner<T> {
+pub struct NodeInner<T>
+where T: Transactional
+{
    pub id: Uuid,
    //keys: openpgp::TPK,
    pub addr: SocketAddr,
-   pub peers: HashMap<Uuid, (Tx<T>, SocketAddr)>,
-   chain: Option<(u32, Chain<T>)>,
-   alt_chains: VecDeque<(u32, Chain<T>)>,
+   // The connected peers by id, shared by all clones
+   pub peers: Arc<Mutex<Peers<T>>>,
+   // The chain, shared by all clones handling the messages of the peers
+   chain: Arc<Mutex<Option<Chain<T>>>>,
+   // The parameters of the network the node takes part in
+   spec: ChainSpec,
+   miner: Miner,
+   // The block template currently mined in the background, shared by all clones
+   mining: Arc<Mutex<Mining<T>>>,
+   // The format peers are asked to send their messages in
+   format: Format,
+   // The download of longer chains of peers, shared by all clones
+   sync: Arc<Mutex<HeaderSync<T>>>,
+   // The blocks and transactions announced or received so far, shared by all clones
+   seen: Arc<Mutex<SeenCache>>,
+}
+
+/// The state of a connection to a peer
+struct Connection {
+    // The format messages are sent in, shared with the codec
+    format: Arc<RwLock<Format>>,
+    // The id of the peer, known once its handshake was accepted
+    peer: Option<Uuid>,
 }
 
 impl<T> Node<T> 
 where T: Transactional + Send + Sync + 'static 
 {
-    fn new(addr: &SocketAddr) -> Node<T> {
-        Node {
-            inner: Arc::new(RwLock::new(NodeInner::<T>::new(*addr))),
-        }
+    fn new(addr: &SocketAddr, spec: ChainSpec) -> Result<Node<T>, SpecError> {
+        Ok(Node {
+            inner: Arc::new(RwLock::new(NodeInner::<T>::new(*addr, spec)?)),
+        })
+    }
+
+    /// Creates a node that persists its chain in the store and resumes the chain stored there.
+    pub fn with_store(addr: &SocketAddr, spec: ChainSpec, store: ChainStore<Namespace>) -> Result<Node<T>, failure::Error> {
+        Ok(Node {
+            inner: Arc::new(RwLock::new(NodeInner::<T>::with_store(*addr, spec, store)?)),
+        })
+    }
+
+    /// Creates a node that persists its chain in the database at the path.
+    /// Migrates the database to the current schema version first.
+    pub fn open<P: AsRef<Path>>(addr: &SocketAddr, spec: ChainSpec, path: P) -> Result<Node<T>, failure::Error> {
+        let mut namespaces = Namespaces::open(path)?;
+        schema::migrate(&mut namespaces)?;
+        Node::with_store(addr, spec, ChainStore::open(&mut namespaces)?)
+    }
+
+    /// Sets the format peers are asked to send their messages in, e.g. JSON for debugging.
+    pub fn update_format(&self, format: Format) {
+        self.inner.write().unwrap().format = format;
+    }
+
+    /// Sets the number of worker threads the node mines its blocks with.
+    pub fn update_miner_threads(&self, threads: usize) {
+        self.inner.write().unwrap().miner = Miner::new(threads);
     }
 
     pub fn run<I: 'static + Iterator<Item=SocketAddr>>(&self, addrs: I) -> Result<(), io::Error> {
@@ -58,17 +124,35 @@ impl<T> NodeInner<T>
 where T: Transactional + 'static + Send + Sync,
       Self: 'static 
 {
-    pub fn new(addr: SocketAddr) -> NodeInner<T> {
+    /// Creates a node starting with the genesis block of the spec, fails if the spec is invalid.
+    pub fn new(addr: SocketAddr, spec: ChainSpec) -> Result<NodeInner<T>, SpecError> {
         let id = Uuid::new_v4();
 //        let (_keys, _) = keys::generate(id).expect("Failed to generate keys!");
-        NodeInner {
+        let chain = Chain::from_spec(id.to_string(), spec.clone())?;
+        Ok(NodeInner {
             id,
             //keys,
             addr,
-            peers: HashMap::new(),
-            chain: None,
-            alt_chains: VecDeque::new(),
-        }
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            chain: Arc::new(Mutex::new(Some(chain))),
+            spec,
+            miner: Miner::default(),
+            mining: Arc::new(Mutex::new(None)),
+            format: Format::Binary,
+            sync: Arc::new(Mutex::new(HeaderSync::default())),
+            seen: Arc::new(Mutex::new(SeenCache::default())),
+        })
+    }
+
+    /// Creates a node that persists its chain in the store and resumes the chain stored there.
+    /// The stored blocks have to start with the genesis block of the spec, and the node keeps
+    /// mining to the address stored with them.
+    pub fn with_store(addr: SocketAddr, spec: ChainSpec, store: ChainStore<Namespace>) -> Result<NodeInner<T>, failure::Error> {
+        let mut inner = NodeInner::new(addr, spec)?;
+        let chain = Chain::open(inner.id.to_string(), inner.spec.clone(), store)?;
+        println!("Loaded {} blocks mining to {}", chain.headers().len(), chain.miner_address());
+        inner.chain = Arc::new(Mutex::new(Some(chain)));
+        Ok(inner)
     }
 
     fn start_client(&self, addr: &SocketAddr) -> impl Future<Item=(), Error=io::Error> {
@@ -77,7 +161,12 @@ where T: Transactional + 'static + Send + Sync,
         // Define the client
          let client = TcpStream::connect(&addr).and_then(move |socket| {
             println!("connected! local: {:?}, peer: {:?}", socket.local_addr(), socket.peer_addr());
-            let framed_socket = codec::Framed::new(socket, MessagesCodec::<T>::new());
+            let codec = MessagesCodec::<T>::new();
+            let mut connection = Connection {
+                format: codec.format(),
+                peer: None,
+            };
+            let framed_socket = codec::Framed::new(socket, codec);
 
             let (sink, stream) = framed_socket.split();
             let (tx, rx): (Tx<T>, Rx<T>) = mpsc::unbounded();
@@ -86,7 +175,7 @@ where T: Transactional + 'static + Send + Sync,
             let inner1 = inner.clone();
             // process messages from other clients
             let read = stream.for_each(move |msg| {
-                    inner1.clone().process(msg, &tx1.clone())
+                    inner1.clone().process(msg, &tx1.clone(), &mut connection)
             })
             .then(|e| {
                 println!("{:?}", e);
@@ -96,10 +185,10 @@ where T: Transactional + 'static + Send + Sync,
             
             let tx2 = tx.clone();
             let inner2 = inner.clone();
-            // Send Ping to bootstrap
+            // Send the handshake to bootstrap
             mpsc::UnboundedSender::unbounded_send(&tx2.clone(),
-                                                  Messages::<T>::Ping((inner2.id, inner2.addr.clone())))
-               .expect("Ping failed");
+                                                  Messages::<T>::Handshake(inner2.handshake()))
+               .expect("Handshake failed");
 
             tokio::spawn(sink.send_all(
                     rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "Error, {}", )))
@@ -123,22 +212,18 @@ where T: Transactional + 'static + Send + Sync,
             }));
         }
         
-        let inner1 = inner.clone();
-        let cache_reset = Interval::new(Instant::now(), Duration::from_secs(30*60)).for_each(move |_| {
-            inner1.clone().alt_chains.retain(|(count, _)| count > &50);
-            Ok(())
-            }).map_err(|e| panic!("interval errored, {:?}", e));
-        // Delete the list of alternative chains all 30 min
-        tokio::spawn(
-           cache_reset
-        );
-
        // start gossiping the peer lists to others
        tokio::spawn(self.gossip(Duration::from_secs(3)).then(|_| {
            println!("gossiped");
            Ok(())
        }));
 
+       // collect blocks mined in the background
+       tokio::spawn(self.collect_mined(Duration::from_secs(1)).then(|_| {
+           println!("stopped collecting mined blocks");
+           Ok(())
+       }));
+
         println!("Starting server");
 
         // Listen for incoming connections, accept all and start a client for each.
@@ -157,130 +242,515 @@ where T: Transactional + 'static + Send + Sync,
         srv
     }
 
-    fn process(&self, msg: Messages<T>, tx: &Tx<T>) -> Result<(), io::Error> {
-        let mut inner = self.clone();
+    fn process(&self, msg: Messages<T>, tx: &Tx<T>, connection: &mut Connection) -> Result<(), io::Error> {
         match msg {
-            Messages::<T>::Ping(m) => inner.handle_ping(m, tx),
-            Messages::<T>::Pong(m) => inner.handle_pong(m, tx),
-            Messages::<T>::PeerList(m) => inner.handle_gossip(m),
-            Messages::<T>::Transaction(m) => inner.integrate_transaction(m),
+            Messages::<T>::Handshake(m) => self.handle_handshake(m, tx, connection),
+            // disconnect peers that skip the handshake
+            _ if connection.peer.is_none() =>
+                Err(io::Error::new(io::ErrorKind::InvalidData, "Received a message before the handshake")),
+            Messages::<T>::Inv(m) => self.handle_inv(m, tx),
+            Messages::<T>::GetData(m) => self.handle_get_data(m, tx),
+            Messages::<T>::GetHeaders(m) => self.handle_get_headers(m, tx),
+            Messages::<T>::Headers(m) => self.handle_headers(m, tx),
+            Messages::<T>::GetBlocks(m) => self.handle_get_blocks(m, tx),
+            Messages::<T>::Blocks(m) => self.handle_blocks(m, tx, connection.peer),
+            Messages::<T>::PeerList(m) => self.handle_gossip(m),
+            Messages::<T>::Transaction(m) => self.handle_transactions((vec![m], HashMap::new()), connection.peer),
+            Messages::<T>::Transactions(m) => self.handle_transactions(m, connection.peer),
         }
     }
 
     fn gossip(&self, duration: Duration) -> impl Future<Item=(), Error=io::Error> + 'static {
         let inner = self.clone();
         Interval::new(Instant::now(), duration).for_each(move |_| {
-            let m: Vec<(Uuid, SocketAddr)> = inner.peers.iter()
-                .map(|(k, v)| (k.clone(), v.1.clone()))
-                .collect();
-            let mut m1 = m.clone();
-        for (tx, _) in inner.peers.values() {
-            tx.unbounded_send(Messages::<T>::PeerList(m1)).expect("Shit hit the fan");
-             m1 = m.clone();
-        }
+            {
+                let peers = inner.peers.lock().unwrap();
+                let m: Vec<(Uuid, SocketAddr)> = peers.iter()
+                    .map(|(k, v)| (k.clone(), v.1.clone()))
+                    .collect();
+                for (tx, _) in peers.values() {
+                    tx.unbounded_send(Messages::<T>::PeerList(m.clone())).expect("Shit hit the fan");
+                }
+            }
+            // ask other peers for blocks whose requests timed out
+            inner.request_blocks();
+            if let Some(chain) = inner.chain.lock().unwrap().as_mut() {
+                chain.expire_transactions(Instant::now());
+            }
             Ok(())
         })
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
     
     }
 
+    /// Starts mining a block in the background if enough transactions are waiting and no block
+    /// is mined yet.
+    fn start_mining(&self) {
+        let chain = self.chain.lock().unwrap();
+        let mut mining = self.mining.lock().unwrap();
+        if let (None, Some(chain)) = (mining.as_ref(), chain.as_ref()) {
+            if chain.is_block_due() {
+                let block = chain.block_template();
+                let job = self.miner.start(block.header.clone());
+                *mining = Some((block, job));
+            }
+        }
+    }
+
+    /// Stops mining the current block template, e.g. because the tip changed.
+    fn cancel_mining(&self) {
+        if let Some((_, job)) = self.mining.lock().unwrap().take() {
+            job.cancel();
+        }
+    }
+
+    /// Periodically checks whether the background miner found a block, adds it to the chain and
+    /// announces it.
+    fn collect_mined(&self, duration: Duration) -> impl Future<Item=(), Error=io::Error> + 'static {
+        let inner = self.clone();
+        Interval::new(Instant::now(), duration).for_each(move |_| {
+            let mined = {
+                let mut mining = inner.mining.lock().unwrap();
+                match mining.as_ref().and_then(|(_, job)| job.result()) {
+                    Some(header) => mining.take().map(|(mut block, job)| {
+                        println!("Mined block at {:.0} H/s", job.hash_rate());
+                        block.header = header;
+                        block
+                    }),
+                    None => None,
+                }
+            };
 
+            if let Some(block) = mined {
+                let hash = block.hash();
+                // the chain may have moved on while the block was mined
+                let added = match inner.chain.lock().unwrap().as_mut() {
+                    Some(chain) => chain.add_block(block),
+                    None => Ok(false),
+                };
+                if let Ok(true) = added {
+                    inner.announce(vec![Inventory::Block(hash)], None);
+                }
+                inner.start_mining();
+            }
+            Ok(())
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
 
-    fn handle_ping(&mut self, m: (Uuid, SocketAddr), tx: &Tx<T>) -> Result<(), io::Error> {
-        let mut inner = self.clone();
-        println!("Received ping from {:?}", m);
 
-        match self.peers.get(&m.0) {
-            None => {
-                let tx1 = tx.clone();
-                inner.peers.insert(m.0, (tx1, m.1));
-                let tx2 = tx.clone();
-                let _ = tx2.unbounded_send( Messages::<T>::Pong((inner.id, inner.addr, inner.chain.unwrap().1)))
-                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "tx failed"));
-                Ok(())
-            },
-            _ => Ok(()),
+
+    /// The handshake introducing the node to its peers.
+    fn handshake(&self) -> Handshake {
+        Handshake::new(self.id, self.addr, self.chain.lock().unwrap().as_ref(), self.format)
+    }
+
+    /// The locator of the own chain and the headers downloaded so far.
+    fn locator(&self) -> Vec<String> {
+        let chain = self.chain.lock().unwrap();
+        self.sync.lock().unwrap().locator(chain.as_ref())
+    }
+
+    fn handle_handshake(&self, m: Handshake, tx: &Tx<T>, connection: &mut Connection) -> Result<(), io::Error> {
+        println!("Received handshake from {:?}", m);
+
+        if let Err(e) = self.handshake().check(&m) {
+            println!("Disconnecting from {}: {}", m.addr, e);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
         }
+        connection.peer = Some(m.id);
+        // send all further messages in the format the peer asked for
+        *connection.format.write().unwrap() = m.format;
+
+        {
+            let mut peers = self.peers.lock().unwrap();
+            if peers.contains_key(&m.id) {
+                return Ok(());
+            }
+            peers.insert(m.id, (tx.clone(), m.addr));
+        }
+        // download the blocks the peer has beyond the own chain
+        let height = self.chain.lock().unwrap().as_ref().map_or(0, |chain| chain.headers().len());
+        if m.height > height {
+            let _ = tx.unbounded_send(Messages::<T>::GetHeaders(self.locator()))
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "tx failed"));
+        }
+        Ok(())
     }
 
-    fn handle_pong(&mut self, m: (Uuid, SocketAddr, Chain<T>), tx: &Tx<T>) -> Result<(), io::Error> {
-        println!("received pong {:?}", m);
+    fn handle_get_headers(&self, m: Vec<String>, tx: &Tx<T>) -> Result<(), io::Error> {
+        if let Some(chain) = self.chain.lock().unwrap().as_ref() {
+            let _ = tx.unbounded_send(Messages::<T>::Headers(chain.headers_after(&m, MAX_HEADERS)))
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "tx failed"));
+        }
+        Ok(())
+    }
 
-        let chain1 = self.chain.clone();
-        match chain1 {
-            Some((count, self_chain)) => {
-                //chains match, all good and break, else one needs majority voting
-                //consensus
-                match self_chain.eq(&m.2) {
-                    true => self.chain = Some((count + 1, self_chain)),
-                    false => self.majority_consensus(m.2),
+    fn handle_headers(&self, m: Vec<BlockHeader>, tx: &Tx<T>) -> Result<(), io::Error> {
+        if m.len() > MAX_HEADERS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Received too many headers"));
+        }
+        let more = {
+            let chain = self.chain.lock().unwrap();
+            let mut sync = self.sync.lock().unwrap();
+            match sync.add_headers(chain.as_ref(), m) {
+                Ok(more) => more,
+                Err(rule) => {
+                    println!("Disconnecting from peer sending invalid headers: {}", rule);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, rule.to_string()));
                 }
             }
-            None => self.chain = Some((1, m.2)),
+        };
+        if more {
+            let _ = tx.unbounded_send(Messages::<T>::GetHeaders(self.locator()))
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "tx failed"));
+        }
+        self.request_blocks();
+        Ok(())
+    }
+
+    fn handle_get_blocks(&self, m: Vec<String>, tx: &Tx<T>) -> Result<(), io::Error> {
+        if m.len() > MAX_BLOCKS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Received too many block requests"));
+        }
+        if let Some(chain) = self.chain.lock().unwrap().as_ref() {
+            let blocks: Vec<Block<T>> = m.iter()
+                .filter_map(|hash| chain.block(hash))
+                .collect();
+            send_blocks(chain, blocks, tx);
         }
+        Ok(())
+    }
 
-        match self.peers.get(&m.0) {
-            None => {
-                self.peers.insert(m.0, (tx.clone(), m.1));
-                Ok(())
-            },
-            _ => Ok(())
+    fn handle_inv(&self, m: Vec<Inventory>, tx: &Tx<T>) -> Result<(), io::Error> {
+        if m.len() > MAX_INVENTORY {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Received too many inventory items"));
+        }
+        // request every item from one peer at a time, the first one announcing it
+        let now = Instant::now();
+        let wanted: Vec<Inventory> = {
+            let chain = self.chain.lock().unwrap();
+            let mut seen = self.seen.lock().unwrap();
+            m.into_iter()
+                .filter(|item| match item {
+                    Inventory::Block(hash) => !chain.as_ref().map_or(false, |chain| chain.contains(hash)),
+                    Inventory::Transaction(_) => true,
+                })
+                .filter(|item| seen.request(item.clone(), now))
+                .collect()
+        };
+        if !wanted.is_empty() {
+            let _ = tx.unbounded_send(Messages::<T>::GetData(wanted))
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "tx failed"));
+        }
+        Ok(())
+    }
 
+    fn handle_get_data(&self, m: Vec<Inventory>, tx: &Tx<T>) -> Result<(), io::Error> {
+        if m.len() > MAX_INVENTORY {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Received too many inventory items"));
+        }
+        let chain = self.chain.lock().unwrap();
+        let chain = match chain.as_ref() {
+            Some(chain) => chain,
+            None => return Ok(()),
+        };
+        let mut blocks = Vec::new();
+        let mut transactions = Vec::new();
+        for item in m {
+            match item {
+                Inventory::Block(hash) => blocks.extend(chain.block(&hash)),
+                Inventory::Transaction(id) => transactions.extend(chain.transaction(&id).cloned()),
+            }
+        }
+        send_blocks(chain, blocks, tx);
+        let size = |transaction: &Transaction<T>| transaction.size() + keys_size(&chain.keys_of(iter::once(transaction)));
+        for batch in batches(transactions, size) {
+            let keys = chain.keys_of(&batch);
+            let _ = tx.unbounded_send(Messages::<T>::Transactions((batch, keys)));
+        }
+        Ok(())
+    }
+
+    fn handle_blocks(&self, m: (Vec<Block<T>>, HashMap<String, Vec<u8>>), tx: &Tx<T>, source: Option<Uuid>)
+        -> Result<(), io::Error> {
+        let (blocks, keys) = m;
+        self.receive(blocks.iter().map(|block| Inventory::Block(block.hash())));
+        let mut chain = self.chain.lock().unwrap();
+        let mut sync = self.sync.lock().unwrap();
+        let mut changed = false;
+        let mut relayed = Vec::new();
+        let mut orphaned = false;
+        for block in blocks {
+            let hash = block.hash();
+            if sync.is_expected(&hash) {
+                if let Some(peer) = source {
+                    if let Err(rule) = sync.add_block(block, &keys, peer, &self.spec.limits) {
+                        println!("Rejecting block {}: {}", hash, rule);
+                    }
+                }
+                continue;
+            }
+            // announced blocks are added right away and relayed to the other peers
+            match chain.as_mut() {
+                Some(chain) if chain.contains(block.header.pre_hash()) => {
+                    match chain.add_block_with_keys(block, &keys) {
+                        Ok(switched) => {
+                            changed |= switched;
+                            relayed.push(Inventory::Block(hash));
+                        }
+                        Err(rule) => println!("Rejecting block {}: {}", hash, rule),
+                    }
+                }
+                _ => orphaned = true,
+            }
+        }
+
+        while let Some((block, keys)) = sync.next_block(chain.as_ref()) {
+            let hash = block.hash();
+            match chain.as_mut() {
+                Some(chain) => {
+                    match chain.add_block_with_keys(block, &keys) {
+                        Ok(switched) => changed |= switched,
+                        Err(rule) => {
+                            println!("Rejecting block {}: {}", hash, rule);
+                            sync.reject(&hash);
+                        }
+                    }
+                }
+                None => {
+                    match Chain::from_blocks(self.id.to_string(), self.spec.clone(), vec![block]) {
+                        Ok(genesis) => {
+                            *chain = Some(genesis);
+                            changed = true;
+                        }
+                        Err(e) => {
+                            println!("Rejecting genesis block {}: {}", hash, e);
+                            sync.reject(&hash);
+                        }
+                    }
+                }
+            }
+        }
+        // the headers ignored while the queue was full are requested again
+        let resume = sync.resume();
+        drop(sync);
+        drop(chain);
+
+        // the peer announced a block on a branch the node lacks the parents of
+        if orphaned || resume {
+            let _ = tx.unbounded_send(Messages::<T>::GetHeaders(self.locator()))
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "tx failed"));
+        }
+        if changed {
+            // the block mined so far builds on the old tip
+            self.cancel_mining();
+            self.start_mining();
+        }
+        self.announce(relayed, source);
+        self.request_blocks();
+        Ok(())
+    }
+
+    /// Requests the missing blocks of the sync from the peers.
+    fn request_blocks(&self) {
+        let peers: Vec<Uuid> = self.peers.lock().unwrap().keys().cloned().collect();
+        let requests = self.sync.lock().unwrap().next_requests(&peers, Instant::now());
+        let peers = self.peers.lock().unwrap();
+        for (peer, hashes) in requests {
+            if let Some((tx, _)) = peers.get(&peer) {
+                let _ = tx.unbounded_send(Messages::<T>::GetBlocks(hashes));
+            }
         }
     }
 
     fn handle_gossip(&self, m: Vec<(Uuid, SocketAddr)>) -> Result<(), io::Error> {
         let inner = self.clone();
-        for (uuid, addr) in m {
-            if !self.peers.contains_key(&uuid) {
-                tokio::spawn(inner.start_client(&addr).then(move |_| {
-                    println!("Started client for address {}", addr.clone());
-                    Ok(())
-                }));
-            }
+        let unknown: Vec<SocketAddr> = {
+            let peers = self.peers.lock().unwrap();
+            m.into_iter()
+                .filter(|(uuid, _)| !peers.contains_key(uuid))
+                .map(|(_, addr)| addr)
+                .collect()
         };
+        for addr in unknown {
+            tokio::spawn(inner.start_client(&addr).then(move |_| {
+                println!("Started client for address {}", addr.clone());
+                Ok(())
+            }));
+        }
         Ok(())
     }
 
-    fn integrate_transaction(&mut self, m: Transaction<T>) -> Result<(), io::Error> {
-        let chain1 = self.chain.clone();
-        match chain1 {
-            Some((_, mut chain)) => {
-                chain.add_transaction(&mut vec!(m));
-                if chain.get_no_curr_trans().eq(&0) {
-                    for (tx, _) in self.peers.values() {
-                        let chain1 = self.chain.clone();
-                        let _ = tx.unbounded_send( Messages::<T>::Pong((self.id, self.addr, chain1.unwrap().1)))
-                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "tx failed"));
-                    };
-                };
-                Ok(())
+    /// Queues the transactions the chain accepts and announces them to the other peers.
+    fn handle_transactions(&self, m: (Vec<Transaction<T>>, HashMap<String, Vec<u8>>), source: Option<Uuid>)
+        -> Result<(), io::Error> {
+        let (transactions, keys) = m;
+        self.receive(transactions.iter().map(|transaction| Inventory::Transaction(transaction.id())));
+        let mut accepted = Vec::new();
+        {
+            let mut chain = self.chain.lock().unwrap();
+            let chain = match chain.as_mut() {
+                Some(chain) => chain,
+                None => return Ok(()),
+            };
+            for transaction in transactions {
+                let id = transaction.id();
+                match chain.queue_transaction_with_keys(transaction, &keys) {
+                    Ok(()) => accepted.push(Inventory::Transaction(id)),
+                    Err(e) => println!("Rejecting transaction: {}", e),
+                }
             }
-            None => Ok(()),
         }
+        // mining happens in the background, the block is announced once it is mined
+        self.start_mining();
+        self.announce(accepted, source);
+        Ok(())
     }
 
-    fn majority_consensus(&mut self, chain: Chain<T>) {
-        if self.alt_chains.len() < 1 {
-           self.alt_chains.push_back((1, chain.clone()));
-            return;
+    /// Marks the received items as seen, so they aren't requested again whether they are valid
+    /// or not.
+    fn receive<I: IntoIterator<Item = Inventory>>(&self, items: I) {
+        let mut seen = self.seen.lock().unwrap();
+        for item in items {
+            seen.insert(item);
         }
+    }
 
-       let alt_chains1 = self.alt_chains.clone(); 
-        let matched = false;
-        for (mut count, sec_chain) in alt_chains1 {
-            if sec_chain.eq(&chain) {
-                let chain1 = self.chain.clone();
-                count += 1;
-                if count > chain1.unwrap().0.clone() {
-                    let tmp = self.chain.clone();
-                    self.chain = Some((count, sec_chain));
-                    self.alt_chains.push_front(tmp.unwrap());
+    /// Announces the items to all peers except the one they were received from.
+    fn announce(&self, items: Vec<Inventory>, source: Option<Uuid>) {
+        self.receive(items.iter().cloned());
+        let peers = self.peers.lock().unwrap();
+        for chunk in items.chunks(MAX_INVENTORY) {
+            for (id, (tx, _)) in peers.iter() {
+                if Some(*id) != source {
+                    let _ = tx.unbounded_send(Messages::<T>::Inv(chunk.to_vec()));
                 }
             }
         }
-        if matched.eq(&false) {
-            self.alt_chains.push_back((1, chain));
+    }
+}
+
+/// Sends the blocks with the keys of their senders in messages of at most `MAX_MESSAGE_SIZE` bytes.
+fn send_blocks<T>(chain: &Chain<T>, blocks: Vec<Block<T>>, tx: &Tx<T>)
+where T: Transactional
+{
+    let size = |block: &Block<T>| block.size() + keys_size(&chain.keys_for(slice::from_ref(block)));
+    for batch in batches(blocks, size) {
+        let keys = chain.keys_for(&batch);
+        let _ = tx.unbounded_send(Messages::<T>::Blocks((batch, keys)));
+    }
+}
+
+/// The serialized size of the keys of senders.
+fn keys_size(keys: &HashMap<String, Vec<u8>>) -> usize {
+    serde_json::to_vec(keys).map(|bytes| bytes.len()).unwrap_or(0)
+}
+
+/// Splits the items into batches of at most `MAX_MESSAGE_SIZE` bytes, or of a single larger item.
+fn batches<I, F>(items: Vec<I>, size: F) -> Vec<Vec<I>>
+where F: Fn(&I) -> usize
+{
+    let mut batches: Vec<Vec<I>> = Vec::new();
+    let mut batch_size = 0;
+    for item in items {
+        let item_size = size(&item);
+        match batches.last_mut() {
+            Some(batch) if batch_size + item_size <= MAX_MESSAGE_SIZE => batch.push(item),
+            _ => {
+                batch_size = 0;
+                batches.push(vec![item]);
+            }
         }
+        batch_size += item_size;
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use futures::{future, Async, Future, Stream};
+    use futures::sync::mpsc;
+
+    use crate::blockchain::chain::Chain;
+    use crate::blockchain::pow::MAX_BITS;
+    use crate::blockchain::transaction::CryptoPayload;
+    use crate::node::codec::Format;
+    use crate::node::messages::Messages;
+    use crate::node::node::{batches, Connection, Node, NodeInner, Rx, Tx, MAX_MESSAGE_SIZE};
+
+    /// Takes the messages sent so far without waiting for more.
+    fn receive(rx: &mut Rx<CryptoPayload>) -> Vec<Messages<CryptoPayload>> {
+        future::lazy(|| -> Result<_, ()> {
+            let mut messages = Vec::new();
+            while let Ok(Async::Ready(Some(message))) = rx.poll() {
+                messages.push(message);
+            }
+            Ok(messages)
+        }).wait().unwrap()
+    }
+
+    fn connection() -> Connection {
+        Connection {
+            format: Arc::new(RwLock::new(Format::Binary)),
+            peer: None,
+        }
+    }
+
+    #[test]
+    fn configure_miner() {
+        let spec = Chain::<CryptoPayload>::new(String::from("Schwurbel"), MAX_BITS).spec().clone();
+        let node: Node<CryptoPayload> = Node::new(&"127.0.0.1:8003".parse().unwrap(), spec).unwrap();
+        assert_eq!(node.inner.read().unwrap().miner.threads, 1);
+        node.update_miner_threads(4);
+        assert_eq!(node.inner.read().unwrap().miner.threads, 4);
+    }
+
+    #[test]
+    fn split_messages() {
+        let half = MAX_MESSAGE_SIZE / 2;
+        let sizes = vec![half, half, 1, MAX_MESSAGE_SIZE + 1, 1];
+        assert_eq!(batches(sizes, |size| *size), vec![vec![half, half], vec![1], vec![MAX_MESSAGE_SIZE + 1], vec![1]]);
+        assert!(batches(Vec::<usize>::new(), |size| *size).is_empty());
+    }
+
+    #[test]
+    fn sync_between_nodes() {
+        let spec = Chain::<CryptoPayload>::new(String::from("Schwurbel"), MAX_BITS).spec().clone();
+        let first: NodeInner<CryptoPayload> = NodeInner::new("127.0.0.1:8001".parse().unwrap(), spec.clone()).unwrap();
+        let second: NodeInner<CryptoPayload> = NodeInner::new("127.0.0.1:8002".parse().unwrap(), spec).unwrap();
+        for _ in 0..3 {
+            first.chain.lock().unwrap().as_mut().unwrap().add_new_block();
+        }
+
+        // every node sends to the other one through its own channel
+        let (to_second, mut second_rx): (Tx<CryptoPayload>, Rx<CryptoPayload>) = mpsc::unbounded();
+        let (to_first, mut first_rx): (Tx<CryptoPayload>, Rx<CryptoPayload>) = mpsc::unbounded();
+        let (mut first_connection, mut second_connection) = (connection(), connection());
+        to_second.unbounded_send(Messages::Handshake(first.handshake())).unwrap();
+        to_first.unbounded_send(Messages::Handshake(second.handshake())).unwrap();
+
+        // like the clients, every message is processed by a fresh clone of the node
+        loop {
+            let (for_first, for_second) = (receive(&mut first_rx), receive(&mut second_rx));
+            if for_first.is_empty() && for_second.is_empty() {
+                break;
+            }
+            for message in for_first {
+                first.clone().process(message, &to_second, &mut first_connection).unwrap();
+            }
+            for message in for_second {
+                second.clone().process(message, &to_first, &mut second_connection).unwrap();
+            }
+        }
+
+        assert_eq!(first.peers.lock().unwrap().len(), 1);
+        assert_eq!(second.peers.lock().unwrap().len(), 1);
+        let first_chain = first.chain.lock().unwrap();
+        let second_chain = second.chain.lock().unwrap();
+        assert_eq!(second_chain.as_ref().unwrap().headers().len(), 4);
+        assert_eq!(second_chain.as_ref().unwrap().headers(), first_chain.as_ref().unwrap().headers());
     }
 }
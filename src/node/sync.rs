@@ -0,0 +1,368 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::blockchain::block::{Block, BlockHeader, BlockLimits};
+use crate::blockchain::chain::{genesis_pre_hash, Chain};
+use crate::blockchain::transaction::Transactional;
+use crate::blockchain::validation::Rule;
+
+/// The most headers sent in a single `Headers` message
+pub const MAX_HEADERS: usize = 500;
+/// The most blocks requested in a single `GetBlocks` message
+pub const MAX_BLOCKS: usize = 16;
+/// The most blocks requested from a single peer at a time
+const MAX_IN_FLIGHT: usize = 2 * MAX_BLOCKS;
+/// Requests unanswered for this long are sent to another peer
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The most headers waiting for their blocks, further headers are requested once half of them
+/// arrived
+const MAX_QUEUED: usize = 20 * MAX_HEADERS;
+
+/// The public keys of the senders of a block by address
+type SenderKeys = HashMap<String, Vec<u8>>;
+
+/// Downloads the blocks of longer chains of peers, headers first.
+///
+/// Headers are requested from a locator of known hashes and checked for their linkage, difficulty
+/// and proof of work. At most `MAX_QUEUED` headers wait for their blocks at a time. The blocks of the checked headers are then requested in batches from all peers
+/// at once and handed out in order, once their parent is known, to be validated by the chain.
+/// Requests to peers that disconnected or don't answer in time are sent to other peers, and the
+/// received headers extend the locator, so the sync resumes where it stopped.
+#[derive(Debug)]
+pub struct HeaderSync<T> {
+    /// Checked headers whose blocks are not known to the chain yet with their height, by hash
+    headers: HashMap<String, (usize, BlockHeader)>,
+    /// The hashes of `headers` in the order they were received, parents before their children
+    queue: VecDeque<String>,
+    /// The hash of the last received header, the most recent known hash for the locator
+    last: Option<String>,
+    /// The peer each block was requested from and when
+    requested: HashMap<String, (Uuid, Instant)>,
+    /// Blocks waiting to be handed out until their parent is known, with the public keys of
+    /// their senders received along
+    received: HashMap<String, (Block<T>, SenderKeys)>,
+    /// Whether headers were ignored because the queue was full
+    stalled: bool,
+}
+
+impl<T> Default for HeaderSync<T> {
+    fn default() -> Self {
+        HeaderSync {
+            headers: HashMap::new(),
+            queue: VecDeque::new(),
+            last: None,
+            requested: HashMap::new(),
+            received: HashMap::new(),
+            stalled: false,
+        }
+    }
+}
+
+impl<T> HeaderSync<T>
+where T: Transactional
+{
+    /// Whether blocks are still missing.
+    pub fn is_syncing(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// The locator to request the next headers with: the last received header followed by the
+    /// locator of the active chain.
+    pub fn locator(&self, chain: Option<&Chain<T>>) -> Vec<String> {
+        let mut locator: Vec<String> = self.last.iter().cloned().collect();
+        if let Some(chain) = chain {
+            locator.extend(chain.locator());
+        }
+        locator
+    }
+
+    /// Adds the headers received from a peer.
+    ///
+    /// Every header has to link to a known block or header, have the difficulty the retargeting
+    /// of the chain expects and meet its target. Headers beyond `MAX_QUEUED` are ignored.
+    /// Returns whether the peer may have more headers to request now, or the rule the headers
+    /// broke.
+    pub fn add_headers(&mut self, chain: Option<&Chain<T>>, headers: Vec<BlockHeader>) -> Result<bool, Rule> {
+        let more = headers.len() >= MAX_HEADERS;
+        for header in headers {
+            let hash = header.hash();
+            if self.headers.contains_key(&hash) || chain.map_or(false, |chain| chain.contains(&hash)) {
+                continue;
+            }
+            if self.queue.len() >= MAX_QUEUED {
+                self.stalled = true;
+                return Ok(false);
+            }
+            let parent = header.pre_hash();
+            let height = if parent == genesis_pre_hash() {
+                0
+            } else {
+                match self.headers.get(parent) {
+                    Some((height, _)) => height + 1,
+                    None => chain.and_then(|chain| chain.height_of(parent)).ok_or(Rule::UnknownParent)? + 1,
+                }
+            };
+            if let Some(chain) = chain {
+                let expected = chain.spec().retarget.next_bits(height, |height| self.header_on(chain, parent, height));
+                if expected.map_or(false, |bits| bits != header.bits) {
+                    return Err(Rule::Difficulty);
+                }
+            }
+            if !header.meets_target() {
+                return Err(Rule::ProofOfWork);
+            }
+            self.queue.push_back(hash.clone());
+            self.last = Some(hash.clone());
+            self.headers.insert(hash, (height, header));
+        }
+        Ok(more)
+    }
+
+    /// The header at the height on the branch ending in the known block or header `tip`.
+    fn header_on<'a>(&'a self, chain: &'a Chain<T>, tip: &'a str, height: usize) -> Option<&'a BlockHeader> {
+        let mut hash = tip;
+        while let Some((at, header)) = self.headers.get(hash) {
+            if *at == height {
+                return Some(header);
+            }
+            if *at < height {
+                return None;
+            }
+            hash = header.pre_hash();
+        }
+        chain.header_on(hash, height)
+    }
+
+    /// Whether headers were ignored because the queue was full and enough blocks arrived since
+    /// to request them again.
+    pub fn resume(&mut self) -> bool {
+        if self.stalled && self.queue.len() <= MAX_QUEUED / 2 {
+            self.stalled = false;
+            return true;
+        }
+        false
+    }
+
+    /// Assigns the missing blocks not requested yet to the connected peers.
+    ///
+    /// Requests to peers that are no longer connected or timed out are assigned again.
+    /// Returns the hashes to request from each peer.
+    pub fn next_requests(&mut self, peers: &[Uuid], now: Instant) -> Vec<(Uuid, Vec<String>)> {
+        let connected: HashSet<&Uuid> = peers.iter().collect();
+        self.requested.retain(|_, (peer, at)| connected.contains(peer) && now.duration_since(*at) < REQUEST_TIMEOUT);
+
+        let mut in_flight: HashMap<Uuid, usize> = HashMap::new();
+        for (peer, _) in self.requested.values() {
+            *in_flight.entry(*peer).or_insert(0) += 1;
+        }
+        let mut missing = self.queue.iter()
+            .filter(|hash| !self.requested.contains_key(*hash) && !self.received.contains_key(*hash))
+            .cloned()
+            .collect::<Vec<String>>()
+            .into_iter();
+
+        let mut requests = Vec::new();
+        // one batch per peer and round, so all peers download in parallel
+        loop {
+            let mut assigned = false;
+            for peer in peers {
+                let count = in_flight.entry(*peer).or_insert(0);
+                let batch: Vec<String> = missing.by_ref().take(MAX_BLOCKS.min(MAX_IN_FLIGHT - *count)).collect();
+                if batch.is_empty() {
+                    continue;
+                }
+                *count += batch.len();
+                for hash in &batch {
+                    self.requested.insert(hash.clone(), (*peer, now));
+                }
+                requests.push((*peer, batch));
+                assigned = true;
+            }
+            if !assigned {
+                return requests;
+            }
+        }
+    }
+
+    /// Whether the block with the hash has a checked header and is not handed out yet.
+    pub fn is_expected(&self, hash: &str) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    /// Adds a block received from a peer with the keys of the senders.
+    ///
+    /// Only blocks requested from the peer are kept, and only if their transactions match the
+    /// checked header and the block limits, so peers can't fill the sync with blocks nobody asked
+    /// for. Returns the rule a requested block broke, it is requested from another peer then.
+    pub fn add_block(&mut self, block: Block<T>, keys: &HashMap<String, Vec<u8>>, peer: Uuid, limits: &BlockLimits)
+        -> Result<(), Rule> {
+        let hash = block.hash();
+        match self.requested.get(&hash) {
+            Some((requested, _)) if *requested == peer => self.requested.remove(&hash),
+            _ => return Ok(()),
+        };
+        limits.check(&block)?;
+        block.verify(block.header.pre_hash())?;
+        let keys = block.transactions().iter()
+            .filter_map(|transaction| keys.get_key_value(&transaction.sender))
+            .map(|(sender, key)| (sender.clone(), key.clone()))
+            .collect();
+        self.received.insert(hash, (block, keys));
+        Ok(())
+    }
+
+    /// Hands out the next received block whose parent is known to the chain, with the keys of
+    /// its senders.
+    pub fn next_block(&mut self, chain: Option<&Chain<T>>) -> Option<(Block<T>, SenderKeys)> {
+        let position = self.queue.iter().position(|hash| {
+            self.received.get(hash).map_or(false, |(block, _)| {
+                let parent = block.header.pre_hash();
+                match chain {
+                    Some(chain) => chain.contains(parent),
+                    None => parent == genesis_pre_hash(),
+                }
+            })
+        })?;
+        let hash = self.queue.remove(position)?;
+        self.headers.remove(&hash);
+        self.received.remove(&hash)
+    }
+
+    /// Drops a block the chain rejected together with the headers building on it.
+    pub fn reject(&mut self, hash: &str) {
+        let mut rejected: HashSet<String> = HashSet::new();
+        rejected.insert(hash.to_string());
+        for hash in &self.queue {
+            if self.headers.get(hash).map_or(false, |(_, header)| rejected.contains(header.pre_hash())) {
+                rejected.insert(hash.clone());
+            }
+        }
+        self.queue.retain(|hash| !rejected.contains(hash));
+        for hash in &rejected {
+            self.headers.remove(hash);
+            self.requested.remove(hash);
+            self.received.remove(hash);
+        }
+        if self.last.as_ref().map_or(false, |last| rejected.contains(last)) {
+            self.last = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    use uuid::Uuid;
+
+    use crate::blockchain::block::BlockLimits;
+    use crate::blockchain::chain::Chain;
+    use crate::blockchain::pow::MAX_BITS;
+    use crate::blockchain::transaction::CryptoPayload;
+    use crate::blockchain::validation::Rule;
+    use crate::node::sync::{HeaderSync, MAX_BLOCKS, MAX_QUEUED, REQUEST_TIMEOUT};
+
+    fn chain(length: usize) -> Chain<CryptoPayload> {
+        let mut chain = Chain::new(String::from("Schwurbel"), MAX_BITS);
+        while chain.headers().len() < length {
+            chain.add_new_block();
+        }
+        chain
+    }
+
+    #[test]
+    fn sync_from_peers() {
+        let remote = chain(40);
+        let limits = remote.spec().limits.clone();
+        let mut local: Option<Chain<CryptoPayload>> = None;
+        let mut sync = HeaderSync::default();
+
+        let headers = remote.headers_after(&sync.locator(local.as_ref()), 30);
+        assert_eq!(sync.add_headers(local.as_ref(), headers), Ok(false));
+        let headers = remote.headers_after(&sync.locator(local.as_ref()), 30);
+        assert_eq!(headers.len(), 10);
+        sync.add_headers(local.as_ref(), headers).unwrap();
+
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        let requests = sync.next_requests(&[first, second], now);
+        let sizes: Vec<(Uuid, usize)> = requests.iter().map(|(peer, hashes)| (*peer, hashes.len())).collect();
+        assert_eq!(sizes, vec![(first, MAX_BLOCKS), (second, MAX_BLOCKS), (first, 40 - 2 * MAX_BLOCKS)]);
+        assert!(sync.next_requests(&[first, second], now).is_empty());
+
+        // the second peer disconnects, the first one answers in reverse order
+        for hash in requests.iter().filter(|(peer, _)| *peer == first).flat_map(|(_, hashes)| hashes.iter()).rev() {
+            sync.add_block(remote.block(hash).unwrap(), &HashMap::new(), first, &limits).unwrap();
+        }
+        assert_eq!(sync.next_requests(&[first], now), vec![(first, requests[1].1.clone())]);
+        let (genesis, _) = sync.next_block(local.as_ref()).unwrap();
+        local = Some(Chain::from_blocks(String::from("Peter"), remote.spec().clone(), vec![genesis]).unwrap());
+        while let Some((block, _)) = sync.next_block(local.as_ref()) {
+            local.as_mut().unwrap().add_block(block).unwrap();
+        }
+        assert_eq!(local.as_ref().unwrap().headers().len(), MAX_BLOCKS);
+
+        // the first peer doesn't answer in time either
+        assert!(sync.next_requests(&[first], now).is_empty());
+        assert_eq!(sync.next_requests(&[first], now + REQUEST_TIMEOUT), vec![(first, requests[1].1.clone())]);
+        for hash in &requests[1].1 {
+            // blocks only count from the peer they were requested from
+            sync.add_block(remote.block(hash).unwrap(), &HashMap::new(), second, &limits).unwrap();
+            assert!(!sync.received.contains_key(hash));
+            sync.add_block(remote.block(hash).unwrap(), &HashMap::new(), first, &limits).unwrap();
+        }
+        while let Some((block, _)) = sync.next_block(local.as_ref()) {
+            local.as_mut().unwrap().add_block(block).unwrap();
+        }
+        assert!(!sync.is_syncing());
+        assert_eq!(local.unwrap().last_hash(), remote.last_hash());
+    }
+
+    #[test]
+    fn reject_headers() {
+        let remote = chain(3);
+        let mut sync = HeaderSync::<CryptoPayload>::default();
+        let headers = remote.headers_after(&[], 3);
+        assert_eq!(sync.add_headers(None, headers[1..].to_vec()), Err(Rule::UnknownParent));
+
+        let mut tampered = headers[0].clone();
+        tampered.bits = 0x1d00ffff;
+        assert_eq!(sync.add_headers(None, vec![tampered]), Err(Rule::ProofOfWork));
+
+        sync.add_headers(None, headers.clone()).unwrap();
+        sync.reject(&headers[1].hash());
+        let peer = Uuid::new_v4();
+        assert_eq!(sync.next_requests(&[peer], Instant::now())[0].1, vec![headers[0].hash()]);
+
+        // a block exceeding the limits is requested again
+        let block = remote.block_at(0).unwrap();
+        let limits = BlockLimits { max_size: block.size() - 1, ..BlockLimits::default() };
+        assert_eq!(sync.add_block(block, &HashMap::new(), peer, &limits), Err(Rule::BlockSize));
+        assert_eq!(sync.next_requests(&[peer], Instant::now())[0].1, vec![headers[0].hash()]);
+    }
+
+    #[test]
+    fn check_difficulty_and_bound_queue() {
+        let remote = chain(3);
+        let local = Chain::from_blocks(String::from("Peter"), remote.spec().clone(), vec![remote.block_at(0).unwrap()]).unwrap();
+        let mut sync = HeaderSync::<CryptoPayload>::default();
+        let headers = remote.headers_after(&local.locator(), 3);
+        let mut tampered = headers[0].clone();
+        tampered.bits = MAX_BITS - 1;
+        assert_eq!(sync.add_headers(Some(&local), vec![tampered]), Err(Rule::Difficulty));
+
+        // headers beyond the queue are requested again once half of the queued blocks arrived
+        sync.queue.extend((0..MAX_QUEUED).map(|height| height.to_string()));
+        assert_eq!(sync.add_headers(Some(&local), headers.clone()), Ok(false));
+        assert!(!sync.resume());
+        sync.queue.truncate(MAX_QUEUED / 2);
+        assert!(sync.resume());
+        assert!(!sync.resume());
+        sync.queue.clear();
+        assert_eq!(sync.add_headers(Some(&local), headers), Ok(false));
+        assert_eq!(sync.queue.len(), 2);
+    }
+}
@@ -0,0 +1,266 @@
+//! # Chain store
+//!
+//! Persists the active chain in its own keyspace of a storage backend.
+use std::cmp;
+use std::collections::HashMap;
+
+use super::storage::{Batch, Namespaced, Result, Storage};
+use crate::blockchain::block::Block;
+use crate::blockchain::transaction::Transactional;
+
+/// The keyspace of the chain store
+pub const NAMESPACE: &str = "block_chain";
+
+/// Key of the number of stored blocks
+const CHAIN_LENGTH: &[u8] = b"length";
+/// Prefix of the keys of the blocks, followed by their height
+const BLOCK_PREFIX: &[u8] = b"block/";
+/// Prefix of the keys of the heights of the blocks, followed by their hash
+const INDEX_PREFIX: &[u8] = b"index/";
+/// Prefix of the public keys of the senders, followed by their address
+const KEY_PREFIX: &[u8] = b"key/";
+/// Key of the address the node mines to
+const MINER: &[u8] = b"miner";
+
+/// Persists the active chain in a keyspace.
+///
+/// Every block is stored by height and indexed by hash. Blocks are only written if they differ
+/// from the stored ones, all changes of a save are written atomically. The public keys of the
+/// senders and the miner address are stored alongside, so a restarted node can check signatures
+/// and keeps its rewards.
+#[derive(Debug)]
+pub struct ChainStore<S> {
+    storage: S,
+}
+
+impl<S> ChainStore<S>
+where S: Storage
+{
+    pub fn new(storage: S) -> ChainStore<S> {
+        ChainStore {
+            storage,
+        }
+    }
+
+    /// Opens the chain store in its own keyspace of the storage.
+    pub fn open<N>(storage: &mut N) -> Result<ChainStore<S>>
+    where N: Namespaced<Namespace = S>
+    {
+        Ok(ChainStore::new(storage.namespace(NAMESPACE)?))
+    }
+
+    /// The number of stored blocks.
+    pub fn len(&self) -> Result<usize> {
+        match self.storage.get(CHAIN_LENGTH)? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Ok(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The stored block at the given height.
+    pub fn block_at<T>(&self, height: usize) -> Result<Option<Block<T>>>
+    where T: Transactional
+    {
+        match self.storage.get(&block_key(height))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The height of the stored block with the given hash.
+    pub fn height_of(&self, hash: &str) -> Result<Option<usize>> {
+        match self.storage.get(&index_key(hash))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The stored block with the given hash.
+    pub fn block<T>(&self, hash: &str) -> Result<Option<Block<T>>>
+    where T: Transactional
+    {
+        match self.height_of(hash)? {
+            Some(height) => self.block_at(height),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads all stored blocks, starting with the genesis block.
+    pub fn load<T>(&self) -> Result<Vec<Block<T>>>
+    where T: Transactional
+    {
+        (0..self.len()?)
+            .map(|height| self.block_at(height)?
+                .ok_or_else(|| failure::err_msg(format!("Missing block at height {}", height))))
+            .collect()
+    }
+
+    /// Stores the blocks of the active chain, replacing stored blocks displaced by a reorg.
+    pub fn save<T>(&mut self, blocks: &[Block<T>]) -> Result<()>
+    where T: Transactional
+    {
+        let stored = self.len()?;
+        let mut fork = cmp::min(stored, blocks.len());
+        while fork > 0 && self.height_of(&blocks[fork - 1].hash())? != Some(fork - 1) {
+            fork -= 1;
+        }
+        if fork == stored && fork == blocks.len() {
+            return Ok(());
+        }
+        self.replace(fork, &blocks[fork..])
+    }
+
+    /// Replaces the stored blocks from the height on by the blocks, e.g. the new branch of a
+    /// reorg.
+    pub fn replace<T>(&mut self, height: usize, blocks: &[Block<T>]) -> Result<()>
+    where T: Transactional
+    {
+        let stored = self.len()?;
+        let mut batch = Batch::new();
+        for height in height..stored {
+            if let Some(block) = self.block_at::<T>(height)? {
+                batch.delete(index_key(&block.hash()));
+            }
+            batch.delete(block_key(height));
+        }
+        for (offset, block) in blocks.iter().enumerate() {
+            batch.put(block_key(height + offset), serde_json::to_vec(block)?);
+            batch.put(index_key(&block.hash()), serde_json::to_vec(&(height + offset))?);
+        }
+        batch.put(CHAIN_LENGTH.to_vec(), serde_json::to_vec(&(height + blocks.len()))?);
+        self.storage.write_batch(batch)
+    }
+
+    /// The stored public keys of the senders by address.
+    pub fn keys(&self) -> Result<HashMap<String, Vec<u8>>> {
+        self.storage.prefix_iter(KEY_PREFIX)?
+            .map(|(key, value)| Ok((String::from_utf8(key[KEY_PREFIX.len()..].to_vec())?, value)))
+            .collect()
+    }
+
+    /// Stores the public key of a sender.
+    pub fn put_key(&mut self, sender: &str, key: &[u8]) -> Result<()> {
+        let mut storage_key = KEY_PREFIX.to_vec();
+        storage_key.extend_from_slice(sender.as_bytes());
+        self.storage.put(storage_key, key.to_vec())
+    }
+
+    /// The stored address the node mines to.
+    pub fn miner(&self) -> Result<Option<String>> {
+        match self.storage.get(MINER)? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores the address the node mines to.
+    pub fn put_miner(&mut self, address: &str) -> Result<()> {
+        self.storage.put(MINER.to_vec(), address.as_bytes().to_vec())
+    }
+
+    /// Moves the store onto a boxed storage, e.g. to be kept by a `Chain` regardless of the
+    /// backend.
+    pub fn boxed(self) -> ChainStore<Box<dyn Storage + Send>>
+    where S: Send + 'static
+    {
+        ChainStore::new(Box::new(self.storage))
+    }
+
+    /// Deletes all stored blocks and their index, keeps the public keys of the senders and the
+    /// miner address.
+    pub fn clear(&mut self) -> Result<()> {
+        let mut batch = Batch::new();
+        for prefix in &[BLOCK_PREFIX, INDEX_PREFIX] {
+            for (key, _) in self.storage.prefix_iter(prefix)? {
+                batch.delete(key);
+            }
+        }
+        batch.delete(CHAIN_LENGTH.to_vec());
+        self.storage.write_batch(batch)
+    }
+}
+
+/// The key of the block at the given height, big endian to keep the blocks ordered.
+fn block_key(height: usize) -> Vec<u8> {
+    let mut key = BLOCK_PREFIX.to_vec();
+    key.extend_from_slice(&(height as u64).to_be_bytes());
+    key
+}
+
+/// The key of the height of the block with the given hash.
+fn index_key(hash: &str) -> Vec<u8> {
+    let mut key = INDEX_PREFIX.to_vec();
+    key.extend_from_slice(hash.as_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::block::Block;
+    use crate::blockchain::chain::genesis_pre_hash;
+    use crate::blockchain::pow::MAX_BITS;
+    use crate::blockchain::transaction::CryptoPayload;
+    use crate::storage::chain::{ChainStore, NAMESPACE};
+    use crate::storage::hashmap::Namespaces;
+    use crate::storage::storage::{Namespaced, Storage};
+
+    fn blocks(count: usize) -> Vec<Block<CryptoPayload>> {
+        let mut blocks: Vec<Block<CryptoPayload>> = Vec::new();
+        for _ in 0..count {
+            let pre_hash = blocks.last().map(|block| block.hash()).unwrap_or_else(genesis_pre_hash);
+            let mut block = Block::new(pre_hash, MAX_BITS, String::from("Schwurbel"), 100, &mut vec![]);
+            block.header.mine();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut namespaces = Namespaces::default();
+        let mut store = ChainStore::open(&mut namespaces).unwrap();
+        let chain = blocks(3);
+        store.save(&chain).unwrap();
+        assert_eq!(store.len().unwrap(), 3);
+        assert_eq!(store.load::<CryptoPayload>().unwrap(), chain);
+        assert_eq!(store.height_of(&chain[2].hash()).unwrap(), Some(2));
+
+        // a shorter branch replaces all blocks after the genesis block
+        let mut block = Block::new(chain[0].hash(), MAX_BITS, String::from("Peter"), 100, &mut vec![]);
+        block.header.mine();
+        let branch = vec![chain[0].clone(), block];
+        store.save(&branch).unwrap();
+
+        let store = ChainStore::open(&mut namespaces).unwrap();
+        assert_eq!(store.load::<CryptoPayload>().unwrap(), branch);
+        assert_eq!(store.height_of(&chain[2].hash()).unwrap(), None);
+        assert_eq!(store.block::<CryptoPayload>(&branch[1].hash()).unwrap(), Some(branch[1].clone()));
+        assert_eq!(namespaces.namespace("other").unwrap().iter().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn keys_and_miner() {
+        let mut namespaces = Namespaces::default();
+        let mut store = ChainStore::open(&mut namespaces).unwrap();
+        assert_eq!(store.miner().unwrap(), None);
+        store.put_miner("Schwurbel").unwrap();
+        store.put_key("Schwurbel", b"key").unwrap();
+        store.replace(0, &blocks(1)).unwrap();
+
+        let mut store = ChainStore::open(&mut namespaces).unwrap().boxed();
+        assert_eq!(store.miner().unwrap(), Some(String::from("Schwurbel")));
+        assert_eq!(store.keys().unwrap().get("Schwurbel"), Some(&b"key".to_vec()));
+        assert_eq!(store.len().unwrap(), 1);
+
+        // dropping the blocks keeps the keys and the miner address
+        store.clear().unwrap();
+        assert!(store.is_empty().unwrap());
+        assert_eq!(store.miner().unwrap(), Some(String::from("Schwurbel")));
+        assert_eq!(store.keys().unwrap().len(), 1);
+        assert_eq!(namespaces.namespace(NAMESPACE).unwrap().iter().unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,431 @@
+//! # Encrypted storage
+//!
+//! `Storage` wrapper that encrypts and authenticates everything written to an inner backend with
+//! a key derived from a passphrase.
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use failure::Fail;
+use sha3::{Digest, Sha3_256};
+
+use super::storage::{Batch, Iter, Namespaced, Operation, Result, Storage};
+
+/// Version of the format of encrypted values and keys, authenticated with each of them
+const VERSION: u8 = 1;
+/// Memory in KiB and passes of Argon2id deriving a key from a passphrase, slowing down guessing it
+#[cfg(not(test))]
+const KDF_MEMORY: u32 = 1 << 16;
+#[cfg(not(test))]
+const KDF_PASSES: u32 = 3;
+#[cfg(test)]
+const KDF_MEMORY: u32 = 8;
+#[cfg(test)]
+const KDF_PASSES: u32 = 1;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// The keyspace of the salt and the passphrase check of `EncryptedNamespaces`
+pub const NAMESPACE: &str = "encryption";
+/// Key of the salt the key is derived with
+const SALT: &[u8] = b"salt";
+/// Key of a known value to check the passphrase with
+const CHECK: &[u8] = b"check";
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum EncryptionError {
+    #[fail(display = "Wrong passphrase for the encrypted storage")]
+    WrongPassphrase,
+    #[fail(display = "Encrypted data was tampered with")]
+    Tampered,
+    #[fail(display = "Unsupported version {} of encrypted data", _0)]
+    Version(u8),
+}
+
+/// Symmetric keys derived from a passphrase with Argon2id.
+///
+/// Values are encrypted with ChaCha20-Poly1305 under a random nonce. The format version and the
+/// storage key of the value are authenticated as associated data, so values can't be altered,
+/// moved to another key or read as another version unnoticed.
+#[derive(Clone)]
+pub struct Key {
+    cipher: [u8; 32],
+    /// Derives the nonces of encrypted storage keys from their plaintext
+    siv: [u8; 32],
+}
+
+impl Key {
+    /// Derives the keys from the passphrase and a random salt unique to the storage, which has
+    /// to be at least 8 bytes long.
+    pub fn derive(passphrase: &str, salt: &[u8]) -> Result<Key> {
+        let config = argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            mem_cost: KDF_MEMORY,
+            time_cost: KDF_PASSES,
+            hash_length: 64,
+            ..argon2::Config::default()
+        };
+        let derived = argon2::hash_raw(passphrase.as_bytes(), salt, &config)?;
+        let mut key = Key {
+            cipher: [0; 32],
+            siv: [0; 32],
+        };
+        key.cipher.copy_from_slice(&derived[..32]);
+        key.siv.copy_from_slice(&derived[32..]);
+        Ok(key)
+    }
+
+    /// Derives the keys of a keyspace, so values can't be moved between keyspaces unnoticed.
+    pub fn for_namespace(&self, name: &str) -> Key {
+        let subkey = |key: &[u8; 32]| {
+            let mut subkey = [0; 32];
+            subkey.copy_from_slice(&Sha3_256::new().chain(key).chain(name.as_bytes()).result());
+            subkey
+        };
+        Key {
+            cipher: subkey(&self.cipher),
+            siv: subkey(&self.siv),
+        }
+    }
+
+    fn aead(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&self.cipher.into())
+    }
+
+    /// Encrypts a value stored under the associated key: version | nonce | ciphertext | tag
+    pub fn seal(&self, value: &[u8], associated: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let ciphertext = self.aead()
+            .encrypt(&nonce.into(), Payload { msg: value, aad: &associated_data(associated) })
+            .expect("[Key seal()]: Value too large to encrypt!");
+        let mut sealed = vec![VERSION];
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypts a value sealed under the associated key.
+    pub fn open(&self, sealed: &[u8], associated: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < 1 + NONCE_SIZE + TAG_SIZE {
+            return Err(EncryptionError::Tampered.into());
+        }
+        if sealed[0] != VERSION {
+            return Err(EncryptionError::Version(sealed[0]).into());
+        }
+        let (nonce, ciphertext) = sealed[1..].split_at(NONCE_SIZE);
+        self.aead()
+            .decrypt(&to_nonce(nonce), Payload { msg: ciphertext, aad: &associated_data(associated) })
+            .map_err(|_| EncryptionError::Tampered.into())
+    }
+
+    /// Encrypts a storage key deterministically, so it can still be looked up: nonce | ciphertext | tag
+    ///
+    /// The nonce is a keyed hash of the key, so only equal keys share a nonce (SIV).
+    pub fn seal_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut sealed = self.synthetic_nonce(key);
+        let ciphertext = self.aead()
+            .encrypt(&to_nonce(&sealed), Payload { msg: key, aad: &[VERSION] })
+            .expect("[Key seal_key()]: Key too large to encrypt!");
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypts a storage key encrypted with `seal_key`.
+    pub fn open_key(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(EncryptionError::Tampered.into());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let key = self.aead()
+            .decrypt(&to_nonce(nonce), Payload { msg: ciphertext, aad: &[VERSION] })
+            .map_err(|_| EncryptionError::Tampered)?;
+        if self.synthetic_nonce(&key) != nonce {
+            return Err(EncryptionError::Tampered.into());
+        }
+        Ok(key)
+    }
+
+    fn synthetic_nonce(&self, key: &[u8]) -> Vec<u8> {
+        Sha3_256::new().chain(self.siv).chain(key).result()[..NONCE_SIZE].to_vec()
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
+
+fn to_nonce(bytes: &[u8]) -> Nonce {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce.copy_from_slice(bytes);
+    nonce.into()
+}
+
+/// Binds a sealed value to the format version and the storage key it is stored under.
+fn associated_data(key: &[u8]) -> Vec<u8> {
+    let mut associated = vec![VERSION];
+    associated.extend_from_slice(key);
+    associated
+}
+
+/// Encrypts the values, and optionally the keys, written to the inner storage.
+///
+/// Reading a value that was altered, or moved to another key, fails with
+/// `EncryptionError::Tampered`. Encrypted keys hide the keys, but not which values share a key.
+/// As their order is lost too, ranges over encrypted keys decrypt and sort all keys of the
+/// inner storage.
+#[derive(Debug)]
+pub struct Encrypted<S> {
+    inner: S,
+    key: Key,
+    encrypt_keys: bool,
+}
+
+impl<S> Encrypted<S>
+where S: Storage
+{
+    /// Encrypts the values written to the storage, keys remain plaintext.
+    pub fn new(inner: S, key: Key) -> Encrypted<S> {
+        Encrypted {
+            inner,
+            key,
+            encrypt_keys: false,
+        }
+    }
+
+    /// Encrypts both the keys and the values written to the storage.
+    pub fn with_encrypted_keys(inner: S, key: Key) -> Encrypted<S> {
+        Encrypted {
+            inner,
+            key,
+            encrypt_keys: true,
+        }
+    }
+
+    fn inner_key(&self, key: &[u8]) -> Vec<u8> {
+        if self.encrypt_keys {
+            self.key.seal_key(key)
+        } else {
+            key.to_vec()
+        }
+    }
+
+    fn outer_key(&self, key: Vec<u8>) -> Result<Vec<u8>> {
+        if self.encrypt_keys {
+            self.key.open_key(&key)
+        } else {
+            Ok(key)
+        }
+    }
+}
+
+impl<S> Storage for Encrypted<S>
+where S: Storage
+{
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.inner.get(&self.inner_key(key))? {
+            Some(sealed) => Ok(Some(self.key.open(&sealed, key)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let sealed = self.key.seal(&value, &key);
+        self.inner.put(self.inner_key(&key), sealed)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let key = self.inner_key(key);
+        self.inner.delete(&key)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        self.inner.contains(&self.inner_key(key))
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        let mut encrypted = Batch::new();
+        for operation in batch.operations() {
+            match operation {
+                Operation::Put(key, value) => encrypted.put(self.inner_key(key), self.key.seal(value, key)),
+                Operation::Delete(key) => encrypted.delete(self.inner_key(key)),
+            }
+        }
+        self.inner.write_batch(encrypted)
+    }
+
+    fn range<'a>(&'a self, start: &[u8], end: Option<&[u8]>) -> Result<Iter<'a>> {
+        let pairs = if self.encrypt_keys {
+            self.inner.iter()?
+        } else {
+            self.inner.range(start, end)?
+        };
+        let mut decrypted = Vec::new();
+        for (key, sealed) in pairs {
+            let key = self.outer_key(key)?;
+            if key.as_slice() >= start && end.map_or(true, |end| key.as_slice() < end) {
+                let value = self.key.open(&sealed, &key)?;
+                decrypted.push((key, value));
+            }
+        }
+        decrypted.sort();
+        Ok(Box::new(decrypted.into_iter()))
+    }
+}
+
+/// Encrypts every keyspace of the inner storage.
+///
+/// Every keyspace is encrypted with its own key, see `Key::for_namespace`. The salt of the key
+/// and a value to check the passphrase with are stored in the `NAMESPACE` keyspace, which can't
+/// be opened through the wrapper.
+#[derive(Debug)]
+pub struct EncryptedNamespaces<N> {
+    inner: N,
+    key: Key,
+    encrypt_keys: bool,
+}
+
+impl<N> EncryptedNamespaces<N>
+where N: Namespaced
+{
+    /// Derives the key from the passphrase, fails if it differs from the one the storage was
+    /// encrypted with before.
+    pub fn open(mut inner: N, passphrase: &str, encrypt_keys: bool) -> Result<EncryptedNamespaces<N>> {
+        let mut parameters = inner.namespace(NAMESPACE)?;
+        let salt = match parameters.get(SALT)? {
+            Some(salt) => salt,
+            None => {
+                let salt: [u8; 32] = rand::random();
+                let key = Key::derive(passphrase, &salt)?;
+                let mut batch = Batch::new();
+                batch.put(SALT.to_vec(), salt.to_vec());
+                batch.put(CHECK.to_vec(), key.seal(CHECK, CHECK));
+                parameters.write_batch(batch)?;
+                salt.to_vec()
+            }
+        };
+
+        let key = Key::derive(passphrase, &salt)?;
+        let check = parameters.get(CHECK)?.ok_or(EncryptionError::Tampered)?;
+        match key.open(&check, CHECK) {
+            Ok(ref value) if value.as_slice() == CHECK => (),
+            _ => return Err(EncryptionError::WrongPassphrase.into()),
+        }
+        Ok(EncryptedNamespaces {
+            inner,
+            key,
+            encrypt_keys,
+        })
+    }
+}
+
+impl<N> Namespaced for EncryptedNamespaces<N>
+where N: Namespaced
+{
+    type Namespace = Encrypted<N::Namespace>;
+
+    fn namespace(&mut self, name: &str) -> Result<Encrypted<N::Namespace>> {
+        if name == NAMESPACE {
+            return Err(failure::err_msg(format!("The namespace {} is reserved", NAMESPACE)));
+        }
+        let inner = self.inner.namespace(name)?;
+        let key = self.key.for_namespace(name);
+        if self.encrypt_keys {
+            Ok(Encrypted::with_encrypted_keys(inner, key))
+        } else {
+            Ok(Encrypted::new(inner, key))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::encrypted::{Encrypted, EncryptedNamespaces, EncryptionError, Key};
+    use crate::storage::hashmap::{Backend, Namespace, Namespaces};
+    use crate::storage::storage::{conformance, Namespaced, Storage};
+
+    fn key() -> Key {
+        Key::derive("passphrase", b"salt of the test").unwrap()
+    }
+
+    #[test]
+    fn conformance() {
+        let key = key();
+        conformance::crud(Encrypted::new(Backend::new(), key.clone()));
+        conformance::write_batch(Encrypted::new(Backend::new(), key.clone()));
+        conformance::iterate(Encrypted::new(Backend::new(), key.clone()));
+        conformance::crud(Encrypted::with_encrypted_keys(Backend::new(), key.clone()));
+        conformance::write_batch(Encrypted::with_encrypted_keys(Backend::new(), key.clone()));
+        conformance::iterate(Encrypted::with_encrypted_keys(Backend::new(), key));
+        conformance::namespaces(EncryptedNamespaces::open(Namespaces::default(), "passphrase", true).unwrap());
+    }
+
+    #[test]
+    fn encrypt_at_rest() {
+        let inner = Namespace::default();
+        let mut storage = Encrypted::with_encrypted_keys(inner.clone(), key());
+        storage.put(b"secret key".to_vec(), b"secret value".to_vec()).unwrap();
+
+        let (key, value) = inner.iter().unwrap().next().unwrap();
+        assert!(!key.windows(6).any(|window| window == b"secret"));
+        assert!(!value.windows(6).any(|window| window == b"secret"));
+        assert_eq!(storage.get(b"secret key").unwrap(), Some(b"secret value".to_vec()));
+    }
+
+    #[test]
+    fn detect_tampering() {
+        let mut inner = Namespace::default();
+        let mut storage = Encrypted::new(inner.clone(), key());
+        storage.put(b"a".to_vec(), b"value".to_vec()).unwrap();
+        storage.put(b"b".to_vec(), b"other".to_vec()).unwrap();
+
+        // a value moved to another key
+        let moved = inner.get(b"a").unwrap().unwrap();
+        inner.put(b"b".to_vec(), moved.clone()).unwrap();
+        let error = storage.get(b"b").unwrap_err();
+        assert_eq!(error.downcast::<EncryptionError>().unwrap(), EncryptionError::Tampered);
+
+        // a flipped bit
+        let mut flipped = moved;
+        flipped[20] ^= 1;
+        inner.put(b"a".to_vec(), flipped).unwrap();
+        assert!(storage.get(b"a").is_err());
+        assert!(storage.iter().is_err());
+
+        let storage = Encrypted::new(inner.clone(), Key::derive("wrong", b"salt of the test").unwrap());
+        assert!(storage.get(b"a").is_err());
+
+        // a value of another format version
+        let mut sealed = key().seal(b"value", b"a");
+        sealed[0] += 1;
+        inner.put(b"a".to_vec(), sealed).unwrap();
+        let error = Encrypted::new(inner, key()).get(b"a").unwrap_err();
+        assert_eq!(error.downcast::<EncryptionError>().unwrap(), EncryptionError::Version(2));
+    }
+
+    #[test]
+    fn check_passphrase() {
+        let mut inner = Namespaces::default();
+        let mut storage = EncryptedNamespaces::open(inner.clone(), "passphrase", false).unwrap();
+        storage.namespace("keys").unwrap().put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert!(storage.namespace("encryption").is_err());
+
+        let error = EncryptedNamespaces::open(inner.clone(), "wrong", false).unwrap_err();
+        assert_eq!(error.downcast::<EncryptionError>().unwrap(), EncryptionError::WrongPassphrase);
+        let mut storage = EncryptedNamespaces::open(inner.clone(), "passphrase", false).unwrap();
+        assert_eq!(storage.namespace("keys").unwrap().get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_ne!(inner.namespace("keys").unwrap().get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn detect_moves_between_namespaces() {
+        let mut inner = Namespaces::default();
+        let mut storage = EncryptedNamespaces::open(inner.clone(), "passphrase", false).unwrap();
+        storage.namespace("keys").unwrap().put(b"key".to_vec(), b"value".to_vec()).unwrap();
+
+        let sealed = inner.namespace("keys").unwrap().get(b"key").unwrap().unwrap();
+        inner.namespace("block_chain").unwrap().put(b"key".to_vec(), sealed).unwrap();
+        let error = storage.namespace("block_chain").unwrap().get(b"key").unwrap_err();
+        assert_eq!(error.downcast::<EncryptionError>().unwrap(), EncryptionError::Tampered);
+    }
+}
@@ -2,7 +2,8 @@
 //!
 //! Storage backend that keeps data in a heap-allocated HashMap.
 use std::collections::HashMap;
-use super::storage::{Result, Storage};
+use std::sync::{Arc, RwLock};
+use super::storage::{Batch, Iter, Namespaced, Operation, Result, Storage};
 
 /// HashMap backend
 pub type Backend = HashMap<Vec<u8>, Vec<u8>>;
@@ -21,4 +22,108 @@ impl Storage for Backend {
         Backend::remove(self, key);
         Ok(())
     }
+
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        Ok(Backend::contains_key(self, key))
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        // writes to a map can't fail, so applying them one by one is atomic
+        for operation in batch.operations() {
+            match operation {
+                Operation::Put(key, value) => Backend::insert(self, key.clone(), value.clone()),
+                Operation::Delete(key) => Backend::remove(self, key),
+            };
+        }
+        Ok(())
+    }
+
+    fn range<'a>(&'a self, start: &[u8], end: Option<&[u8]>) -> Result<Iter<'a>> {
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = Backend::iter(self)
+            .filter(|(key, _)| key.as_slice() >= start && end.map_or(true, |end| key.as_slice() < end))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        pairs.sort();
+        Ok(Box::new(pairs.into_iter()))
+    }
+}
+
+/// A keyspace of `Namespaces`, handles to the same keyspace share their map
+#[derive(Clone, Debug, Default)]
+pub struct Namespace {
+    map: Arc<RwLock<Backend>>,
+}
+
+impl Storage for Namespace {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Storage::get(&*self.map.read().unwrap(), key)
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        Storage::put(&mut *self.map.write().unwrap(), key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        Storage::delete(&mut *self.map.write().unwrap(), key)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        Storage::contains(&*self.map.read().unwrap(), key)
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        self.map.write().unwrap().write_batch(batch)
+    }
+
+    fn range<'a>(&'a self, start: &[u8], end: Option<&[u8]>) -> Result<Iter<'a>> {
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = self.map.read().unwrap().range(start, end)?.collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+}
+
+/// HashMap backend with a separate map for every keyspace, clones share their keyspaces
+#[derive(Clone, Debug, Default)]
+pub struct Namespaces {
+    namespaces: Arc<RwLock<HashMap<String, Namespace>>>,
+}
+
+impl Namespaced for Namespaces {
+    type Namespace = Namespace;
+
+    fn namespace(&mut self, name: &str) -> Result<Namespace> {
+        Ok(self.namespaces.write().unwrap().entry(name.to_string()).or_default().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::hashmap::{Backend, Namespace, Namespaces};
+    use crate::storage::storage::conformance;
+
+    #[test]
+    fn crud() {
+        conformance::crud(Backend::new());
+    }
+
+    #[test]
+    fn write_batch() {
+        conformance::write_batch(Backend::new());
+    }
+
+    #[test]
+    fn iterate() {
+        conformance::iterate(Backend::new());
+    }
+
+    #[test]
+    fn namespace() {
+        conformance::crud(Namespace::default());
+        conformance::write_batch(Namespace::default());
+        conformance::iterate(Namespace::default());
+    }
+
+    #[test]
+    fn namespaces() {
+        conformance::namespaces(Namespaces::default());
+    }
 }
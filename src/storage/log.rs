@@ -0,0 +1,619 @@
+//! # Log storage backend
+//!
+//! Storage backend in pure Rust that appends all writes to a log file and keeps an index of the
+//! live values in memory.
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use sha3::{Digest, Sha3_256};
+
+use super::storage::{Batch, Iter, Namespaced, Operation, Result, Storage};
+
+/// log format:
+/// every batch is appended as a single record, that is only applied if it was written completely,
+/// a compaction writes the live values in records of about `COMPACTION_CHUNK_SIZE` bytes:
+/// record = length: u32 | checksum: [u8; 8] | operations (length bytes)
+/// operation = 0: u8 | key length: u32 | key | value length: u32 | value   (put)
+///           | 1: u8 | key length: u32 | key                              (delete)
+/// all integers are big endian, the checksum are the first bytes of the SHA3 hash of the operations
+const RECORD_HEADER: u64 = 12;
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+/// Logs smaller than this are never compacted.
+const COMPACTION_MIN_SIZE: u64 = 1 << 20;
+/// Logs are compacted once they are this many times larger than their live values.
+const COMPACTION_RATIO: u64 = 2;
+/// A compaction starts a new record once the current one exceeds this size, so it never holds
+/// more than a record and a value in memory.
+const COMPACTION_CHUNK_SIZE: usize = 1 << 20;
+
+/// The outcome of reading a record from the log file.
+enum Record {
+    Operations(Vec<u8>),
+    /// The log ends before or within the record, e.g. as its write was interrupted.
+    Torn,
+    /// The record doesn't match its checksum, but other records follow it.
+    Corrupted,
+}
+
+/// The position of a value in the log file.
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    offset: u64,
+    length: u32,
+}
+
+/// A single key/value store persisted in an append-only log file.
+///
+/// Every write is synced to disk before it is applied to the index. A record torn by a crash is
+/// truncated when the log is opened again, so a batch is either fully applied or not at all.
+/// Once most of the log consists of overwritten or deleted values, it is compacted by rewriting
+/// the live values to a new file that atomically replaces the log.
+pub struct Log {
+    path: PathBuf,
+    file: File,
+    index: BTreeMap<Vec<u8>, Location>,
+    /// The size of the valid part of the log file
+    size: u64,
+    /// The size of the records the live values would take up after a compaction
+    live: u64,
+}
+
+impl Log {
+    /// Opens the log at the given path, creating it if missing and dropping a torn last record.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Log> {
+        let path = path.as_ref().to_path_buf();
+        let compacted = compaction_path(&path);
+        if compacted.exists() {
+            // a compaction was interrupted before it replaced the log
+            fs::remove_file(&compacted)?;
+        }
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let mut log = Log {
+            path,
+            file,
+            index: BTreeMap::new(),
+            size: 0,
+            live: 0,
+        };
+        log.replay()?;
+        Ok(log)
+    }
+
+    /// Rebuilds the index from the records of the log file.
+    ///
+    /// Only a torn last record is dropped, a corrupted record followed by others fails, as
+    /// truncating the log there would drop the records written after it.
+    fn replay(&mut self) -> Result<()> {
+        let file_length = self.file.metadata()?.len();
+        let mut reader = BufReader::new(self.file.try_clone()?);
+        reader.seek(SeekFrom::Start(0))?;
+        loop {
+            let operations = match read_record(&mut reader, file_length - self.size)? {
+                Record::Operations(operations) => operations,
+                Record::Torn => break,
+                Record::Corrupted => return Err(failure::err_msg(format!(
+                    "Corrupted record at offset {} of {}", self.size, self.path.display()))),
+            };
+            self.apply(self.size + RECORD_HEADER, &operations)?;
+            self.size += RECORD_HEADER + operations.len() as u64;
+        }
+        if self.size < file_length {
+            self.file.set_len(self.size)?;
+            self.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Applies the encoded operations starting at the offset in the log file to the index.
+    fn apply(&mut self, offset: u64, operations: &[u8]) -> Result<()> {
+        let mut position = 0;
+        while position < operations.len() {
+            let tag = operations[position];
+            position += 1;
+            let key = read_slice(operations, &mut position)?.to_vec();
+            let old = match tag {
+                PUT => {
+                    let length = read_length(operations, &mut position)?;
+                    let location = Location { offset: offset + position as u64, length };
+                    position += length as usize;
+                    self.live += entry_size(&key, length);
+                    self.index.insert(key.clone(), location)
+                }
+                DELETE => self.index.remove(&key),
+                _ => return Err(failure::err_msg(format!("Unknown operation {} in {}", tag, self.path.display()))),
+            };
+            if let Some(old) = old {
+                self.live -= entry_size(&key, old.length);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the value at the location from the log file.
+    fn read(&self, location: Location) -> Result<Vec<u8>> {
+        let mut value = vec![0; location.length as usize];
+        read_at(&self.file, &mut value, location.offset)?;
+        Ok(value)
+    }
+
+    /// Appends the operations as a single record and syncs it to disk.
+    fn append(&mut self, operations: &[u8]) -> Result<()> {
+        let record = encode_record(operations)?;
+        self.file.seek(SeekFrom::Start(self.size))?;
+        let written = self.file.write_all(&record).and_then(|_| self.file.sync_data());
+        if let Err(e) = written {
+            // drop what was written of the record, a torn record is dropped on open anyway
+            let _ = self.file.set_len(self.size);
+            return Err(e.into());
+        }
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    /// Rewrites the live values to a new log file that replaces the log.
+    ///
+    /// The index of the new file is built while writing it, and the log switches to the file
+    /// handle it wrote, so nothing can fail after the rename and leave the log writing to the
+    /// replaced file.
+    pub fn compact(&mut self) -> Result<()> {
+        let compacted = compaction_path(&self.path);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&compacted)?;
+        let (index, size) = self.write_compacted(&file)?;
+        file.sync_all()?;
+        fs::rename(&compacted, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            // persists the rename, not supported by every platform
+            let _ = File::open(dir).and_then(|dir| dir.sync_all());
+        }
+        self.file = file;
+        self.index = index;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Writes the live values to the file and returns their index in it and its size.
+    ///
+    /// The values are read one after another and written in records of about
+    /// `COMPACTION_CHUNK_SIZE` bytes, a value larger than that gets a record of its own.
+    fn write_compacted(&self, file: &File) -> Result<(BTreeMap<Vec<u8>, Location>, u64)> {
+        let mut writer = BufWriter::new(file);
+        let mut index = BTreeMap::new();
+        let mut size = 0;
+        let mut operations = Vec::new();
+        for (key, location) in &self.index {
+            if !operations.is_empty() && operations.len() as u64 + entry_size(key, location.length) > COMPACTION_CHUNK_SIZE as u64 {
+                writer.write_all(&encode_record(&operations)?)?;
+                size += RECORD_HEADER + operations.len() as u64;
+                operations.clear();
+            }
+            operations.push(PUT);
+            write_slice(&mut operations, key)?;
+            write_slice(&mut operations, &self.read(*location)?)?;
+            let offset = size + RECORD_HEADER + (operations.len() - location.length as usize) as u64;
+            index.insert(key.clone(), Location { offset, length: location.length });
+        }
+        if !operations.is_empty() {
+            writer.write_all(&encode_record(&operations)?)?;
+            size += RECORD_HEADER + operations.len() as u64;
+        }
+        writer.flush()?;
+        Ok((index, size))
+    }
+
+    /// Whether most of the log consists of overwritten or deleted values.
+    fn needs_compaction(&self) -> bool {
+        self.size > COMPACTION_MIN_SIZE && self.size > COMPACTION_RATIO * (self.live + RECORD_HEADER)
+    }
+}
+
+impl Storage for Log {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key) {
+            Some(location) => Ok(Some(self.read(*location)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut batch = Batch::new();
+        batch.put(key, value);
+        self.write_batch(batch)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let mut batch = Batch::new();
+        batch.delete(key.to_vec());
+        self.write_batch(batch)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.index.contains_key(key))
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        if batch.operations().is_empty() {
+            return Ok(());
+        }
+        let operations = encode_operations(&batch)?;
+        let offset = self.size + RECORD_HEADER;
+        self.append(&operations)?;
+        self.apply(offset, &operations)?;
+        if self.needs_compaction() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn range<'a>(&'a self, start: &[u8], end: Option<&[u8]>) -> Result<Iter<'a>> {
+        if end.map_or(false, |end| end <= start) {
+            return Ok(Box::new(Vec::new().into_iter()));
+        }
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        let pairs = self.index.range::<[u8], _>((Bound::Included(start), end))
+            .map(|(key, location)| Ok((key.clone(), self.read(*location)?)))
+            .collect::<Result<Vec<(Vec<u8>, Vec<u8>)>>>()?;
+        Ok(Box::new(pairs.into_iter()))
+    }
+}
+
+impl fmt::Debug for Log {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Log({})", self.path.display())
+    }
+}
+
+/// A directory with a log file for every keyspace.
+#[derive(Debug)]
+pub struct Namespaces {
+    dir: PathBuf,
+    namespaces: HashMap<String, Namespace>,
+}
+
+impl Namespaces {
+    /// Opens the directory at the given path, creating it if missing.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Namespaces> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Namespaces {
+            dir: dir.as_ref().to_path_buf(),
+            namespaces: HashMap::new(),
+        })
+    }
+}
+
+impl Namespaced for Namespaces {
+    type Namespace = Namespace;
+
+    fn namespace(&mut self, name: &str) -> Result<Namespace> {
+        if let Some(namespace) = self.namespaces.get(name) {
+            return Ok(namespace.clone());
+        }
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(failure::err_msg(format!("Invalid namespace {:?}", name)));
+        }
+        let log = Log::open(self.dir.join(format!("{}.log", name)))?;
+        let namespace = Namespace {
+            log: Arc::new(Mutex::new(log)),
+        };
+        self.namespaces.insert(name.to_string(), namespace.clone());
+        Ok(namespace)
+    }
+}
+
+/// A keyspace of `Namespaces`, handles to the same keyspace share their log
+#[derive(Clone, Debug)]
+pub struct Namespace {
+    log: Arc<Mutex<Log>>,
+}
+
+impl Storage for Namespace {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.log.lock().unwrap().get(key)
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.log.lock().unwrap().put(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.log.lock().unwrap().delete(key)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        self.log.lock().unwrap().contains(key)
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        self.log.lock().unwrap().write_batch(batch)
+    }
+
+    fn range<'a>(&'a self, start: &[u8], end: Option<&[u8]>) -> Result<Iter<'a>> {
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = self.log.lock().unwrap().range(start, end)?.collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+}
+
+/// Reads exactly the buffer at the offset of the file without moving a cursor shared by
+/// concurrent readers.
+#[cfg(unix)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buffer = &mut buffer[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The path of the file a log is compacted into.
+fn compaction_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(".compact");
+    path.with_file_name(name)
+}
+
+/// The size of the operation storing the value of the given length under the key.
+fn entry_size(key: &[u8], length: u32) -> u64 {
+    9 + key.len() as u64 + u64::from(length)
+}
+
+fn checksum(operations: &[u8]) -> [u8; 8] {
+    let mut checksum = [0; 8];
+    checksum.copy_from_slice(&Sha3_256::digest(operations)[..8]);
+    checksum
+}
+
+fn encode_operations(batch: &Batch) -> Result<Vec<u8>> {
+    let mut operations = Vec::new();
+    for operation in batch.operations() {
+        match operation {
+            Operation::Put(key, value) => {
+                operations.push(PUT);
+                write_slice(&mut operations, key)?;
+                write_slice(&mut operations, value)?;
+            }
+            Operation::Delete(key) => {
+                operations.push(DELETE);
+                write_slice(&mut operations, key)?;
+            }
+        }
+    }
+    Ok(operations)
+}
+
+/// Fails if the operations don't fit into a single record, instead of writing a record whose
+/// length is truncated.
+fn encode_record(operations: &[u8]) -> Result<Vec<u8>> {
+    let mut record = Vec::with_capacity(RECORD_HEADER as usize + operations.len());
+    record.extend_from_slice(&encode_length(operations.len())?.to_be_bytes());
+    record.extend_from_slice(&checksum(operations));
+    record.extend_from_slice(operations);
+    Ok(record)
+}
+
+/// The length as stored in the log, which can't describe more than `u32::MAX` bytes.
+fn encode_length(length: usize) -> Result<u32> {
+    u32::try_from(length)
+        .map_err(|_| failure::err_msg(format!("{} bytes exceed the largest record of the log", length)))
+}
+
+/// Reads the next record from the `remaining` bytes of the log.
+///
+/// A record is torn if it doesn't fit into the remaining bytes, or if it is the last one and
+/// doesn't match its checksum. Its length is checked before its operations are read, so a corrupt
+/// header can't make it allocate more than the log holds.
+fn read_record<R: Read>(reader: &mut R, remaining: u64) -> Result<Record> {
+    if remaining < RECORD_HEADER {
+        return Ok(Record::Torn);
+    }
+    let mut header = [0; RECORD_HEADER as usize];
+    reader.read_exact(&mut header)?;
+    let mut length = [0; 4];
+    length.copy_from_slice(&header[..4]);
+    let length = u64::from(u32::from_be_bytes(length));
+    if RECORD_HEADER + length > remaining {
+        return Ok(Record::Torn);
+    }
+    let mut operations = vec![0; length as usize];
+    reader.read_exact(&mut operations)?;
+    if checksum(&operations) != header[4..] {
+        if RECORD_HEADER + length == remaining {
+            return Ok(Record::Torn);
+        }
+        return Ok(Record::Corrupted);
+    }
+    Ok(Record::Operations(operations))
+}
+
+fn write_slice(buffer: &mut Vec<u8>, slice: &[u8]) -> Result<()> {
+    buffer.extend_from_slice(&encode_length(slice.len())?.to_be_bytes());
+    buffer.extend_from_slice(slice);
+    Ok(())
+}
+
+fn read_length(buffer: &[u8], position: &mut usize) -> Result<u32> {
+    let bytes = buffer.get(*position..*position + 4)
+        .ok_or_else(|| failure::err_msg("Truncated operation in log"))?;
+    let mut length = [0; 4];
+    length.copy_from_slice(bytes);
+    *position += 4;
+    Ok(u32::from_be_bytes(length))
+}
+
+fn read_slice<'a>(buffer: &'a [u8], position: &mut usize) -> Result<&'a [u8]> {
+    let length = read_length(buffer, position)? as usize;
+    let slice = buffer.get(*position..*position + length)
+        .ok_or_else(|| failure::err_msg("Truncated operation in log"))?;
+    *position += length;
+    Ok(slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{BufReader, Write};
+    use std::path::PathBuf;
+
+    use uuid::Uuid;
+
+    use crate::storage::log::{encode_length, read_record, Log, Namespaces, Record, COMPACTION_CHUNK_SIZE, COMPACTION_MIN_SIZE,
+                              RECORD_HEADER};
+    use crate::storage::storage::{conformance, Batch, Storage};
+
+    fn path() -> PathBuf {
+        env::temp_dir().join(Uuid::new_v4().to_string())
+    }
+
+    #[test]
+    fn conformance() {
+        conformance::crud(Log::open(path()).unwrap());
+        conformance::write_batch(Log::open(path()).unwrap());
+        conformance::iterate(Log::open(path()).unwrap());
+        conformance::namespaces(Namespaces::open(path()).unwrap());
+    }
+
+    #[test]
+    fn reopen() {
+        let path = path();
+        let mut log = Log::open(&path).unwrap();
+        log.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let mut batch = Batch::new();
+        batch.put(b"b".to_vec(), b"2".to_vec());
+        batch.delete(b"a".to_vec());
+        log.write_batch(batch).unwrap();
+        drop(log);
+
+        let log = Log::open(&path).unwrap();
+        assert_eq!(log.get(b"a").unwrap(), None);
+        assert_eq!(log.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn drop_torn_record() {
+        let path = path();
+        let mut log = Log::open(&path).unwrap();
+        log.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let size = fs::metadata(&path).unwrap().len();
+        let mut batch = Batch::new();
+        batch.put(b"b".to_vec(), b"2".to_vec());
+        batch.put(b"c".to_vec(), b"3".to_vec());
+        log.write_batch(batch).unwrap();
+        drop(log);
+
+        // a crash in the middle of the second batch
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(fs::metadata(&path).unwrap().len() - 3).unwrap();
+
+        let mut log = Log::open(&path).unwrap();
+        assert_eq!(log.iter().unwrap().collect::<Vec<_>>(), vec![(b"a".to_vec(), b"1".to_vec())]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        log.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        drop(log);
+
+        // a corrupted record is dropped as well
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 42]).unwrap();
+        let log = Log::open(&path).unwrap();
+        assert_eq!(log.iter().unwrap().count(), 2);
+        drop(log);
+
+        // a torn header claiming more than the log holds
+        let length = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0, 42]).unwrap();
+        let log = Log::open(&path).unwrap();
+        assert_eq!(log.iter().unwrap().count(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+    }
+
+    #[test]
+    fn keep_records_after_corruption() {
+        let path = path();
+        let mut log = Log::open(&path).unwrap();
+        log.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        log.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        drop(log);
+
+        // a flipped bit in the first of two records
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[RECORD_HEADER as usize + 5] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert!(Log::open(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn compact() {
+        let path = path();
+        let mut log = Log::open(&path).unwrap();
+        let value = vec![7; 1024];
+        for _ in 0..2 * COMPACTION_MIN_SIZE / 1024 {
+            log.put(b"key".to_vec(), value.clone()).unwrap();
+        }
+        log.put(b"other".to_vec(), b"value".to_vec()).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < COMPACTION_MIN_SIZE);
+
+        log.delete(b"other").unwrap();
+        log.compact().unwrap();
+        drop(log);
+        let log = Log::open(&path).unwrap();
+        assert_eq!(log.iter().unwrap().collect::<Vec<_>>(), vec![(b"key".to_vec(), value)]);
+    }
+
+    #[test]
+    fn compact_in_chunks() {
+        let path = path();
+        let mut log = Log::open(&path).unwrap();
+        let value = vec![7; COMPACTION_CHUNK_SIZE / 3];
+        let mut batch = Batch::new();
+        for key in 0..4u8 {
+            batch.put(vec![key], value.clone());
+        }
+        log.write_batch(batch).unwrap();
+        log.put(vec![4], vec![7; COMPACTION_CHUNK_SIZE + 1]).unwrap();
+        log.compact().unwrap();
+        // the log keeps working on the compacted file
+        assert_eq!(log.get(&[4]).unwrap().map(|value| value.len()), Some(COMPACTION_CHUNK_SIZE + 1));
+        log.put(vec![5], b"after".to_vec()).unwrap();
+        drop(log);
+
+        // two values per record, the large value in a record of its own and the later put
+        let mut reader = BufReader::new(File::open(&path).unwrap());
+        let mut remaining = fs::metadata(&path).unwrap().len();
+        let mut records = 0;
+        while let Record::Operations(operations) = read_record(&mut reader, remaining).unwrap() {
+            remaining -= RECORD_HEADER + operations.len() as u64;
+            records += 1;
+        }
+        assert_eq!(remaining, 0);
+        assert_eq!(records, 4);
+        let log = Log::open(&path).unwrap();
+        assert_eq!(log.iter().unwrap().count(), 6);
+        assert_eq!(log.get(&[3]).unwrap(), Some(value));
+    }
+
+    #[test]
+    fn reject_oversized_records() {
+        assert_eq!(encode_length(u32::MAX as usize).unwrap(), u32::MAX);
+        assert!(encode_length(u32::MAX as usize + 1).is_err());
+    }
+}
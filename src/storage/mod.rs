@@ -1,3 +1,18 @@
+#[cfg(not(any(feature = "rocksdb", feature = "log-storage")))]
+compile_error!("A persistent storage backend is required, enable the `rocksdb` or `log-storage` feature");
+
+pub mod chain;
+pub mod encrypted;
 pub mod hashmap;
+#[cfg(feature = "log-storage")]
+pub mod log;
+#[cfg(feature = "rocksdb")]
 pub mod rocksdb;
-mod storage;
+pub mod schema;
+pub mod storage;
+
+// The persistent backend, RocksDB unless only the `log-storage` feature is enabled
+#[cfg(feature = "rocksdb")]
+pub use self::rocksdb::{Namespace, Namespaces};
+#[cfg(all(feature = "log-storage", not(feature = "rocksdb")))]
+pub use self::log::{Namespace, Namespaces};
@@ -1,11 +1,15 @@
 //! # Rocksdb storage backend
 //!
 //! Storage backend that persists data in the file system using a RocksDB database.
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
 use failure::Fail;
 use rocksdb;
 
-use super::storage::{Result, Storage};
-use rocksdb::DB;
+use super::storage::{Batch, Iter, Namespaced, Operation, Result, Storage};
+use rocksdb::{ColumnFamily, Direction, IteratorMode, Options, WriteBatch, DB};
 
 /// To be stored are:
 /// crypto: nodes pk + sk, revocation certificate and uuid, pk pairs of other nodes
@@ -13,17 +17,133 @@ use rocksdb::DB;
 /// blockchain: a copy of the blockchain, only the current transactions shall remain in ram
 ///
 /// storage schema:
-/// rocks db supports column families similar to sql tables so just create a column familiy for
-/// each set of items to be saved: o
-/// 1. ownKeyPairFamily containing pk, sk and the revocation certificate
-/// 2. publicKeysFamily conatining Uuid, TPK pairs
-/// 3. peerTableFamily: Uuid, IP pairs
-/// 4. blockChainFamily: block_count, Block pairs (the chain header shall remain in ram as its
-/// frequently changing
+/// rocks db supports column families similar to sql tables so every keyspace opened via
+/// `Namespaces::namespace` is a column family of its own, e.g.:
+/// 1. keys: own key pair, revocation certificate and the TPKs of the peers
+/// 2. block_chain: the blocks of the active chain and their index (the chain header shall remain
+/// in ram as its frequently changing
 #[derive(Debug, Fail)]
 #[fail(display = "RocksDB error")]
 struct Error(#[fail(cause)] rocksdb::Error);
 
+/// A RocksDB database with a column family for every keyspace.
+#[derive(Clone)]
+pub struct Namespaces {
+    db: Arc<RwLock<DB>>,
+    path: PathBuf,
+}
+
+impl Namespaces {
+    /// Opens the database at the given path with all its column families, creating it if missing.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Namespaces> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        let families = DB::list_cf(&options, path.as_ref()).unwrap_or_default();
+        let families: Vec<&str> = families.iter()
+            .map(|name| name.as_str())
+            .filter(|name| *name != DEFAULT_FAMILY)
+            .collect();
+        let db = DB::open_cf(&options, path.as_ref(), &families).map_err(Error)?;
+        Ok(Namespaces {
+            db: Arc::new(RwLock::new(db)),
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+}
+
+impl Namespaced for Namespaces {
+    type Namespace = Namespace;
+
+    fn namespace(&mut self, name: &str) -> Result<Namespace> {
+        let mut db = self.db.write().unwrap();
+        if db.cf_handle(name).is_none() {
+            db.create_cf(name, &Options::default()).map_err(Error)?;
+        }
+        Ok(Namespace {
+            db: self.db.clone(),
+            name: name.to_string(),
+        })
+    }
+}
+
+impl fmt::Debug for Namespaces {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Namespaces({})", self.path.display())
+    }
+}
+
+/// The name of the column family RocksDB always creates
+const DEFAULT_FAMILY: &str = "default";
+
+/// A keyspace of `Namespaces`, backed by a column family.
+#[derive(Clone)]
+pub struct Namespace {
+    db: Arc<RwLock<DB>>,
+    name: String,
+}
+
+impl Namespace {
+    fn family(&self, db: &DB) -> Result<ColumnFamily> {
+        db.cf_handle(&self.name)
+            .ok_or_else(|| failure::err_msg(format!("Missing column family {}", self.name)))
+    }
+}
+
+impl Storage for Namespace {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let db = self.db.read().unwrap();
+        let result = db.get_cf(self.family(&db)?, key)
+            .map(|opt| opt.map(|dbvec| dbvec.to_vec()))
+            .map_err(Error)?;
+        Ok(result)
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let db = self.db.read().unwrap();
+        db.put_cf(self.family(&db)?, &key, &value).map_err(Error)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let db = self.db.read().unwrap();
+        db.delete_cf(self.family(&db)?, key).map_err(Error)?;
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        let db = self.db.read().unwrap();
+        let family = self.family(&db)?;
+        let mut write_batch = WriteBatch::default();
+        for operation in batch.operations() {
+            let result = match operation {
+                Operation::Put(key, value) => write_batch.put_cf(family, key, value),
+                Operation::Delete(key) => write_batch.delete_cf(family, key),
+            };
+            result.map_err(Error)?;
+        }
+        db.write(write_batch).map_err(Error)?;
+        Ok(())
+    }
+
+    fn range<'a>(&'a self, start: &[u8], end: Option<&[u8]>) -> Result<Iter<'a>> {
+        // the iterator borrows the database, so the pairs are collected before the lock is released
+        let db = self.db.read().unwrap();
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = db
+            .iterator_cf(self.family(&db)?, IteratorMode::From(start, Direction::Forward))
+            .map_err(Error)?
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .take_while(|(key, _)| end.map_or(true, |end| key.as_slice() < end))
+            .collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+}
+
+impl fmt::Debug for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Namespace({})", self.name)
+    }
+}
+
  impl Storage for DB {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         let result = DB::get(self, &key)
@@ -41,4 +161,68 @@ struct Error(#[fail(cause)] rocksdb::Error);
         DB::delete(self, &key).map_err(Error)?;
         Ok(())
     }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        let mut write_batch = WriteBatch::default();
+        for operation in batch.operations() {
+            let result = match operation {
+                Operation::Put(key, value) => write_batch.put(key, value),
+                Operation::Delete(key) => write_batch.delete(key),
+            };
+            result.map_err(Error)?;
+        }
+        DB::write(self, write_batch).map_err(Error)?;
+        Ok(())
+    }
+
+    fn range<'a>(&'a self, start: &[u8], end: Option<&[u8]>) -> Result<Iter<'a>> {
+        let end = end.map(|end| end.to_vec());
+        let iter = DB::iterator(self, IteratorMode::From(start, Direction::Forward))
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .take_while(move |(key, _)| end.as_ref().map_or(true, |end| key < end));
+        Ok(Box::new(iter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use rocksdb::DB;
+    use uuid::Uuid;
+
+    use crate::storage::rocksdb::Namespaces;
+    use crate::storage::storage::{conformance, Namespaced, Storage};
+
+    fn open() -> DB {
+        DB::open_default(env::temp_dir().join(Uuid::new_v4().to_string())).unwrap()
+    }
+
+    #[test]
+    fn crud() {
+        conformance::crud(open());
+    }
+
+    #[test]
+    fn write_batch() {
+        conformance::write_batch(open());
+    }
+
+    #[test]
+    fn iterate() {
+        conformance::iterate(open());
+    }
+
+    #[test]
+    fn namespaces() {
+        let path = env::temp_dir().join(Uuid::new_v4().to_string());
+        conformance::namespaces(Namespaces::open(&path).unwrap());
+
+        let mut namespaces = Namespaces::open(&path).unwrap();
+        let namespace = namespaces.namespace("keys").unwrap();
+        assert_eq!(namespace.get(b"key").unwrap(), Some(b"own".to_vec()));
+        conformance::crud(namespaces.namespace("crud").unwrap());
+        conformance::write_batch(namespaces.namespace("write_batch").unwrap());
+        conformance::iterate(namespaces.namespace("iterate").unwrap());
+    }
 }
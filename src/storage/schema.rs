@@ -0,0 +1,157 @@
+//! # Schema
+//!
+//! Versions the layout of the persisted data and migrates older databases on open.
+use failure::Fail;
+
+use super::chain::ChainStore;
+use super::storage::{Namespaced, Result, Storage};
+
+/// The schema version written by this binary
+pub const VERSION: u32 = 4;
+/// The keyspace of the schema version
+pub const NAMESPACE: &str = "schema";
+/// Key of the schema version
+const SCHEMA_VERSION: &[u8] = b"version";
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum SchemaError {
+    #[fail(display = "The database has schema version {}, but this binary only supports up to version {}", version, supported)]
+    Newer { version: u32, supported: u32 },
+    #[fail(display = "There is no migration to schema version {}", _0)]
+    MissingMigration(u32),
+}
+
+/// A step migrating the database from the previous schema version to `version`
+pub struct Migration<N> {
+    pub version: u32,
+    pub description: &'static str,
+    pub migrate: fn(&mut N) -> Result<()>,
+}
+
+/// All migrations, ordered by version.
+///
+/// Databases without a schema version are version 0. A change to the serialization of persisted
+/// data, e.g. of `Block<T>`, `BlockHeader` or `Transaction<T>`, bumps `VERSION` and adds a
+/// migration that rewrites the data stored with the previous version.
+pub fn migrations<N: Namespaced>() -> Vec<Migration<N>> {
+    vec![
+        Migration {
+            version: 1,
+            description: "Record the schema version",
+            migrate: |_| Ok(()),
+        },
+        Migration {
+            version: 2,
+            description: "Drop the blocks stored without transaction fees, they are synced again",
+            // the fees are covered by the merkle roots and signatures, so the blocks can't be converted
+            migrate: |storage| ChainStore::open(storage)?.clear(),
+        },
+        Migration {
+            version: 3,
+            description: "Drop the blocks stored without transaction nonces, they are synced again",
+            migrate: |storage| ChainStore::open(storage)?.clear(),
+        },
+        Migration {
+            version: 4,
+            description: "Drop the blocks stored before the timestamp rules, they are synced again",
+            // blocks mined within the same second break the median time past rule
+            migrate: |storage| ChainStore::open(storage)?.clear(),
+        },
+    ]
+}
+
+/// The schema version of the database, 0 if none is recorded.
+pub fn version<N: Namespaced>(storage: &mut N) -> Result<u32> {
+    match storage.namespace(NAMESPACE)?.get(SCHEMA_VERSION)? {
+        Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        None => Ok(0),
+    }
+}
+
+/// Migrates the database to `VERSION`, refusing databases written by a newer binary.
+/// Returns the schema version the database had before.
+pub fn migrate<N: Namespaced>(storage: &mut N) -> Result<u32> {
+    migrate_to(storage, &migrations(), VERSION)
+}
+
+/// Runs the migrations after the schema version of the database up to `target`.
+///
+/// The version is recorded after every migration, so an interrupted migration resumes with the
+/// step that failed.
+pub fn migrate_to<N: Namespaced>(storage: &mut N, migrations: &[Migration<N>], target: u32) -> Result<u32> {
+    let version = self::version(storage)?;
+    if version > target {
+        return Err(SchemaError::Newer { version, supported: target }.into());
+    }
+
+    for next in version + 1..=target {
+        let migration = migrations.iter()
+            .find(|migration| migration.version == next)
+            .ok_or(SchemaError::MissingMigration(next))?;
+        println!("Migrating the database to schema version {}: {}", next, migration.description);
+        (migration.migrate)(storage)?;
+        storage.namespace(NAMESPACE)?.put(SCHEMA_VERSION.to_vec(), serde_json::to_vec(&next)?)?;
+    }
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::hashmap::Namespaces;
+    use crate::storage::schema::{self, Migration, SchemaError, VERSION};
+    use crate::storage::storage::{Namespaced, Result, Storage};
+
+    fn rename(storage: &mut Namespaces) -> Result<()> {
+        let mut namespace = storage.namespace("data")?;
+        if let Some(value) = namespace.get(b"old")? {
+            namespace.put(b"new".to_vec(), value)?;
+            namespace.delete(b"old")?;
+        }
+        Ok(())
+    }
+
+    fn fail(_: &mut Namespaces) -> Result<()> {
+        Err(failure::err_msg("interrupted"))
+    }
+
+    fn migrations(third: fn(&mut Namespaces) -> Result<()>) -> Vec<Migration<Namespaces>> {
+        vec![
+            Migration { version: 1, description: "Record the schema version", migrate: |_| Ok(()) },
+            Migration { version: 2, description: "Rename old to new", migrate: rename },
+            Migration { version: 3, description: "Third", migrate: third },
+        ]
+    }
+
+    #[test]
+    fn migrate_current() {
+        let mut storage = Namespaces::default();
+        assert_eq!(schema::migrate(&mut storage).unwrap(), 0);
+        assert_eq!(schema::version(&mut storage).unwrap(), VERSION);
+        assert_eq!(schema::migrate(&mut storage).unwrap(), VERSION);
+    }
+
+    #[test]
+    fn migrate_in_order() {
+        let mut storage = Namespaces::default();
+        storage.namespace("data").unwrap().put(b"old".to_vec(), b"value".to_vec()).unwrap();
+
+        assert!(schema::migrate_to(&mut storage, &migrations(fail), 3).is_err());
+        assert_eq!(schema::version(&mut storage).unwrap(), 2);
+        assert_eq!(storage.namespace("data").unwrap().get(b"new").unwrap(), Some(b"value".to_vec()));
+
+        assert_eq!(schema::migrate_to(&mut storage, &migrations(|_| Ok(())), 3).unwrap(), 2);
+        assert_eq!(schema::version(&mut storage).unwrap(), 3);
+    }
+
+    #[test]
+    fn refuse_newer() {
+        let mut storage = Namespaces::default();
+        schema::migrate_to(&mut storage, &migrations(|_| Ok(())), 3).unwrap();
+
+        let error = schema::migrate_to(&mut storage, &migrations(|_| Ok(())), 2).unwrap_err();
+        assert_eq!(error.downcast::<SchemaError>().unwrap(), SchemaError::Newer { version: 3, supported: 2 });
+        let error = schema::migrate_to(&mut storage, &migrations(|_| Ok(())), 4).unwrap_err();
+        assert_eq!(error.downcast::<SchemaError>().unwrap(), SchemaError::MissingMigration(4));
+        assert_eq!(schema::version(&mut storage).unwrap(), 3);
+    }
+}
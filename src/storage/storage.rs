@@ -9,6 +9,43 @@ use failure;
 /// Result with error set to `failure::Error`
 pub type Result<T> = result::Result<T, failure::Error>;
 
+/// Iterator over key/value pairs, ordered by key
+pub type Iter<'a> = Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>;
+
+/// A single write of a `Batch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// Writes that are applied all at once or not at all by `Storage::write_batch`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    operations: Vec<Operation>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    /// Put a value in the storage
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.operations.push(Operation::Put(key, value));
+    }
+
+    /// Delete a value from the storage
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.operations.push(Operation::Delete(key));
+    }
+
+    /// The writes in the order they were added
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+}
+
 /// Generic trait that exposes a very simple key/value CRUD API for data storage.
 ///
 /// This trait can be easily implemented for any specific storage
@@ -22,4 +59,158 @@ pub trait Storage {
 
     /// Delete a value from the storage
     fn delete(&mut self, key: &[u8]) -> Result<()>;
+
+    /// Check whether a value is stored for the key
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Apply all writes of the batch atomically, in order
+    fn write_batch(&mut self, batch: Batch) -> Result<()>;
+
+    /// Iterate the pairs with keys from `start` up to, but excluding, `end`, ordered by key
+    ///
+    /// Iterates up to the last key if `end` is `None`.
+    fn range<'a>(&'a self, start: &[u8], end: Option<&[u8]>) -> Result<Iter<'a>>;
+
+    /// Iterate all pairs ordered by key
+    fn iter<'a>(&'a self) -> Result<Iter<'a>> {
+        self.range(&[], None)
+    }
+
+    /// Iterate the pairs whose keys start with the prefix, ordered by key
+    fn prefix_iter<'a>(&'a self, prefix: &[u8]) -> Result<Iter<'a>> {
+        let end = prefix_end(prefix);
+        self.range(prefix, end.as_ref().map(|end| end.as_slice()))
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        (**self).get(key)
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        (**self).put(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        (**self).delete(key)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        (**self).contains(key)
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        (**self).write_batch(batch)
+    }
+
+    fn range<'a>(&'a self, start: &[u8], end: Option<&[u8]>) -> Result<Iter<'a>> {
+        (**self).range(start, end)
+    }
+}
+
+/// Storage split into isolated, named keyspaces.
+///
+/// Every subsystem owns a keyspace, so their keys can't collide.
+pub trait Namespaced {
+    /// A handle to a keyspace
+    type Namespace: Storage;
+
+    /// Open the keyspace with the given name, creating it if missing
+    fn namespace(&mut self, name: &str) -> Result<Self::Namespace>;
+}
+
+/// The smallest key greater than all keys starting with the prefix, `None` if there is none
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Tests every backend has to pass
+#[cfg(test)]
+pub mod conformance {
+    use crate::storage::storage::{Batch, Namespaced, Storage};
+
+    fn pair(key: &str, value: &str) -> (Vec<u8>, Vec<u8>) {
+        (key.as_bytes().to_vec(), value.as_bytes().to_vec())
+    }
+
+    pub fn crud<S: Storage>(mut storage: S) {
+        assert_eq!(storage.get(b"key").unwrap(), None);
+        assert!(!storage.contains(b"key").unwrap());
+
+        storage.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(storage.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert!(storage.contains(b"key").unwrap());
+
+        storage.delete(b"key").unwrap();
+        assert_eq!(storage.get(b"key").unwrap(), None);
+        storage.delete(b"key").unwrap();
+    }
+
+    pub fn write_batch<S: Storage>(mut storage: S) {
+        storage.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+
+        let mut batch = Batch::new();
+        batch.put(b"b".to_vec(), b"2".to_vec());
+        batch.delete(b"a".to_vec());
+        batch.put(b"c".to_vec(), b"3".to_vec());
+        batch.delete(b"c".to_vec());
+        batch.put(b"b".to_vec(), b"4".to_vec());
+        storage.write_batch(batch).unwrap();
+
+        assert_eq!(storage.iter().unwrap().collect::<Vec<_>>(), vec![pair("b", "4")]);
+    }
+
+    pub fn iterate<S: Storage>(mut storage: S) {
+        for key in &["b", "ab", "a", "ba", "c", "a\u{7f}"] {
+            storage.put(key.as_bytes().to_vec(), key.to_uppercase().into_bytes()).unwrap();
+        }
+
+        assert_eq!(storage.iter().unwrap().collect::<Vec<_>>(),
+                   vec![pair("a", "A"), pair("ab", "AB"), pair("a\u{7f}", "A\u{7f}"), pair("b", "B"), pair("ba", "BA"), pair("c", "C")]);
+        assert_eq!(storage.prefix_iter(b"a").unwrap().collect::<Vec<_>>(),
+                   vec![pair("a", "A"), pair("ab", "AB"), pair("a\u{7f}", "A\u{7f}")]);
+        assert_eq!(storage.range(b"ab", Some(b"ba")).unwrap().collect::<Vec<_>>(),
+                   vec![pair("ab", "AB"), pair("a\u{7f}", "A\u{7f}"), pair("b", "B")]);
+        assert_eq!(storage.range(b"b", None).unwrap().count(), 3);
+        assert_eq!(storage.prefix_iter(b"d").unwrap().count(), 0);
+    }
+
+    pub fn namespaces<N: Namespaced>(mut storage: N) {
+        let mut keys = storage.namespace("keys").unwrap();
+        let mut blocks = storage.namespace("blocks").unwrap();
+        keys.put(b"key".to_vec(), b"own".to_vec()).unwrap();
+        blocks.put(b"key".to_vec(), b"genesis".to_vec()).unwrap();
+
+        let mut batch = Batch::new();
+        batch.put(b"other".to_vec(), b"peer".to_vec());
+        keys.write_batch(batch).unwrap();
+
+        assert_eq!(blocks.iter().unwrap().collect::<Vec<_>>(), vec![pair("key", "genesis")]);
+        let keys = storage.namespace("keys").unwrap();
+        assert_eq!(keys.iter().unwrap().collect::<Vec<_>>(), vec![pair("key", "own"), pair("other", "peer")]);
+        assert!(!storage.namespace("peers").unwrap().contains(b"key").unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::storage::prefix_end;
+
+    #[test]
+    fn prefix_end_increments_last_byte() {
+        assert_eq!(prefix_end(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_end(&[1, 0xff]), Some(vec![2]));
+        assert_eq!(prefix_end(&[0xff, 0xff]), None);
+        assert_eq!(prefix_end(&[]), None);
+    }
 }